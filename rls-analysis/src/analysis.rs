@@ -46,9 +46,16 @@ pub struct PerCrateAnalysis {
     pub def_fst_values: Vec<Vec<Id>>,
 
     pub ref_spans: HashMap<Id, Vec<Span>>,
+    // The refs in each file, by the position they start at.
+    pub refs_per_file: HashMap<PathBuf, RefsByPosition>,
     pub globs: HashMap<Span, Glob>,
     pub impls: HashMap<Id, Vec<Span>>,
+    pub relations: RelationGraph,
     pub idents: HashMap<PathBuf, IdentsByLine>,
+    // Save-analysis has no defs for macros, so we make up a def for each macro
+    // used in a crate. Maps the span of the macro's definition to the id of
+    // its def, which is the same in all crates using the macro.
+    pub macro_defs: HashMap<Span, Id>,
 
     pub root_id: Option<Id>,
     pub timestamp: SystemTime,
@@ -100,11 +107,40 @@ pub struct Def {
     pub parent: Option<Id>,
     pub value: String,
     pub docs: String,
-    // pub sig: Option<Signature>,
+    pub sig: Option<Signature>,
+    pub attributes: Vec<Attribute>,
+}
+
+/// An attribute of a def, e.g., `deprecated(since = "1.0.0")` for
+/// `#[deprecated(since = "1.0.0")]`. Doc comments are not included.
+#[derive(Debug, Clone)]
+pub struct Attribute {
+    pub value: String,
+    pub span: Span,
+}
+
+impl Attribute {
+    /// The name of the attribute, e.g., `deprecated` or `cfg`.
+    pub fn name(&self) -> &str {
+        let end = self.value.find(&['(', '='][..]).unwrap_or(self.value.len());
+        self.value[..end].trim()
+    }
+
+    /// The arguments of the attribute, e.g., `test` for `cfg(test)`.
+    pub fn args(&self) -> Option<&str> {
+        let start = self.value.find(&['(', '='][..])?;
+        let end = self.value.rfind(')')?;
+        if self.value[start..].starts_with('(') && start < end {
+            Some(self.value[start + 1..end].trim())
+        } else {
+            None
+        }
+    }
 }
 
 pub type IdentsByLine = BTreeMap<Row<ZeroIndexed>, IdentsByColumn>;
 pub type IdentsByColumn = BTreeMap<Column<ZeroIndexed>, IdentBound>;
+pub type RefsByPosition = BTreeMap<(Row<ZeroIndexed>, Column<ZeroIndexed>), (Span, Id)>;
 
 /// We store the identifiers for a file in a BTreeMap ordered by starting index.
 /// This struct contains the rest of the information we need to create an `Ident`.
@@ -136,12 +172,12 @@ pub struct Ident {
     pub kind: IdentKind,
 }
 
+/// A textual signature of a def (e.g. `fn foo<T: Bar>(x: T) -> u32`), along
+/// with the defs and refs found in it. `start` and `end` of each element are
+/// byte offsets into `text`.
 #[derive(Debug, Clone)]
 pub struct Signature {
-    pub span: Span,
     pub text: String,
-    pub ident_start: u32,
-    pub ident_end: u32,
     pub defs: Vec<SigElement>,
     pub refs: Vec<SigElement>,
 }
@@ -153,6 +189,41 @@ pub struct SigElement {
     pub end: usize,
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum TypeRelation {
+    /// An edge from a trait to one of its supertraits.
+    SuperTrait,
+    /// An edge from a type to a trait it implements.
+    Impl,
+}
+
+/// Relations between types and traits as a directed graph, see `TypeRelation`
+/// for the direction of edges.
+#[derive(Debug, Default)]
+pub struct RelationGraph {
+    outgoing: HashMap<(TypeRelation, Id), Vec<Id>>,
+    incoming: HashMap<(TypeRelation, Id), Vec<Id>>,
+}
+
+impl RelationGraph {
+    pub fn add_edge(&mut self, kind: TypeRelation, from: Id, to: Id) {
+        let targets = self.outgoing.entry((kind, from)).or_insert_with(Vec::new);
+        if targets.contains(&to) {
+            return;
+        }
+        targets.push(to);
+        self.incoming.entry((kind, to)).or_insert_with(Vec::new).push(from);
+    }
+
+    pub fn outgoing(&self, kind: TypeRelation, id: Id) -> &[Id] {
+        self.outgoing.get(&(kind, id)).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn incoming(&self, kind: TypeRelation, id: Id) -> &[Id] {
+        self.incoming.get(&(kind, id)).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
+
 #[derive(Debug)]
 pub struct Glob {
     pub value: String,
@@ -170,9 +241,12 @@ impl PerCrateAnalysis {
             def_fst: empty_fst,
             def_fst_values: Vec::new(),
             ref_spans: HashMap::new(),
+            refs_per_file: HashMap::new(),
             globs: HashMap::new(),
             impls: HashMap::new(),
+            relations: RelationGraph::default(),
             idents: HashMap::new(),
+            macro_defs: HashMap::new(),
             root_id: None,
             timestamp,
             path,
@@ -231,6 +305,28 @@ impl PerCrateAnalysis {
             })
             .unwrap_or_else(Vec::new)
     }
+
+    // Returns all identifiers in `file`, ordered by their position.
+    #[cfg(feature = "idents")]
+    fn file_idents(&self, file: &Path) -> Vec<Ident> {
+        self.idents
+            .get(file)
+            .map(|by_line| {
+                by_line
+                    .iter()
+                    .flat_map(|(line, by_col)| {
+                        by_col.iter().map(move |(col_start, id)| {
+                            Ident::new(
+                                Span::new(*line, *line, *col_start, id.column_end, file),
+                                id.id,
+                                id.kind,
+                            )
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new)
+    }
 }
 
 impl Analysis {
@@ -333,6 +429,144 @@ impl Analysis {
         self.for_each_crate(|c| c.defs.get(&id).and_then(&f))
     }
 
+    /// Resolves a local, field, static or const to the id of the def of its
+    /// type. Type defs resolve to themselves.
+    pub fn type_def_id(&self, id: Id) -> Option<Id> {
+        self.with_defs_and_then(id, |def| {
+            if is_type_kind(def.kind) {
+                return Some(id);
+            }
+            match def.kind {
+                DefKind::Local
+                | DefKind::Field
+                | DefKind::Static
+                | DefKind::ForeignStatic
+                | DefKind::Const => {}
+                _ => return None,
+            }
+
+            // The signature (e.g., `x: Vec<Foo>`) records precisely what the
+            // type refers to, the first type ref is the outermost type.
+            let from_sig = def.sig.as_ref().and_then(|sig| {
+                sig.refs
+                    .iter()
+                    .map(|r| r.id)
+                    .find(|id| self.with_defs(*id, |d| is_type_kind(d.kind)).unwrap_or(false))
+            });
+
+            from_sig.or_else(|| self.type_def_id_for_value(id, &def.value))
+        })
+    }
+
+    // Signatures are not always available (e.g., for locals), in which case
+    // we try to find the type by the name found in the textual type.
+    fn type_def_id_for_value(&self, id: Id, value: &str) -> Option<Id> {
+        let name = type_name(value)?;
+        let candidates: Vec<Id> = self.with_def_names(name, |ids| {
+            ids.iter()
+                .filter(|id| self.with_defs(**id, |d| is_type_kind(d.kind)).unwrap_or(false))
+                .cloned()
+                .collect()
+        });
+
+        // Prefer a type from the same crate, otherwise only accept an
+        // unambiguous match.
+        self.for_each_crate(|c| {
+            if c.defs.contains_key(&id) {
+                candidates.iter().find(|id| c.defs.contains_key(id)).cloned()
+            } else {
+                None
+            }
+        })
+        .or_else(|| if candidates.len() == 1 { Some(candidates[0]) } else { None })
+    }
+
+    /// Collects the (unique) ids that `f` finds in the relation graphs of all
+    /// crates.
+    pub fn with_relations<F>(&self, f: F) -> Vec<Id>
+    where
+        F: Fn(&RelationGraph) -> &[Id],
+    {
+        let mut result: Vec<Id> = vec![];
+        for per_crate in self.per_crate.values() {
+            for id in f(&per_crate.relations) {
+                if !result.contains(id) {
+                    result.push(*id);
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the functions and methods which may call the function `id`,
+    /// each with the spans of the refs attributed to them. As for `callees`,
+    /// it is up to the caller to check that the refs are calls within the
+    /// body of the function, since each ref is attributed to the function
+    /// before it.
+    pub fn callers(&self, id: Id) -> Vec<(Id, Vec<Span>)> {
+        let refs = self.with_ref_spans(id, |refs| Some(refs.clone())).unwrap_or_else(Vec::new);
+
+        let mut result = vec![];
+        for span in refs {
+            if let Some(caller) = self.enclosing_fn_id(&span) {
+                push_grouped(&mut result, caller, span);
+            }
+        }
+        result
+    }
+
+    /// Returns the functions and methods referenced in `body`, which is the
+    /// body of a function, each with the spans of the refs. Save-analysis
+    /// doesn't record the extent of function bodies, so it is up to the caller
+    /// to find it (and to tell calls apart from other refs).
+    pub fn callees(&self, body: &Span) -> Vec<(Id, Vec<Span>)> {
+        let start = (body.range.row_start, body.range.col_start);
+        let end = (body.range.row_end, body.range.col_end);
+
+        let mut refs = self.for_all_crates(|c| {
+            let refs = c.refs_per_file.get(&body.file)?;
+            Some(refs.range(start..end).map(|(_, r)| r.clone()).collect())
+        });
+        refs.sort_by(|a, b| a.0.cmp(&b.0));
+        refs.dedup_by(|a, b| a.0 == b.0);
+
+        let mut result = vec![];
+        for (span, callee) in refs {
+            if self.with_defs(callee, |def| is_fn_kind(def.kind)) == Some(true) {
+                push_grouped(&mut result, callee, span);
+            }
+        }
+        result
+    }
+
+    // Save-analysis only records the span of the name of a function, not its
+    // body, so we assume that a function extends until the next function in
+    // the same file.
+    fn enclosing_fn_id(&self, span: &Span) -> Option<Id> {
+        let pos = span.range.start();
+        self.fn_defs_in_file(&span.file)
+            .into_iter()
+            .take_while(|(_, fn_span)| fn_span.range.start() < pos)
+            .last()
+            .map(|(id, _)| id)
+    }
+
+    // Returns functions and methods defined in `file`, ordered by position.
+    fn fn_defs_in_file(&self, file: &Path) -> Vec<(Id, Span)> {
+        let mut result = self.for_all_crates(|c| {
+            c.defs_per_file.get(file).map(|ids| {
+                ids.iter()
+                    .filter_map(|id| c.defs.get(id).map(|def| (*id, def)))
+                    .filter(|(_, def)| is_fn_kind(def.kind))
+                    .map(|(id, def)| (id, def.span.clone()))
+                    .collect()
+            })
+        });
+        result.sort_by(|a, b| a.1.cmp(&b.1));
+        result.dedup_by(|a, b| a.0 == b.0);
+        result
+    }
+
     pub fn with_globs<F, T>(&self, span: &Span, f: F) -> Option<T>
     where
         F: Fn(&Glob) -> T,
@@ -371,6 +605,37 @@ impl Analysis {
         self.for_each_crate(|c| c.ref_spans.get(&id).and_then(&f))
     }
 
+    /// Returns the spans of all invocations of the macro used or defined at
+    /// `span`, in any crate.
+    pub fn macro_invocations(&self, span: &Span) -> Vec<Span> {
+        let def_id = self
+            .def_id_for_span(span)
+            .filter(|id| self.with_defs(*id, |def| def.kind == DefKind::Macro) == Some(true))
+            .or_else(|| {
+                // The span of a macro def is the whole definition, so also look
+                // for macros defined on the row of `span`.
+                self.for_each_crate(|c| {
+                    c.macro_defs
+                        .iter()
+                        .find(|(s, _)| {
+                            s.file == span.file && s.range.row_start == span.range.row_start
+                        })
+                        .map(|(_, id)| *id)
+                })
+            });
+
+        match def_id {
+            Some(id) => {
+                // Crates compiled more than once (e.g., as tests) have the same refs.
+                let mut spans = self.for_all_crates(|c| c.ref_spans.get(&id).cloned());
+                spans.sort();
+                spans.dedup();
+                spans
+            }
+            None => vec![],
+        }
+    }
+
     pub fn with_defs_per_file<F, T>(&self, file: &Path, f: F) -> Option<T>
     where
         F: Fn(&Vec<Id>) -> T,
@@ -391,6 +656,19 @@ impl Analysis {
         .unwrap_or_else(Vec::new)
     }
 
+    #[cfg(feature = "idents")]
+    pub fn file_idents(&self, file: &Path) -> Vec<Ident> {
+        self.for_each_crate(|c| {
+            let result = c.file_idents(file);
+            if result.is_empty() {
+                None
+            } else {
+                Some(result)
+            }
+        })
+        .unwrap_or_else(Vec::new)
+    }
+
     pub fn query_defs(&self, query: SymbolQuery) -> Vec<Def> {
         let mut crates = Vec::with_capacity(self.per_crate.len());
         let stream = query.build_stream(self.per_crate.values().map(|c| {
@@ -412,3 +690,60 @@ impl Analysis {
         self.for_all_crates(|c| c.def_names.get(name).map(&f))
     }
 }
+
+fn is_type_kind(kind: DefKind) -> bool {
+    match kind {
+        DefKind::Enum
+        | DefKind::Tuple
+        | DefKind::Struct
+        | DefKind::Union
+        | DefKind::Trait
+        | DefKind::Type
+        | DefKind::ExternType => true,
+        _ => false,
+    }
+}
+
+/// Extracts the name of the outermost named type from a textual type, e.g.,
+/// `Vec` from `&'a mut std::vec::Vec<Foo>`.
+fn type_name(ty: &str) -> Option<&str> {
+    let mut ty = ty.trim_start();
+    loop {
+        let rest = ["&", "*const ", "*mut ", "mut ", "dyn ", "impl ", "["]
+            .iter()
+            .find(|prefix| ty.starts_with(*prefix))
+            .map(|prefix| &ty[prefix.len()..]);
+        ty = match rest {
+            Some(rest) => rest.trim_start(),
+            // Lifetime of a reference.
+            None if ty.starts_with('\'') => {
+                ty.find(char::is_whitespace).map(|i| ty[i..].trim_start())?
+            }
+            None => break,
+        };
+    }
+
+    let end = ty.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':')).unwrap_or(ty.len());
+    let name = ty[..end].rsplit("::").next()?;
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+fn is_fn_kind(kind: DefKind) -> bool {
+    match kind {
+        DefKind::Function | DefKind::Method | DefKind::ForeignFunction => true,
+        _ => false,
+    }
+}
+
+// Adds `span` to the spans of `id`, keeping the order in which ids are first
+// seen.
+fn push_grouped(groups: &mut Vec<(Id, Vec<Span>)>, id: Id, span: Span) {
+    match groups.iter_mut().find(|(group_id, _)| *group_id == id) {
+        Some((_, spans)) => spans.push(span),
+        None => groups.push((id, vec![span])),
+    }
+}
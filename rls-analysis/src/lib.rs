@@ -18,13 +18,13 @@ mod symbol_query;
 mod test;
 mod util;
 
-use analysis::Analysis;
-pub use analysis::{Def, Ident, IdentKind, Ref};
+use analysis::{Analysis, TypeRelation};
+pub use analysis::{Attribute, Def, Ident, IdentKind, Ref, SigElement, Signature};
 pub use loader::{AnalysisLoader, CargoAnalysisLoader, SearchDirectory, Target};
 pub use raw::{name_space_for_def_kind, read_analysis_from_files, Crate, CrateId, DefKind};
 pub use symbol_query::SymbolQuery;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
@@ -231,10 +231,25 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         self.with_analysis(|a| a.with_defs(id, Clone::clone))
     }
 
+    /// Returns the attributes of the def with the given id.
+    pub fn attributes(&self, id: Id) -> AResult<Vec<Attribute>> {
+        self.with_analysis(|a| a.with_defs(id, |def| def.attributes.clone()))
+    }
+
     pub fn goto_def(&self, span: &Span) -> AResult<Span> {
         self.with_analysis(|a| a.def_id_for_span(span).and_then(|id| def_span!(a, id)))
     }
 
+    /// Returns the def of the type of the def referred to by `span`, e.g., the
+    /// struct which is the type of a local variable, field or parameter.
+    pub fn type_def(&self, span: &Span) -> AResult<Def> {
+        self.with_analysis(|a| {
+            a.def_id_for_span(span)
+                .and_then(|id| a.type_def_id(id))
+                .and_then(|id| a.with_defs(id, Clone::clone))
+        })
+    }
+
     pub fn for_each_child_def<F, T>(&self, id: Id, f: F) -> AResult<Vec<T>>
     where
         F: FnMut(Id, &Def) -> T,
@@ -337,6 +352,14 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         })
     }
 
+    /// Returns the signature of the def referred to by `span`, if save-analysis
+    /// recorded one for it.
+    pub fn signature(&self, span: &Span) -> AResult<Signature> {
+        self.with_analysis(|a| {
+            a.def_id_for_span(span).and_then(|id| a.with_defs_and_then(id, |def| def.sig.clone()))
+        })
+    }
+
     pub fn docs(&self, span: &Span) -> AResult<String> {
         self.with_analysis(|a| {
             a.def_id_for_span(span).and_then(|id| a.with_defs(id, clone_field!(docs)))
@@ -370,24 +393,28 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
     pub fn search(&self, name: &str) -> AResult<Vec<Span>> {
         let t_start = Instant::now();
         let result = self.with_analysis(|a| {
-            Some(a.with_def_names(name, |defs| {
-                info!("defs: {:?}", defs);
-                defs.iter()
+            // Made-up macro defs are named in every crate using the macro.
+            let mut seen = HashSet::new();
+            let mut defs = a.with_def_names(name, Clone::clone);
+            defs.retain(|id| seen.insert(*id));
+            info!("defs: {:?}", defs);
+            Some(
+                defs.into_iter()
                     .flat_map(|id| {
-                        a.with_ref_spans(*id, |refs| {
+                        a.with_ref_spans(id, |refs| {
                             Some(
-                                def_span!(a, *id)
+                                def_span!(a, id)
                                     .into_iter()
                                     .chain(refs.iter().cloned())
                                     .collect::<Vec<_>>(),
                             )
                         })
-                        .or_else(|| def_span!(a, *id).map(|s| vec![s]))
+                        .or_else(|| def_span!(a, id).map(|s| vec![s]))
                         .unwrap_or_else(Vec::new)
                         .into_iter()
                     })
-                    .collect::<Vec<Span>>()
-            }))
+                    .collect::<Vec<Span>>(),
+            )
         });
 
         let time = t_start.elapsed();
@@ -417,10 +444,48 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         result
     }
 
+    /// Returns the spans of all invocations of the macro used or defined at
+    /// `span`.
+    pub fn find_macro_invocations(&self, span: &Span) -> AResult<Vec<Span>> {
+        self.with_analysis(|a| Some(a.macro_invocations(span)))
+    }
+
     pub fn find_impls(&self, id: Id) -> AResult<Vec<Span>> {
         self.with_analysis(|a| Some(a.for_all_crates(|c| c.impls.get(&id).cloned())))
     }
 
+    /// Returns the traits which are supertraits of the trait `id`.
+    pub fn supertraits(&self, id: Id) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| Some(a.with_relations(|g| g.outgoing(TypeRelation::SuperTrait, id))))
+    }
+
+    /// Returns the traits which have the trait `id` as a supertrait.
+    pub fn subtraits(&self, id: Id) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| Some(a.with_relations(|g| g.incoming(TypeRelation::SuperTrait, id))))
+    }
+
+    /// Returns the types which implement the trait `id`.
+    pub fn implementors(&self, id: Id) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| Some(a.with_relations(|g| g.incoming(TypeRelation::Impl, id))))
+    }
+
+    /// Returns the traits implemented by the type `id`.
+    pub fn implemented_traits(&self, id: Id) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| Some(a.with_relations(|g| g.outgoing(TypeRelation::Impl, id))))
+    }
+
+    /// Returns the functions which may call the function `id`, i.e., which
+    /// precede refs to it, along with the spans of those refs.
+    pub fn callers(&self, id: Id) -> AResult<Vec<(Id, Vec<Span>)>> {
+        self.with_analysis(|a| Some(a.callers(id)))
+    }
+
+    /// Returns the functions referenced in the function body `body`, along with
+    /// the spans of the refs to each of them.
+    pub fn callees(&self, body: &Span) -> AResult<Vec<(Id, Vec<Span>)>> {
+        self.with_analysis(|a| Some(a.callees(body)))
+    }
+
     /// Search for a symbol name, returning a list of def_ids for that name.
     pub fn search_for_id(&self, name: &str) -> AResult<Vec<Id>> {
         self.with_analysis(|a| Some(a.with_def_names(name, Clone::clone)))
@@ -432,6 +497,12 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         self.with_analysis(|a| Some(a.idents(span)))
     }
 
+    /// Returns all identifiers in the given file, ordered by their position.
+    #[cfg(feature = "idents")]
+    pub fn file_idents(&self, file: &Path) -> AResult<Vec<Ident>> {
+        self.with_analysis(|a| Some(a.file_idents(file)))
+    }
+
     pub fn symbols(&self, file_name: &Path) -> AResult<Vec<SymbolResult>> {
         self.with_analysis(|a| {
             a.with_defs_per_file(file_name, |ids| {
@@ -1,7 +1,9 @@
 //! For processing the raw save-analysis data from rustc into the rls
 //! in-memory representation.
 
-use crate::analysis::{Def, Glob, PerCrateAnalysis, Ref};
+use crate::analysis::{
+    Attribute, Def, Glob, PerCrateAnalysis, Ref, SigElement, Signature, TypeRelation,
+};
 #[cfg(feature = "idents")]
 use crate::analysis::{IdentBound, IdentKind, IdentsByColumn, IdentsByLine};
 use crate::loader::AnalysisLoader;
@@ -9,8 +11,9 @@ use crate::raw::{self, CrateId, DefKind, RelationKind};
 use crate::util;
 use crate::{AResult, AnalysisHost, Id, Span, NULL};
 
-use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::iter::Extend;
 use std::path::{Path, PathBuf};
 use std::time::Instant;
@@ -178,7 +181,8 @@ impl<'a> CrateReader<'a> {
         reader.read_defs(krate.analysis.defs, &mut per_crate, is_distro_crate, project_analysis);
         reader.read_imports(krate.analysis.imports, &mut per_crate, project_analysis);
         reader.read_refs(krate.analysis.refs, &mut per_crate, project_analysis);
-        reader.read_impls(krate.analysis.relations, &mut per_crate, project_analysis);
+        reader.read_relations(krate.analysis.relations, &mut per_crate, project_analysis);
+        reader.read_macro_refs(krate.analysis.macro_refs, &mut per_crate, project_analysis);
         per_crate.global_crate_num = reader.crate_map[0];
 
         {
@@ -224,6 +228,59 @@ impl<'a> CrateReader<'a> {
         }
     }
 
+    fn read_macro_refs<L: AnalysisLoader>(
+        &self,
+        macro_refs: Vec<raw::MacroRef>,
+        analysis: &mut PerCrateAnalysis,
+        project_analysis: &AnalysisHost<L>,
+    ) {
+        for m in macro_refs {
+            if bad_span(&m.span, false) || bad_span(&m.callee_span, false) {
+                continue;
+            }
+            let name = macro_name(&m.qualname);
+            if name.is_empty() {
+                continue;
+            }
+
+            let callee_span = lower_span(&m.callee_span, &self.base_dir, &self.path_rewrite);
+            let id = macro_def_id(&m.qualname, &callee_span);
+            // Each crate has its own copy of the def, so that its refs don't
+            // go away when another crate using the macro is reloaded.
+            if !analysis.defs.contains_key(&id) {
+                let def = Def {
+                    kind: DefKind::Macro,
+                    span: callee_span.clone(),
+                    name: name.to_owned(),
+                    // The macro may be a builtin or procedural macro, rather
+                    // than `macro_rules!`.
+                    value: format!("{}!", name),
+                    qualname: m.qualname.clone(),
+                    distro_crate: false,
+                    parent: None,
+                    docs: String::new(),
+                    sig: None,
+                    attributes: vec![],
+                };
+                trace!("record macro def: {:?}: {:?}", id, def);
+                analysis.defs.insert(id, def);
+                analysis.macro_defs.insert(callee_span, id);
+                analysis.def_names.entry(name.to_owned()).or_insert_with(Vec::new).push(id);
+            }
+
+            // The span of a macro ref is the whole invocation, but we want refs
+            // to cover only the name of the macro, like other refs. We assume
+            // that the invocation starts with the name, so a qualified name,
+            // e.g., `std::println!`, is covered from the start of its path.
+            let callsite = lower_span(&m.span, &self.base_dir, &self.path_rewrite);
+            let row = callsite.range.row_start;
+            let col_start = callsite.range.col_start;
+            let col_end = span::Column::new_zero_indexed(col_start.0 + name.chars().count() as u32);
+            let span = Span::new(row, row, col_start, col_end, callsite.file);
+            self.record_ref(id, span, analysis, project_analysis);
+        }
+    }
+
     fn record_ref<L: AnalysisLoader>(
         &self,
         def_id: Id,
@@ -249,6 +306,13 @@ impl<'a> CrateReader<'a> {
             {
                 Self::record_ident(analysis, &span, def_id, IdentKind::Ref);
             }
+            let start = (span.range.row_start, span.range.col_start);
+            analysis
+                .refs_per_file
+                .entry(span.file.clone())
+                .or_insert_with(BTreeMap::new)
+                .entry(start)
+                .or_insert_with(|| (span.clone(), def_id));
             analysis.ref_spans.entry(def_id).or_insert_with(Vec::new).push(span);
         }
     }
@@ -391,7 +455,15 @@ impl<'a> CrateReader<'a> {
                     distro_crate,
                     parent,
                     docs: d.docs,
-                    // sig: d.sig.map(|ref s| self.lower_sig(s, &self.base_dir)),
+                    sig: d.sig.as_ref().map(|s| self.lower_sig(s)),
+                    attributes: d
+                        .attributes
+                        .into_iter()
+                        .map(|a| Attribute {
+                            value: a.value,
+                            span: lower_span(&a.span, &self.base_dir, &self.path_rewrite),
+                        })
+                        .collect(),
                 };
                 trace!(
                     "record def: {:?}/{:?} ({}): {:?}",
@@ -441,53 +513,57 @@ impl<'a> CrateReader<'a> {
         }
     }
 
-    fn read_impls<L: AnalysisLoader>(
+    fn read_relations<L: AnalysisLoader>(
         &self,
         relations: Vec<raw::Relation>,
         analysis: &mut PerCrateAnalysis,
         project_analysis: &AnalysisHost<L>,
     ) {
         for r in relations {
+            let from = self.id_from_compiler_id(r.from);
+            let to = self.id_from_compiler_id(r.to);
+            let from =
+                if from != NULL { abs_ref_id(from, analysis, project_analysis) } else { None };
+            let to = if to != NULL { abs_ref_id(to, analysis, project_analysis) } else { None };
+
             match r.kind {
-                RelationKind::Impl { .. } => {}
-                _ => continue,
-            }
-            let self_id = self.id_from_compiler_id(r.from);
-            let trait_id = self.id_from_compiler_id(r.to);
-            let span = lower_span(&r.span, &self.base_dir, &self.path_rewrite);
-            if self_id != NULL {
-                if let Some(self_id) = abs_ref_id(self_id, analysis, project_analysis) {
-                    trace!("record impl for self type {:?} {}", span, self_id);
-                    analysis.impls.entry(self_id).or_insert_with(Vec::new).push(span.clone());
+                RelationKind::Impl { .. } => {
+                    let span = lower_span(&r.span, &self.base_dir, &self.path_rewrite);
+                    if let Some(self_id) = from {
+                        trace!("record impl for self type {:?} {}", span, self_id);
+                        analysis.impls.entry(self_id).or_insert_with(Vec::new).push(span.clone());
+                    }
+                    if let Some(trait_id) = to {
+                        trace!("record impl for trait {:?} {}", span, trait_id);
+                        analysis.impls.entry(trait_id).or_insert_with(Vec::new).push(span);
+                    }
+                    if let (Some(self_id), Some(trait_id)) = (from, to) {
+                        analysis.relations.add_edge(TypeRelation::Impl, self_id, trait_id);
+                    }
                 }
-            }
-            if trait_id != NULL {
-                if let Some(trait_id) = abs_ref_id(trait_id, analysis, project_analysis) {
-                    trace!("record impl for trait {:?} {}", span, trait_id);
-                    analysis.impls.entry(trait_id).or_insert_with(Vec::new).push(span);
+                // The compiler records an edge from the supertrait to the
+                // trait which declares it.
+                RelationKind::SuperTrait => {
+                    if let (Some(super_id), Some(sub_id)) = (from, to) {
+                        trace!("record supertrait {} of {}", super_id, sub_id);
+                        analysis.relations.add_edge(TypeRelation::SuperTrait, sub_id, super_id);
+                    }
                 }
             }
         }
     }
 
-    // fn lower_sig(&self, raw_sig: &raw::Signature, base_dir: &Path) -> Signature {
-    //     Signature {
-    //         span: lower_span(&raw_sig.span, base_dir, &self.path_rewrite),
-    //         text: raw_sig.text.clone(),
-    //         ident_start: raw_sig.ident_start as u32,
-    //         ident_end: raw_sig.ident_end as u32,
-    //         defs: raw_sig.defs.iter().map(|se| self.lower_sig_element(se)).collect(),
-    //         refs: raw_sig.refs.iter().map(|se| self.lower_sig_element(se)).collect(),
-    //     }
-    // }
-
-    // fn lower_sig_element(&self, raw_se: &raw::SigElement) -> SigElement {
-    //     SigElement {
-    //         id: self.id_from_compiler_id(raw_se.id),
-    //         start: raw_se.start,
-    //         end: raw_se.end,
-    //     }
-    // }
+    fn lower_sig(&self, raw_sig: &raw::Signature) -> Signature {
+        Signature {
+            text: raw_sig.text.clone(),
+            defs: raw_sig.defs.iter().map(|se| self.lower_sig_element(se)).collect(),
+            refs: raw_sig.refs.iter().map(|se| self.lower_sig_element(se)).collect(),
+        }
+    }
+
+    fn lower_sig_element(&self, raw_se: &raw::SigElement) -> SigElement {
+        SigElement { id: self.id_from_compiler_id(raw_se.id), start: raw_se.start, end: raw_se.end }
+    }
 
     /// Recreates resulting crate-local (`u32`, `u32`) id from compiler
     /// to a global `u64` `Id`, mapping from a local to global crate id.
@@ -529,7 +605,56 @@ fn build_index(mut defs: Vec<(String, Id)>) -> (fst::Map, Vec<Vec<Id>>) {
     (fst, values)
 }
 
+/// Returns the name of a macro from its qualified name, e.g., `println` from
+/// `std::println!`.
+fn macro_name(qualname: &str) -> &str {
+    qualname.rsplit("::").next().unwrap_or(qualname).trim_end_matches('!')
+}
+
+/// Save-analysis has no ids for macros, so we make up the id of the def of
+/// a macro from its name and the span of its definition. That way, it is the
+/// same in every crate and across reloads. Made up ids are in a crate of their
+/// own, which doesn't collide with any real crate.
+fn macro_def_id(qualname: &str, callee_span: &Span) -> Id {
+    let mut hasher = DefaultHasher::new();
+    qualname.hash(&mut hasher);
+    callee_span.hash(&mut hasher);
+    // Avoid `NULL`.
+    let local_id = (hasher.finish() as u32).min(u32::MAX - 1);
+    Id::from_crate_and_local(u32::MAX, local_id)
+}
+
 fn bad_span(span: &raw::SpanData, is_mod: bool) -> bool {
     span.file_name.to_str().map(|s| s.ends_with('>')).unwrap_or(true)
         || (!is_mod && span.byte_start == 0 && span.byte_end == 0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn macro_names() {
+        assert_eq!(macro_name("std::println!"), "println");
+        assert_eq!(macro_name("foo!"), "foo");
+    }
+
+    #[test]
+    fn macro_def_ids() {
+        let span = |row| {
+            Span::new(
+                span::Row::new_zero_indexed(row),
+                span::Row::new_zero_indexed(row + 2),
+                span::Column::new_zero_indexed(0),
+                span::Column::new_zero_indexed(1),
+                "/src/lib.rs",
+            )
+        };
+        // The same for every crate using the macro.
+        assert_eq!(macro_def_id("debug", &span(0)), macro_def_id("debug", &span(0)));
+        // Macros of the same name are told apart by their definitions.
+        assert_ne!(macro_def_id("debug", &span(0)), macro_def_id("debug", &span(10)));
+        assert_ne!(macro_def_id("debug", &span(0)), macro_def_id("info", &span(0)));
+        assert_ne!(macro_def_id("debug", &span(0)), NULL);
+    }
+}
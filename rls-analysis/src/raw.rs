@@ -1,8 +1,8 @@
 use data::config::Config;
 use data::Analysis;
 pub use data::{
-    CratePreludeData, Def, DefKind, GlobalCrateId as CrateId, Import, Ref, Relation, RelationKind,
-    SigElement, Signature, SpanData,
+    CratePreludeData, Def, DefKind, GlobalCrateId as CrateId, Import, MacroRef, Ref, Relation,
+    RelationKind, SigElement, Signature, SpanData,
 };
 
 use std::collections::HashMap;
@@ -1,6 +1,7 @@
 use crate::loader::SearchDirectory;
 use crate::raw::DefKind;
-use crate::{AnalysisHost, AnalysisLoader};
+use crate::{AnalysisHost, AnalysisLoader, Span};
+use span::{Column, Row};
 
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
@@ -373,3 +374,131 @@ fn test_all_ref_unique() {
     let refs = host.find_all_refs(&spans[2], true, true);
     assert_eq!(refs.unwrap().len(), 3);
 }
+
+#[test]
+fn test_type_def() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/types/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/types"), Path::new("test_data/types")).unwrap();
+
+    let foo = host.get_def(host.search_for_id("Foo").unwrap()[0]).unwrap();
+
+    // Locals and parameters of type `Foo`, as well as a `u8` field.
+    let spans = host.search("x").unwrap();
+    for span in &spans {
+        let type_def = host.type_def(span);
+        if span.range.row_start.0 < 30 {
+            assert_eq!(type_def.unwrap().span, foo.span);
+        } else {
+            assert!(type_def.is_err());
+        }
+    }
+
+    // Type defs resolve to themselves.
+    let spans = host.search("Foo").unwrap();
+    assert_eq!(host.type_def(&spans[1]).unwrap().span, foo.span);
+}
+
+#[test]
+fn test_call_hierarchy() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/hello/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/hello"), Path::new("test_data/hello")).unwrap();
+
+    let print_hello = host.search_for_id("print_hello").unwrap()[0];
+    let main = host.search_for_id("main").unwrap()[0];
+
+    let callers = host.callers(print_hello).unwrap();
+    assert_eq!(callers.len(), 1);
+    assert_eq!(callers[0].0, main);
+    assert_eq!(callers[0].1.len(), 1);
+    assert_eq!(callers[0].1[0].range.row_start.0, 6);
+
+    // The body of `main`.
+    let body = Span::new(
+        Row::new_zero_indexed(5),
+        Row::new_zero_indexed(7),
+        Column::new_zero_indexed(10),
+        Column::new_zero_indexed(1),
+        callers[0].1[0].file.clone(),
+    );
+    let callees = host.callees(&body).unwrap();
+    assert_eq!(callees.len(), 1);
+    assert_eq!(callees[0].0, print_hello);
+    assert_eq!(callees[0].1, callers[0].1);
+
+    // Refs before the body aren't included.
+    let body = Span::new(
+        Row::new_zero_indexed(6),
+        Row::new_zero_indexed(7),
+        Column::new_zero_indexed(10),
+        Column::new_zero_indexed(1),
+        body.file,
+    );
+    assert!(host.callees(&body).unwrap().is_empty());
+
+    assert!(host.callers(main).unwrap().is_empty());
+}
+
+#[test]
+fn test_trait_hierarchy() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rust-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/rust-analysis"), Path::new("test_data/rust-analysis"))
+        .unwrap();
+
+    let find_trait = |name: &str, qualname: &str| {
+        host.search_for_id(name)
+            .unwrap()
+            .into_iter()
+            .find(|id| host.get_def(*id).unwrap().qualname == qualname)
+            .unwrap()
+    };
+    let read = find_trait("Read", "::io::Read");
+    let buf_read = find_trait("BufRead", "::io::BufRead");
+
+    assert!(host.supertraits(buf_read).unwrap().contains(&read));
+    assert!(host.subtraits(read).unwrap().contains(&buf_read));
+    assert!(!host.subtraits(buf_read).unwrap().contains(&read));
+    assert!(!host.implementors(read).unwrap().is_empty());
+}
+
+#[test]
+fn test_attributes() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rls-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/rls-analysis"), Path::new("test_data/rls-analysis")).unwrap();
+
+    let to_writer = host
+        .search_for_id("to_writer")
+        .unwrap()
+        .into_iter()
+        .find(|id| host.get_def(*id).unwrap().qualname.ends_with("<JsonValue>::to_writer"))
+        .unwrap();
+    let attributes = host.attributes(to_writer).unwrap();
+    let deprecated = attributes.iter().find(|attr| attr.name() == "deprecated").unwrap();
+    assert_eq!(
+        deprecated.args(),
+        Some(r#"since = "0.10.2", note = "use `JsonValue::write` instead""#)
+    );
+
+    // `Span` is `#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]`, but
+    // rustc expands derives before save-analysis, so only the attributes the
+    // expansion leaves behind are recorded.
+    let span = host
+        .search_for_id("Span")
+        .unwrap()
+        .into_iter()
+        .find(|id| {
+            let def = host.get_def(*id).unwrap();
+            def.kind == DefKind::Struct && def.span.file.to_str().unwrap().contains("rls-span")
+        })
+        .unwrap();
+    let attributes = host.attributes(span).unwrap();
+    assert!(attributes.iter().any(|attr| attr.name() == "structural_match"));
+    assert!(attributes.iter().all(|attr| attr.name() != "derive"));
+}
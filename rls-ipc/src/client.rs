@@ -2,6 +2,7 @@
 
 use crate::rpc::callbacks::gen_client::Client as CallbacksClient;
 use crate::rpc::file_loader::gen_client::Client as FileLoaderClient;
+use crate::rpc::worker::gen_client::Client as WorkerClient;
 
 pub use jsonrpc_core_client::transports::ipc::connect;
 pub use jsonrpc_core_client::{RpcChannel, RpcError};
@@ -13,13 +14,16 @@ pub struct Client {
     pub file_loader: FileLoaderClient,
     /// Callbacks interface
     pub callbacks: CallbacksClient,
+    /// Worker interface
+    pub worker: WorkerClient,
 }
 
 impl From<RpcChannel> for Client {
     fn from(channel: RpcChannel) -> Self {
         Client {
             file_loader: FileLoaderClient::from(channel.clone()),
-            callbacks: CallbacksClient::from(channel),
+            callbacks: CallbacksClient::from(channel.clone()),
+            worker: WorkerClient::from(channel),
         }
     }
 }
@@ -1,6 +1,6 @@
 //! Available remote procedure call (RPC) interfaces.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
 
 use jsonrpc_derive::rpc;
@@ -46,12 +46,78 @@ pub mod callbacks {
         #[rpc(name = "complete_analysis")]
         fn complete_analysis(&self, analysis: rls_data::Analysis) -> Result<()>;
 
+        /// Starts handing back computed analysis data for the compiled crate
+        /// in chunks, rather than in one message. The header is the analysis
+        /// without any of the data in `AnalysisChunk`s, which follow in
+        /// `analysis_chunk` calls.
+        #[rpc(name = "begin_analysis")]
+        fn begin_analysis(&self, header: rls_data::Analysis) -> Result<()>;
+
+        /// Hands back a chunk of the analysis data started by `begin_analysis`.
+        #[rpc(name = "analysis_chunk")]
+        fn analysis_chunk(&self, chunk: AnalysisChunk) -> Result<()>;
+
+        /// Finishes handing back the analysis data started by
+        /// `begin_analysis`. Until then, the analysis data is incomplete.
+        #[rpc(name = "end_analysis")]
+        fn end_analysis(&self) -> Result<()>;
+
+        /// Hands back a diagnostic, in rustc's JSON format, as soon as it's
+        /// emitted, i.e., before the compilation finishes
+        #[rpc(name = "diagnostic")]
+        fn diagnostic(&self, diagnostic: String) -> Result<()>;
+
         /// Hands back computed input files for the compiled crate
         #[rpc(name = "input_files")]
         fn input_files(&self, input_files: HashMap<PathBuf, HashSet<Crate>>) -> Result<()>;
     }
 }
 
+// Separated because #[rpc] macro generated a `gen_client` mod and so two
+// interfaces cannot be derived in the same scope due to a generated name clash
+/// RPC interface for long-lived `rustc` workers to fetch compile jobs.
+pub mod worker {
+    use super::*;
+    // Expanded via #[rpc]
+    pub use gen_client::Client;
+    pub use rpc_impl_Rpc::gen_server::Rpc as Server;
+
+    #[rpc]
+    /// RPC interface for long-lived `rustc` workers to fetch compile jobs.
+    pub trait Rpc {
+        /// Waits for the next compile job. Returns `None` once the worker
+        /// should exit.
+        #[rpc(name = "next_job")]
+        fn next_job(&self) -> Result<Option<CompileJob>>;
+
+        /// Hands back the outcome of the current compile job
+        #[rpc(name = "finish_job")]
+        fn finish_job(&self, outcome: JobOutcome) -> Result<()>;
+    }
+}
+
+/// A single `rustc` invocation for a worker to run
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CompileJob {
+    /// Command line arguments, excluding the program name
+    pub args: Vec<String>,
+    /// Environment variables to set, or to unset if `None`
+    pub envs: BTreeMap<String, Option<String>>,
+    /// Directory to compile in, or the worker's own if `None`
+    pub cwd: Option<PathBuf>,
+}
+
+/// Outcome of a `CompileJob`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JobOutcome {
+    /// Whether compilation was successful
+    pub success: bool,
+    /// Diagnostics emitted by `rustc`, in the JSON format
+    pub stderr: String,
+    /// Whether `rustc` crashed, after which the worker exits
+    pub crashed: bool,
+}
+
 /// Build system-agnostic, basic compilation unit
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Deserialize, Serialize)]
 pub struct Crate {
@@ -76,3 +142,118 @@ pub enum Edition {
     /// Rust 2021
     Edition2021,
 }
+
+/// The maximum number of items in an `AnalysisChunk`, which keeps messages to
+/// a reasonable size.
+pub const ANALYSIS_CHUNK_LEN: usize = 4096;
+
+/// Part of the analysis data for a crate, handed back in a separate message.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum AnalysisChunk {
+    /// Imports
+    Imports(Vec<rls_data::Import>),
+    /// Definitions
+    Defs(Vec<rls_data::Def>),
+    /// Impls
+    Impls(Vec<rls_data::Impl>),
+    /// References
+    Refs(Vec<rls_data::Ref>),
+    /// Macro references
+    MacroRefs(Vec<rls_data::MacroRef>),
+    /// Relations
+    Relations(Vec<rls_data::Relation>),
+}
+
+impl AnalysisChunk {
+    /// Splits `analysis` into a header and chunks of at most `chunk_len`
+    /// items, which `append_to` puts back together.
+    pub fn split(
+        mut analysis: rls_data::Analysis,
+        chunk_len: usize,
+    ) -> (rls_data::Analysis, Vec<AnalysisChunk>) {
+        fn chunks<T>(
+            items: Vec<T>,
+            chunk_len: usize,
+            chunk: fn(Vec<T>) -> AnalysisChunk,
+            result: &mut Vec<AnalysisChunk>,
+        ) {
+            let mut items = items.into_iter().peekable();
+            while items.peek().is_some() {
+                result.push(chunk(items.by_ref().take(chunk_len).collect()));
+            }
+        }
+
+        let chunk_len = chunk_len.max(1);
+        let mut result = vec![];
+        let a = &mut analysis;
+        chunks(std::mem::take(&mut a.imports), chunk_len, AnalysisChunk::Imports, &mut result);
+        chunks(std::mem::take(&mut a.defs), chunk_len, AnalysisChunk::Defs, &mut result);
+        chunks(std::mem::take(&mut a.impls), chunk_len, AnalysisChunk::Impls, &mut result);
+        chunks(std::mem::take(&mut a.refs), chunk_len, AnalysisChunk::Refs, &mut result);
+        chunks(std::mem::take(&mut a.macro_refs), chunk_len, AnalysisChunk::MacroRefs, &mut result);
+        chunks(std::mem::take(&mut a.relations), chunk_len, AnalysisChunk::Relations, &mut result);
+        (analysis, result)
+    }
+
+    /// Adds the items in this chunk to `analysis`.
+    pub fn append_to(self, analysis: &mut rls_data::Analysis) {
+        match self {
+            AnalysisChunk::Imports(items) => analysis.imports.extend(items),
+            AnalysisChunk::Defs(items) => analysis.defs.extend(items),
+            AnalysisChunk::Impls(items) => analysis.impls.extend(items),
+            AnalysisChunk::Refs(items) => analysis.refs.extend(items),
+            AnalysisChunk::MacroRefs(items) => analysis.macro_refs.extend(items),
+            AnalysisChunk::Relations(items) => analysis.relations.extend(items),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rls_data::{Analysis, Id, MacroRef, Ref, RefKind, Relation, RelationKind, SpanData};
+    use rls_span::{Column, Row};
+
+    fn span(line: u32) -> SpanData {
+        SpanData {
+            file_name: PathBuf::from("src/lib.rs"),
+            byte_start: line * 10,
+            byte_end: line * 10 + 4,
+            line_start: Row::new_one_indexed(line),
+            line_end: Row::new_one_indexed(line),
+            column_start: Column::new_one_indexed(1),
+            column_end: Column::new_one_indexed(5),
+        }
+    }
+
+    fn analysis() -> Analysis {
+        let id = |index| Id { krate: 0, index };
+        let mut analysis = Analysis::new(Default::default());
+        analysis.refs =
+            (1..6).map(|i| Ref { kind: RefKind::Function, span: span(i), ref_id: id(i) }).collect();
+        analysis.macro_refs = (1..3)
+            .map(|i| MacroRef { span: span(i), qualname: format!("m{}", i), callee_span: span(0) })
+            .collect();
+        analysis.relations = vec![Relation {
+            span: span(7),
+            kind: RelationKind::SuperTrait,
+            from: id(1),
+            to: id(2),
+        }];
+        analysis
+    }
+
+    #[test]
+    fn split_and_append_round_trip() {
+        let expected = format!("{:?}", analysis());
+        for &(chunk_len, chunk_count) in &[(1, 8), (ANALYSIS_CHUNK_LEN, 3)] {
+            let (mut header, chunks) = AnalysisChunk::split(analysis(), chunk_len);
+            assert!(header.refs.is_empty() && header.macro_refs.is_empty());
+            assert_eq!(chunks.len(), chunk_count);
+            for chunk in chunks {
+                chunk.append_to(&mut header);
+            }
+            assert_eq!(format!("{:?}", header), expected);
+        }
+    }
+}
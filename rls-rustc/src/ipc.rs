@@ -1,5 +1,5 @@
 use std::collections::{HashMap, HashSet};
-use std::io;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 
 use futures::Future;
@@ -7,6 +7,8 @@ use futures::Future;
 use rls_ipc::client::{Client as JointClient, RpcChannel, RpcError};
 use rls_ipc::rpc::callbacks::Client as CallbacksClient;
 use rls_ipc::rpc::file_loader::Client as FileLoaderClient;
+use rls_ipc::rpc::worker::Client as WorkerClient;
+use rls_ipc::rpc::{AnalysisChunk, CompileJob, JobOutcome, ANALYSIS_CHUNK_LEN};
 
 pub use rls_ipc::client::connect;
 
@@ -45,11 +47,24 @@ impl rustc_span::source_map::FileLoader for IpcFileLoader {
 pub struct IpcCallbacks(CallbacksClient);
 
 impl IpcCallbacks {
-    pub fn complete_analysis(
-        &self,
-        analysis: rls_data::Analysis,
-    ) -> impl Future<Item = (), Error = RpcError> {
-        self.0.complete_analysis(analysis)
+    /// Hands back analysis data in chunks, so that neither side has to
+    /// serialize it all at once. Falls back to a single message if the RLS
+    /// doesn't support chunks.
+    pub fn send_analysis(&self, analysis: rls_data::Analysis) -> Result<(), RpcError> {
+        let (header, chunks) = AnalysisChunk::split(analysis, ANALYSIS_CHUNK_LEN);
+        if let Err(e) = self.0.begin_analysis(header.clone()).wait() {
+            log::debug!("Can't send analysis in chunks, sending it whole: {:?}", e);
+            let mut analysis = header;
+            for chunk in chunks {
+                chunk.append_to(&mut analysis);
+            }
+            return self.0.complete_analysis(analysis).wait();
+        }
+
+        for chunk in chunks {
+            self.0.analysis_chunk(chunk).wait()?;
+        }
+        self.0.end_analysis().wait()
     }
 
     pub fn input_files(
@@ -60,9 +75,142 @@ impl IpcCallbacks {
     }
 }
 
+#[derive(Clone)]
+pub struct IpcWorker(WorkerClient);
+
+impl IpcWorker {
+    pub fn next_job(&self) -> Result<Option<CompileJob>, RpcError> {
+        self.0.next_job().wait()
+    }
+
+    pub fn finish_job(&self, outcome: JobOutcome) -> Result<(), RpcError> {
+        self.0.finish_job(outcome).wait()
+    }
+}
+
+/// Where `DiagnosticWriter` hands back each diagnostic.
+pub trait DiagnosticSink {
+    fn send_diagnostic(&self, diagnostic: String) -> Result<(), RpcError>;
+}
+
+impl DiagnosticSink for IpcCallbacks {
+    fn send_diagnostic(&self, diagnostic: String) -> Result<(), RpcError> {
+        self.0.diagnostic(diagnostic).wait()
+    }
+}
+
+/// Writes diagnostics to `out`, by default stderr as rustc does, and also
+/// hands each one back to the RLS as soon as it's emitted.
+pub struct DiagnosticWriter<W = io::Stderr, S = IpcCallbacks> {
+    sink: S,
+    out: W,
+    line: Vec<u8>,
+    // Cleared if the RLS doesn't support diagnostics before the compilation
+    // finishes. It still gets them from stderr afterwards.
+    enabled: bool,
+}
+
+impl DiagnosticWriter {
+    pub fn new(callbacks: IpcCallbacks) -> DiagnosticWriter {
+        DiagnosticWriter::with_output(callbacks, io::stderr())
+    }
+}
+
+impl<W: Write, S: DiagnosticSink> DiagnosticWriter<W, S> {
+    pub fn with_output(sink: S, out: W) -> DiagnosticWriter<W, S> {
+        DiagnosticWriter { sink, out, line: vec![], enabled: true }
+    }
+
+    fn send_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        if !self.enabled || line.trim().is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.sink.send_diagnostic(line) {
+            log::debug!("Can't send diagnostics before the compilation finishes: {:?}", e);
+            self.enabled = false;
+        }
+    }
+}
+
+impl<W: Write, S: DiagnosticSink> Write for DiagnosticWriter<W, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.write_all(buf)?;
+        // The JSON emitter writes one diagnostic per line.
+        for &byte in buf {
+            if byte == b'\n' {
+                self.send_line();
+            } else {
+                self.line.push(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
 impl Client {
     pub fn split(self) -> (IpcFileLoader, IpcCallbacks) {
-        let JointClient { file_loader, callbacks } = self.0;
+        let JointClient { file_loader, callbacks, .. } = self.0;
         (IpcFileLoader(file_loader), IpcCallbacks(callbacks))
     }
+
+    pub fn worker(&self) -> IpcWorker {
+        IpcWorker(self.0.worker.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct Sink {
+        sent: RefCell<Vec<String>>,
+        fail: bool,
+    }
+
+    impl DiagnosticSink for &Sink {
+        fn send_diagnostic(&self, diagnostic: String) -> Result<(), RpcError> {
+            self.sent.borrow_mut().push(diagnostic);
+            if self.fail {
+                Err(RpcError::Timeout)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn diagnostic_writer_splits_lines() {
+        let sink = Sink::default();
+        let mut out = vec![];
+        {
+            let mut writer = DiagnosticWriter::with_output(&sink, &mut out);
+            writer.write_all(b"{\"a\":").unwrap();
+            writer.write_all(b"1}\n\n  \n{\"b\":2}\n{\"c\"").unwrap();
+            writer.write_all(b":3}").unwrap();
+            writer.flush().unwrap();
+        }
+
+        // Blank lines and the trailing partial line aren't sent, but
+        // everything is still written out.
+        assert_eq!(*sink.sent.borrow(), vec!["{\"a\":1}", "{\"b\":2}"]);
+        assert_eq!(out, b"{\"a\":1}\n\n  \n{\"b\":2}\n{\"c\":3}".to_vec());
+    }
+
+    #[test]
+    fn diagnostic_writer_stops_sending_after_an_error() {
+        let sink = Sink { fail: true, ..Sink::default() };
+        let mut out = vec![];
+        let mut writer = DiagnosticWriter::with_output(&sink, &mut out);
+        writer.write_all(b"first\nsecond\n").unwrap();
+        assert_eq!(*sink.sent.borrow(), vec!["first"]);
+    }
 }
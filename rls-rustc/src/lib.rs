@@ -15,8 +15,10 @@ use rustc_interface::interface;
 use rustc_interface::Queries;
 use rustc_session::config::ErrorOutputType;
 use rustc_session::early_error;
+use rustc_span::source_map::FileLoader;
 
 use std::env;
+use std::io::Write;
 #[allow(unused_imports)]
 use std::path::{Path, PathBuf};
 
@@ -24,6 +26,8 @@ use std::path::{Path, PathBuf};
 mod clippy;
 #[cfg(feature = "ipc")]
 mod ipc;
+#[cfg(feature = "ipc")]
+mod worker;
 
 pub fn run() -> Result<(), ()> {
     #[cfg(feature = "ipc")]
@@ -40,6 +44,11 @@ pub fn run() -> Result<(), ()> {
                 ipc::connect(endpoint, &reactor).expect("Couldn't connect to IPC endpoint");
             let client: ipc::Client =
                 rt.block_on(connection).expect("Couldn't connect to IPC endpoint");
+            // Compile crates as the RLS hands them out, rather than the one
+            // on our command line.
+            if env::var_os("RLS_IPC_WORKER").is_some() {
+                return worker::run(client);
+            }
             let (file_loader, callbacks) = client.split();
 
             (
@@ -56,6 +65,16 @@ pub fn run() -> Result<(), ()> {
     #[cfg(not(feature = "ipc"))]
     let (mut shim_calls, file_loader) = (ShimCalls::default(), None);
 
+    // Hand back diagnostics as they're emitted, so that the RLS can show them
+    // before the compilation finishes.
+    #[cfg(feature = "ipc")]
+    let emitter = shim_calls
+        .callbacks
+        .clone()
+        .map(|callbacks| Box::new(ipc::DiagnosticWriter::new(callbacks)) as Box<dyn Write + Send>);
+    #[cfg(not(feature = "ipc"))]
+    let emitter = None;
+
     let args = env::args_os()
         .enumerate()
         .map(|(i, arg)| {
@@ -75,12 +94,23 @@ pub fn run() -> Result<(), ()> {
     };
 
     rustc_driver::install_ice_hook();
+    compile(&args, &mut shim_calls, file_loader, emitter).map(|_| ())
+}
+
+/// Runs the compiler, returning whether the compilation succeeded, or an error
+/// if it failed fatally.
+fn compile(
+    args: &[String],
+    shim_calls: &mut ShimCalls,
+    file_loader: Option<Box<dyn FileLoader + Send + Sync>>,
+    emitter: Option<Box<dyn Write + Send>>,
+) -> Result<bool, ()> {
     rustc_driver::catch_fatal_errors(move || {
-        let mut compiler = RunCompiler::new(&args, &mut shim_calls);
-        compiler.set_file_loader(file_loader);
+        let mut compiler = RunCompiler::new(args, shim_calls);
+        compiler.set_file_loader(file_loader).set_emitter(emitter);
         compiler.run()
     })
-    .map(|_| ())
+    .map(|result| result.is_ok())
     .map_err(|_| ())
 }
 
@@ -162,8 +192,6 @@ impl Callbacks for ShimCalls {
         compiler: &interface::Compiler,
         queries: &'tcx Queries<'tcx>,
     ) -> Compilation {
-        use futures::future::Future;
-
         let callbacks = match self.callbacks.as_ref() {
             Some(callbacks) => callbacks,
             None => return Compilation::Continue,
@@ -196,7 +224,7 @@ impl Callbacks for ShimCalls {
                 CallbackHandler {
                     callback: &mut |a| {
                         let analysis = unsafe { ::std::mem::transmute(a.clone()) };
-                        if let Err(e) = callbacks.complete_analysis(analysis).wait() {
+                        if let Err(e) = callbacks.send_analysis(analysis) {
                             log::error!(
                                 "Can't send analysis as part of a compilation callback: {:?}",
                                 e
@@ -0,0 +1,158 @@
+//! Long-lived worker mode, in which the shim compiles crate after crate as the
+//! RLS hands out compile jobs, so that starting up is only paid for once.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use rls_ipc::rpc::{CompileJob, JobOutcome};
+
+use crate::ipc::{Client, DiagnosticWriter};
+use crate::{compile, ShimCalls};
+
+/// Compiles jobs until the RLS has no more work for this worker. Returns an
+/// error after an ICE, since the compiler's global state can no longer be
+/// trusted; the RLS starts a new worker instead.
+pub fn run(client: Client) -> Result<(), ()> {
+    let worker = client.worker();
+    let (file_loader, callbacks) = client.split();
+
+    let base_envs: HashMap<OsString, OsString> = env::vars_os().collect();
+    let base_cwd = env::current_dir().ok();
+    let program = env::args().next().unwrap_or_else(|| "rustc".to_owned());
+
+    rustc_driver::install_ice_hook();
+    loop {
+        let job = match worker.next_job() {
+            Ok(Some(job)) => job,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                log::error!("Can't fetch the next compile job: {:?}", e);
+                return Err(());
+            }
+        };
+
+        set_envs(&base_envs, &job.envs);
+        // Rustc resolves relative paths, e.g., of the crate root, against the
+        // working directory of the session.
+        if let Err(e) = set_cwd(&base_cwd, &job) {
+            log::error!("Can't change to the directory of a compile job: {}", e);
+            let outcome = JobOutcome { success: false, stderr: String::new(), crashed: false };
+            if let Err(e) = worker.finish_job(outcome) {
+                log::error!("Can't hand back the outcome of a compile job: {:?}", e);
+                return Err(());
+            }
+            continue;
+        }
+
+        let args: Vec<_> = Some(program.clone()).into_iter().chain(job.args).collect();
+        #[cfg(feature = "clippy")]
+        let clippy_preference = crate::clippy::preference();
+        #[cfg(feature = "clippy")]
+        let args = match clippy_preference {
+            Some(preference) => crate::clippy::adjust_args(args, preference),
+            None => args,
+        };
+        let mut shim_calls = ShimCalls {
+            callbacks: Some(callbacks.clone()),
+            #[cfg(feature = "clippy")]
+            clippy_preference,
+        };
+
+        let stderr = Arc::default();
+        let emitter = DiagnosticWriter::with_output(callbacks.clone(), Buffer(Arc::clone(&stderr)));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            compile(
+                &args,
+                &mut shim_calls,
+                file_loader.clone().into_boxed(),
+                Some(Box::new(emitter)),
+            )
+        }));
+
+        let (success, crashed) = match result {
+            Ok(result) => (result == Ok(true), false),
+            Err(_) => (false, true),
+        };
+        let stderr = String::from_utf8_lossy(&stderr.lock().unwrap()).into_owned();
+        if let Err(e) = worker.finish_job(JobOutcome { success, stderr, crashed }) {
+            log::error!("Can't hand back the outcome of a compile job: {:?}", e);
+            return Err(());
+        }
+        if crashed {
+            return Err(());
+        }
+    }
+}
+
+/// Sets the environment to the one the worker started with, updated with `envs`.
+fn set_envs(base_envs: &HashMap<OsString, OsString>, envs: &BTreeMap<String, Option<String>>) {
+    for (key, _) in env::vars_os() {
+        if !base_envs.contains_key(&key) {
+            env::remove_var(key);
+        }
+    }
+    for (key, value) in base_envs {
+        env::set_var(key, value);
+    }
+
+    for (key, value) in envs {
+        match value {
+            Some(value) => env::set_var(key, value),
+            None => env::remove_var(key),
+        }
+    }
+}
+
+/// Changes to the directory of `job`, or to the one the worker started in.
+fn set_cwd(base_cwd: &Option<PathBuf>, job: &CompileJob) -> io::Result<()> {
+    match job.cwd.as_ref().or_else(|| base_cwd.as_ref()) {
+        Some(cwd) => env::set_current_dir(cwd),
+        None => Ok(()),
+    }
+}
+
+// A threadsafe buffer for the diagnostics of a compile job.
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn relative_crate_root() {
+        let dir = env::temp_dir().join(format!("rls-rustc-test-cwd-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let base_cwd = env::current_dir().ok();
+        let job = |cwd| CompileJob {
+            args: vec!["--crate-name".to_owned(), "foo".to_owned(), "src/lib.rs".to_owned()],
+            envs: BTreeMap::new(),
+            cwd,
+        };
+
+        set_cwd(&base_cwd, &job(Some(dir.clone()))).unwrap();
+        let crate_root = crate::src_path(None, "src/lib.rs");
+        // A job without a directory compiles in the one the worker started in.
+        set_cwd(&base_cwd, &job(None)).unwrap();
+        let cwd = env::current_dir().ok();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(crate_root, Some(dir.join("src/lib.rs")));
+        assert_eq!(cwd, base_cwd);
+    }
+}
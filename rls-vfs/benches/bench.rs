@@ -28,7 +28,7 @@ fn add_file(vfs: &Vfs, path: &Path) {
     let mut buf = String::new();
     let mut file = fs::File::open(path).unwrap();
     file.read_to_string(&mut buf).unwrap();
-    let change = Change::AddFile { file: path.to_owned(), text: buf };
+    let change = Change::AddFile { file: path.to_owned(), text: buf, version: None };
     vfs.on_changes(&[change]).unwrap();
 }
 
@@ -45,6 +45,7 @@ fn make_change_(path: &Path, start_line: usize, interval: usize) -> Change {
     Change::ReplaceText {
         span: VfsSpan::from_usv(Span::from_positions(start, end, path), None),
         text: buf,
+        version: None,
     }
 }
 
@@ -130,3 +131,77 @@ fn insert_tale(b: &mut test::Bencher) {
         }
     })
 }
+
+/// A generated file about as long as the biggest generated sources we see in
+/// practice, so the benches don't depend on rust-src being installed.
+fn prepare_large() -> (Vfs, PathBuf) {
+    const LINES: usize = 20_000;
+    let path = PathBuf::from("/large_generated.rs");
+    let text = (0..LINES)
+        .map(|i| format!("    pub const ITEM_{}: &str = \"generated item number {}\";\n", i, i))
+        .collect::<String>();
+    let vfs = Vfs::new();
+    vfs.set_file(&path, &text);
+    (vfs, path)
+}
+
+/// Types a single character at the start of `line`, as an editor does on
+/// every keystroke.
+fn make_keystroke(path: &Path, line: usize) -> Change {
+    let pos = Position::new(Row::new_zero_indexed(line as u32), Column::new_zero_indexed(0));
+    Change::ReplaceText {
+        span: VfsSpan::from_usv(Span::from_positions(pos, pos, path), Some(0)),
+        text: "x".to_owned(),
+        version: None,
+    }
+}
+
+#[bench]
+fn keystroke_large_front(b: &mut test::Bencher) {
+    let (vfs, path) = prepare_large();
+    b.iter(|| {
+        for _ in 0..10 {
+            vfs.on_changes(&[make_keystroke(&path, 0)]).unwrap();
+        }
+    })
+}
+
+#[bench]
+fn keystroke_large_mid(b: &mut test::Bencher) {
+    let (vfs, path) = prepare_large();
+    b.iter(|| {
+        for _ in 0..10 {
+            vfs.on_changes(&[make_keystroke(&path, 10_000)]).unwrap();
+        }
+    })
+}
+
+#[bench]
+fn keystroke_large_tail(b: &mut test::Bencher) {
+    let (vfs, path) = prepare_large();
+    b.iter(|| {
+        for _ in 0..10 {
+            vfs.on_changes(&[make_keystroke(&path, 19_999)]).unwrap();
+        }
+    })
+}
+
+#[bench]
+fn replace_large_mid(b: &mut test::Bencher) {
+    let (vfs, path) = prepare_large();
+    b.iter(|| {
+        for _ in 0..10 {
+            vfs.on_changes(&[make_replace(&path, 10_000)]).unwrap();
+        }
+    })
+}
+
+#[bench]
+fn load_line_large(b: &mut test::Bencher) {
+    let (vfs, path) = prepare_large();
+    b.iter(|| {
+        for line in (0..20_000).step_by(2_000) {
+            test::black_box(vfs.load_line(&path, Row::new_zero_indexed(line)).unwrap());
+        }
+    })
+}
@@ -6,16 +6,23 @@ extern crate log;
 
 use std::collections::HashMap;
 use std::fmt;
-use std::fs;
-use std::io::Read;
-use std::marker::PhantomData;
 use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(all(feature = "watcher", target_os = "linux"))]
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 use std::thread::{self, Thread};
 
+pub use crate::loader::{FileLoader, MemoryFileLoader, OverlayFileLoader, RealFileLoader};
+use crate::rope::{Metric, Rope};
+
+mod loader;
+mod rope;
 #[cfg(test)]
 mod test;
+#[cfg(all(feature = "watcher", target_os = "linux"))]
+mod watcher;
 
 macro_rules! try_opt_loc {
     ($e:expr) => {
@@ -26,7 +33,7 @@ macro_rules! try_opt_loc {
     };
 }
 
-pub struct Vfs<U = ()>(VfsInternal<RealFileLoader, U>);
+pub struct Vfs<U = ()>(VfsInternal<Box<dyn FileLoader>, U>);
 
 /// Span of the text to be replaced defined in col/row terms.
 #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -87,13 +94,22 @@ impl VfsSpan {
 #[derive(Debug)]
 pub enum Change {
     /// Create an in-memory image of the file.
-    AddFile { file: PathBuf, text: String },
+    AddFile {
+        file: PathBuf,
+        text: String,
+        /// Version of the file after the change, if the client keeps track.
+        version: Option<u64>,
+    },
     /// Changes in-memory contents of the previously added file.
     ReplaceText {
         /// Span of the text to be replaced.
         span: VfsSpan,
         /// Text to replace specified text range with.
         text: String,
+        /// Version of the file after the change, if the client keeps track.
+        /// Changes which the client made together may all have the same
+        /// version.
+        version: Option<u64>,
     },
 }
 
@@ -104,6 +120,12 @@ impl Change {
             Change::ReplaceText { ref span, .. } => span.span().file.as_ref(),
         }
     }
+
+    fn version(&self) -> Option<u64> {
+        match *self {
+            Change::AddFile { version, .. } | Change::ReplaceText { version, .. } => version,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -117,6 +139,11 @@ pub enum Error {
     /// Client specified a location that is not within a file. I.e., a row or
     /// column not in the file.
     BadLocation,
+    /// A change to the given file is not newer than the version of the file,
+    /// i.e., it is out of order or a duplicate, or (with strict versions)
+    /// changes are missing. 2nd arg is the version of the file, 3rd arg is the
+    /// version of the change.
+    UnexpectedVersion(PathBuf, u64, u64),
     /// The requested file was not cached in the VFS.
     FileNotCached,
     /// Not really an error, file is cached but there is no user data for it.
@@ -136,6 +163,7 @@ impl Error {
                 "changes exist which have not been written to disk"
             }
             Error::BadLocation => "client specified location not existing within a file",
+            Error::UnexpectedVersion(..) => "change does not follow the file version",
             Error::FileNotCached => "requested file was not cached in the VFS",
             Error::NoUserDataForFile => "file is cached but there is no user data for it",
             Error::BadFileKind => {
@@ -161,6 +189,13 @@ impl fmt::Display for Error {
             Error::UncommittedChanges(ref path_buf) => {
                 write!(f, "{} has uncommitted changes", path_buf.display())
             }
+            Error::UnexpectedVersion(ref path_buf, file_version, change_version) => write!(
+                f,
+                "change to {} has version {}, but the file has version {}",
+                path_buf.display(),
+                change_version,
+                file_version
+            ),
             Error::InternalError(e) => write!(f, "internal error: {}", e),
             Error::BadLocation
             | Error::FileNotCached
@@ -178,9 +213,14 @@ impl<U> Default for Vfs<U> {
 }
 
 impl<U> Vfs<U> {
-    /// Creates a new, empty VFS.
+    /// Creates a new, empty VFS which loads files from disk.
     pub fn new() -> Vfs<U> {
-        Vfs(VfsInternal::<RealFileLoader, U>::new())
+        Vfs::with_loader(RealFileLoader)
+    }
+
+    /// Creates a new, empty VFS which loads files using `loader`.
+    pub fn with_loader<L: FileLoader + 'static>(loader: L) -> Vfs<U> {
+        Vfs(VfsInternal::new(Box::new(loader)))
     }
 
     /// Indicate that the current file as known to the VFS has been written to
@@ -189,6 +229,13 @@ impl<U> Vfs<U> {
         self.0.file_saved(path)
     }
 
+    /// Indicate that the client has closed the file. If the client didn't
+    /// change it from the file on disk, the VFS becomes responsible for the
+    /// file again, so it may be evicted. Otherwise, the changes are kept.
+    pub fn file_closed(&self, path: &Path) {
+        self.0.file_closed(path)
+    }
+
     /// Removes a file from the VFS. Does not check if the file is synced with
     /// the disk. Does not check if the file exists.
     pub fn flush_file(&self, path: &Path) -> Result<(), Error> {
@@ -199,7 +246,9 @@ impl<U> Vfs<U> {
         self.0.file_is_synced(path)
     }
 
-    /// Record a set of changes to the VFS.
+    /// Record a set of changes to the VFS. If a change has a version, it must
+    /// be newer than the version of the file, otherwise none of the changes to
+    /// that file are made.
     pub fn on_changes(&self, changes: &[Change]) -> Result<(), Error> {
         self.0.on_changes(changes)
     }
@@ -218,8 +267,47 @@ impl<U> Vfs<U> {
         self.0.has_changes()
     }
 
+    /// Limits the memory used by cached files to about `limit` bytes, by
+    /// evicting the least recently used files which the client hasn't changed
+    /// or opened. Files which the client is responsible for are never evicted,
+    /// so may take the VFS over the limit. `None`, the default, means no limit.
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.0.set_memory_limit(limit)
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.0.cache_stats()
+    }
+
+    /// Makes `on_changes` also reject versioned changes which skip versions,
+    /// i.e., which don't follow on from the version of the file exactly. Off
+    /// by default, since clients only have to increase versions (e.g., LSP
+    /// clients may skip versions).
+    pub fn set_strict_versions(&self, strict: bool) {
+        self.0.set_strict_versions(strict)
+    }
+
     pub fn set_file(&self, path: &Path, text: &str) {
-        self.0.set_file(path, text)
+        self.0.set_file(path, text, None)
+    }
+
+    /// Sets the text of a file, whatever its previous text and version. The
+    /// version is that of the file as known to the client, e.g., the version
+    /// of an LSP document.
+    pub fn set_file_with_version(&self, path: &Path, text: &str, version: u64) {
+        self.0.set_file(path, text, Some(version))
+    }
+
+    /// Returns the version of the file as known to the client, if any.
+    pub fn version(&self, path: &Path) -> Result<Option<u64>, Error> {
+        self.0.version(path)
+    }
+
+    /// Returns an immutable view of all text files in the VFS, tagged with
+    /// their versions. The view shares the text with the VFS, so it is cheap
+    /// to take and to keep while the VFS changes.
+    pub fn snapshot(&self) -> Snapshot {
+        self.0.snapshot()
     }
 
     pub fn load_file(&self, path: &Path) -> Result<FileContents, Error> {
@@ -283,21 +371,141 @@ impl<U> Vfs<U> {
     }
 }
 
+#[cfg(all(feature = "watcher", target_os = "linux"))]
+impl<U: Send + 'static> Vfs<U> {
+    /// Starts watching the directories of cached files for changes on disk,
+    /// e.g., by `git checkout` or a code generator. A changed, cached file is
+    /// evicted from the VFS, unless it has been changed in memory or is
+    /// versioned (in which case the client is responsible for it), and its path
+    /// is sent to `subscriber`. Requires the `watcher` feature and Linux.
+    pub fn watch(&self, subscriber: Sender<PathBuf>) -> Result<(), Error> {
+        self.0.watch(subscriber)
+    }
+}
+
 // Important invariants! If you are going to lock both files and pending_files,
 // you must lock pending_files first.
 // You must have both locks to insert or remove files.
+// If you are going to lock the watcher as well, you must lock it before
+// either of them. If you are going to lock the memory limit as well, you must
+// lock it after them.
 struct VfsInternal<T, U> {
-    files: Mutex<HashMap<PathBuf, File<U>>>,
-    pending_files: Mutex<HashMap<PathBuf, Vec<Thread>>>,
-    loader: PhantomData<T>,
+    files: Arc<Mutex<HashMap<PathBuf, File<U>>>>,
+    pending_files: Arc<Mutex<HashMap<PathBuf, Vec<Thread>>>>,
+    loader: T,
+    memory_limit: Mutex<Option<usize>>,
+    strict_versions: AtomicBool,
+    counters: CacheCounters,
+    #[cfg(all(feature = "watcher", target_os = "linux"))]
+    watcher: Mutex<Option<watcher::Watcher>>,
 }
 
 impl<T: FileLoader, U> VfsInternal<T, U> {
-    fn new() -> VfsInternal<T, U> {
+    fn new(loader: T) -> VfsInternal<T, U> {
         VfsInternal {
-            files: Mutex::new(HashMap::new()),
-            pending_files: Mutex::new(HashMap::new()),
-            loader: PhantomData,
+            files: Arc::default(),
+            pending_files: Arc::default(),
+            loader,
+            memory_limit: Mutex::default(),
+            strict_versions: AtomicBool::new(false),
+            counters: CacheCounters::default(),
+            #[cfg(all(feature = "watcher", target_os = "linux"))]
+            watcher: Mutex::default(),
+        }
+    }
+
+    #[cfg(all(feature = "watcher", target_os = "linux"))]
+    fn watch(&self, subscriber: Sender<PathBuf>) -> Result<(), Error>
+    where
+        U: Send + 'static,
+    {
+        let mut watcher = self.watcher.lock().unwrap();
+        if watcher.is_some() {
+            return Err(Error::InternalError("VFS is already being watched"));
+        }
+        let new_watcher = watcher::Watcher::start(
+            Arc::downgrade(&self.pending_files),
+            Arc::downgrade(&self.files),
+            subscriber,
+        )
+        .map_err(|e| Error::Io(None, Some(e.to_string())))?;
+        let paths: Vec<PathBuf> = self.files.lock().unwrap().keys().cloned().collect();
+        for path in paths {
+            new_watcher.watch(&path);
+        }
+        *watcher = Some(new_watcher);
+        Ok(())
+    }
+
+    /// Watches the file for changes on disk, if the VFS is being watched.
+    #[cfg(all(feature = "watcher", target_os = "linux"))]
+    fn watch_file(&self, path: &Path) {
+        if let Some(ref watcher) = *self.watcher.lock().unwrap() {
+            watcher.watch(path);
+        }
+    }
+
+    #[cfg(not(all(feature = "watcher", target_os = "linux")))]
+    fn watch_file(&self, _path: &Path) {}
+
+    fn read(&self, path: &Path) -> Result<File<U>, Error> {
+        let bytes = self.loader.read(path)?;
+        let kind = match String::from_utf8(bytes) {
+            Ok(s) => FileKind::Text(TextFile::new(&s, None)),
+            Err(e) => FileKind::Binary(e.into_bytes()),
+        };
+        Ok(File::new(kind))
+    }
+
+    fn set_memory_limit(&self, limit: Option<usize>) {
+        *self.memory_limit.lock().unwrap() = limit;
+        let _pending_files = self.pending_files.lock().unwrap();
+        let mut files = self.files.lock().unwrap();
+        self.enforce_memory_limit(&mut files, None);
+    }
+
+    /// Evicts the least recently used files which the client isn't responsible
+    /// for, other than `keep`, until the cached files fit in the memory limit.
+    /// The caller must hold the pending_files lock.
+    fn enforce_memory_limit(&self, files: &mut HashMap<PathBuf, File<U>>, keep: Option<&Path>) {
+        let limit = match *self.memory_limit.lock().unwrap() {
+            Some(limit) => limit,
+            None => return,
+        };
+        let mut bytes: usize = files.values().map(File::size).sum();
+        if bytes <= limit {
+            return;
+        }
+
+        let mut candidates: Vec<(u64, PathBuf, usize)> = files
+            .iter()
+            .filter(|&(path, file)| Some(&**path) != keep && !file.owned_by_client())
+            .map(|(path, file)| (file.last_used, path.clone(), file.size()))
+            .collect();
+        candidates.sort_unstable_by_key(|&(last_used, ..)| last_used);
+        for (_, path, size) in candidates {
+            if bytes <= limit {
+                break;
+            }
+            trace!("evicting {} from the VFS", path.display());
+            files.remove(&path);
+            bytes -= size;
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn set_strict_versions(&self, strict: bool) {
+        self.strict_versions.store(strict, Ordering::SeqCst);
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        let files = self.files.lock().unwrap();
+        CacheStats {
+            files: files.len(),
+            bytes: files.values().map(File::size).sum(),
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
         }
     }
 
@@ -325,6 +533,21 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         Ok(())
     }
 
+    fn file_closed(&self, path: &Path) {
+        // Don't hold the locks while reading the file from disk.
+        let on_disk = self.loader.read(path).ok();
+
+        let _pending_files = self.pending_files.lock().unwrap();
+        let mut files = self.files.lock().unwrap();
+        if let Some(FileKind::Text(ref mut t)) = files.get_mut(path).map(|f| &mut f.kind) {
+            t.version = None;
+            if on_disk.as_deref() == Some(t.text.to_string().as_bytes()) {
+                t.changed = false;
+            }
+        }
+        self.enforce_memory_limit(&mut files, None);
+    }
+
     fn flush_file(&self, path: &Path) -> Result<(), Error> {
         loop {
             let mut pending_files = self.pending_files.lock().unwrap();
@@ -349,25 +572,30 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
 
     fn on_changes(&self, changes: &[Change]) -> Result<(), Error> {
         trace!("on_changes: {:?}", changes);
+        let strict = self.strict_versions.load(Ordering::SeqCst);
         for (file_name, changes) in coalesce_changes(changes) {
             let path = Path::new(file_name);
             {
                 let mut files = self.files.lock().unwrap();
                 if let Some(file) = files.get_mut(Path::new(path)) {
-                    file.make_change(&changes)?;
+                    file.make_change(&changes, strict)?;
                     continue;
                 }
             }
 
-            // FIXME(#11): if the first change is `Add`, we should avoid
-            // loading the file. If the first change is not `Add`, then
-            // this is subtly broken, because we can't guarantee that the
-            // edits are intended to be applied to the version of the file
-            // we read from disk. That is, the on disk contents might have
-            // changed after the edit request.
-            let mut file = T::read(Path::new(path))?;
-            file.make_change(&changes)?;
+            let mut file = match *changes[0] {
+                // The first change replaces the whole file, so there is no
+                // need to load it.
+                Change::AddFile { .. } => File::new(FileKind::Text(TextFile::new("", None))),
+                // FIXME(#11): this is subtly broken, because we can't
+                // guarantee that the edits are intended to be applied to the
+                // version of the file we read from disk. That is, the on disk
+                // contents might have changed after the edit request.
+                Change::ReplaceText { .. } => self.read(Path::new(path))?,
+            };
+            file.make_change(&changes, strict)?;
 
+            self.watch_file(path);
             let mut files = self.files.lock().unwrap();
             files.insert(path.to_path_buf(), file);
         }
@@ -375,16 +603,12 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         Ok(())
     }
 
-    fn set_file(&self, path: &Path, text: &str) {
-        let file = File {
-            kind: FileKind::Text(TextFile {
-                text: text.to_owned(),
-                line_indices: make_line_indices(text),
-                changed: true,
-            }),
-            user_data: None,
-        };
+    fn set_file(&self, path: &Path, text: &str, version: Option<u64>) {
+        let mut text_file = TextFile::new(text, version);
+        text_file.changed = true;
+        let file = File::new(FileKind::Text(text_file));
 
+        self.watch_file(path);
         loop {
             let mut pending_files = self.pending_files.lock().unwrap();
             let mut files = self.files.lock().unwrap();
@@ -398,12 +622,35 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         }
     }
 
+    fn version(&self, path: &Path) -> Result<Option<u64>, Error> {
+        let files = self.files.lock().unwrap();
+        match files.get(path) {
+            Some(f) => match f.kind {
+                FileKind::Text(ref t) => Ok(t.version),
+                FileKind::Binary(_) => Ok(None),
+            },
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let files = self.files.lock().unwrap();
+        let files = files
+            .iter()
+            .filter_map(|(p, f)| match f.kind {
+                FileKind::Text(ref f) => Some((p.clone(), f.clone())),
+                FileKind::Binary(_) => None,
+            })
+            .collect();
+        Snapshot { files: Arc::new(files) }
+    }
+
     fn get_cached_files(&self) -> HashMap<PathBuf, String> {
         let files = self.files.lock().unwrap();
         files
             .iter()
             .filter_map(|(p, f)| match f.kind {
-                FileKind::Text(ref f) => Some((p.clone(), f.text.clone())),
+                FileKind::Text(ref f) => Some((p.clone(), f.text.to_string())),
                 FileKind::Binary(_) => None,
             })
             .collect()
@@ -414,7 +661,7 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         files
             .iter()
             .filter_map(|(p, f)| match f.kind {
-                FileKind::Text(ref f) if f.changed => Some((p.clone(), f.text.clone())),
+                FileKind::Text(ref f) if f.changed => Some((p.clone(), f.text.to_string())),
                 _ => None,
             })
             .collect()
@@ -426,7 +673,7 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
     }
 
     fn load_line(&self, path: &Path, line: span::Row<span::ZeroIndexed>) -> Result<String, Error> {
-        self.ensure_file(path, |f| f.load_line(line).map(|s| s.to_owned()))
+        self.ensure_file(path, |f| f.load_line(line))
     }
 
     fn load_lines(
@@ -435,11 +682,11 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         line_start: span::Row<span::ZeroIndexed>,
         line_end: span::Row<span::ZeroIndexed>,
     ) -> Result<String, Error> {
-        self.ensure_file(path, |f| f.load_lines(line_start, line_end).map(|s| s.to_owned()))
+        self.ensure_file(path, |f| f.load_lines(line_start, line_end))
     }
 
     fn load_span(&self, span: span::Span<span::ZeroIndexed>) -> Result<String, Error> {
-        self.ensure_file(&span.file, |f| f.load_range(span.range).map(|s| s.to_owned()))
+        self.ensure_file(&span.file, |f| f.load_range(span.range))
     }
 
     fn for_each_line<F>(&self, path: &Path, f: F) -> Result<(), Error>
@@ -460,9 +707,11 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         loop {
             {
                 let mut pending_files = self.pending_files.lock().unwrap();
-                let files = self.files.lock().unwrap();
-                if files.contains_key(path) {
-                    return f(&files[path]);
+                let mut files = self.files.lock().unwrap();
+                if let Some(file) = files.get_mut(path) {
+                    self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                    file.last_used = self.counters.tick();
+                    return f(file);
                 }
                 if !pending_files.contains_key(path) {
                     pending_files.insert(path.to_owned(), vec![]);
@@ -474,14 +723,20 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         }
 
         // We should not hold the locks while we read from disk.
-        let file = T::read(path);
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        let file = self.read(path);
+        if file.is_ok() {
+            self.watch_file(path);
+        }
 
         // Need to re-get the locks here.
         let mut pending_files = self.pending_files.lock().unwrap();
         let mut files = self.files.lock().unwrap();
         match file {
-            Ok(file) => {
+            Ok(mut file) => {
+                file.last_used = self.counters.tick();
                 files.insert(path.to_owned(), file);
+                self.enforce_memory_limit(&mut files, Some(path));
                 let ts = pending_files.remove(path).unwrap();
                 for t in ts {
                     t.unpark();
@@ -513,7 +768,11 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
             }
         };
 
-        T::write(path, &file)?;
+        let contents = match file {
+            FileKind::Text(t) => t.text.to_string().into_bytes(),
+            FileKind::Binary(b) => b,
+        };
+        self.loader.write(path, &contents)?;
         Ok(())
     }
 
@@ -521,7 +780,7 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         let mut files = self.files.lock().unwrap();
         match files.get_mut(path) {
             Some(ref mut f) => {
-                f.user_data = data;
+                f.set_user_data(data);
                 Ok(())
             }
             None => Err(Error::FileNotCached),
@@ -540,19 +799,13 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
             None => return f(Err(Error::FileNotCached)),
         };
 
-        let result = f(match file.user_data {
-            Some(ref mut u) => {
-                let text = match file.kind {
-                    FileKind::Text(ref f) => Some(&f.text as &str),
-                    FileKind::Binary(_) => None,
-                };
-                Ok((text, u))
-            }
-            None => Err(Error::NoUserDataForFile),
+        let result = f(match file.text_and_user_data() {
+            (text, Some(u)) => Ok((text, u)),
+            (_, None) => Err(Error::NoUserDataForFile),
         });
 
         if let Err(Error::NoUserDataForFile) = result {
-            file.user_data = None;
+            file.set_user_data(None);
         }
 
         result
@@ -566,17 +819,20 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         match files.get_mut(path) {
             Some(ref mut file) => {
                 if file.user_data.is_none() {
+                    // Keep the text we compute the user data from, so that
+                    // we don't have to collect it out of the rope again.
                     let text = match file.kind {
-                        FileKind::Text(ref f) => Some(&f.text as &str),
+                        FileKind::Text(ref f) => Some(f.text.to_string()),
                         FileKind::Binary(_) => None,
                     };
-                    match f(text) {
+                    match f(text.as_deref()) {
                         Ok(u) => {
-                            file.user_data = Some(u);
+                            file.set_user_data(Some(u));
+                            file.user_data_text = text;
                             Ok(())
                         }
                         Err(Error::NoUserDataForFile) => {
-                            file.user_data = None;
+                            file.set_user_data(None);
                             Ok(())
                         }
                         Err(e) => Err(e),
@@ -599,70 +855,203 @@ fn coalesce_changes<'a>(changes: &'a [Change]) -> HashMap<&'a Path, Vec<&'a Chan
     result
 }
 
-fn make_line_indices(text: &str) -> Vec<u32> {
-    let mut result = vec![0];
-    for (i, b) in text.bytes().enumerate() {
-        if b == 0xA {
-            result.push((i + 1) as u32);
-        }
-    }
-    result.push(text.len() as u32);
-    result
-}
-
 #[derive(Clone)]
 enum FileKind {
     Text(TextFile),
     Binary(Vec<u8>),
 }
 
-impl FileKind {
-    fn as_bytes(&self) -> &[u8] {
-        match *self {
-            FileKind::Text(ref t) => t.text.as_bytes(),
-            FileKind::Binary(ref b) => b,
-        }
-    }
-}
-
 #[derive(Debug, PartialEq)]
 pub enum FileContents {
     Text(String),
     Binary(Vec<u8>),
 }
 
+/// Statistics about the files cached in the VFS. See `Vfs::cache_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of cached files.
+    pub files: usize,
+    /// The size of the cached files, in bytes.
+    pub bytes: usize,
+    /// The number of times a file was loaded from the cache.
+    pub hits: u64,
+    /// The number of times a file had to be read to load it.
+    pub misses: u64,
+    /// The number of files evicted to keep within the memory limit.
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// The proportion of loads served from the cache, between 0 and 1.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            loads => self.hits as f64 / loads as f64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct CacheCounters {
+    /// Orders uses of files, for LRU eviction.
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl CacheCounters {
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// An immutable view of the text files in the VFS at some point in time. See
+/// `Vfs::snapshot`.
+#[derive(Clone, Default)]
+pub struct Snapshot {
+    files: Arc<HashMap<PathBuf, TextFile>>,
+}
+
+impl Snapshot {
+    /// Returns the paths of all files in the snapshot.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(PathBuf::as_path)
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    /// Returns the version of the file when the snapshot was taken, if the
+    /// client keeps track.
+    pub fn version(&self, path: &Path) -> Option<u64> {
+        self.files.get(path).and_then(|f| f.version)
+    }
+
+    pub fn load_file(&self, path: &Path) -> Result<String, Error> {
+        self.get(path).map(|f| f.text.to_string())
+    }
+
+    pub fn load_line(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+    ) -> Result<String, Error> {
+        self.get(path)?.load_line(line)
+    }
+
+    pub fn load_lines(
+        &self,
+        path: &Path,
+        line_start: span::Row<span::ZeroIndexed>,
+        line_end: span::Row<span::ZeroIndexed>,
+    ) -> Result<String, Error> {
+        self.get(path)?.load_lines(line_start, line_end)
+    }
+
+    pub fn load_span(&self, span: span::Span<span::ZeroIndexed>) -> Result<String, Error> {
+        self.get(&span.file)?.load_range(span.range)
+    }
+
+    pub fn for_each_line<F>(&self, path: &Path, f: F) -> Result<(), Error>
+    where
+        F: FnMut(&str, usize) -> Result<(), Error>,
+    {
+        self.get(path)?.for_each_line(f)
+    }
+
+    /// Returns the text of all files in the snapshot.
+    pub fn to_map(&self) -> HashMap<PathBuf, String> {
+        self.files.iter().map(|(p, f)| (p.clone(), f.text.to_string())).collect()
+    }
+
+    fn get(&self, path: &Path) -> Result<&TextFile, Error> {
+        self.files.get(path).ok_or(Error::FileNotCached)
+    }
+}
+
 #[derive(Clone)]
 struct TextFile {
-    // FIXME(https://github.com/jonathandturner/rustls/issues/21) should use a rope.
-    text: String,
-    line_indices: Vec<u32>,
+    text: Rope,
     changed: bool,
+    /// The version of the text as known to the client, if any.
+    version: Option<u64>,
 }
 
 struct File<U> {
     kind: FileKind,
     user_data: Option<U>,
+    /// The text collected out of the rope for users of the user data, which is
+    /// kept (and dropped) along with it.
+    user_data_text: Option<String>,
+    /// When the file was last loaded, see `CacheCounters::tick`.
+    last_used: u64,
 }
 
 impl<U> File<U> {
+    fn new(kind: FileKind) -> File<U> {
+        File { kind, user_data: None, user_data_text: None, last_used: 0 }
+    }
+
+    fn size(&self) -> usize {
+        let size = match self.kind {
+            FileKind::Text(ref t) => t.text.len(),
+            FileKind::Binary(ref b) => b.len(),
+        };
+        size + self.user_data_text.as_ref().map_or(0, String::len)
+    }
+
+    fn set_user_data(&mut self, data: Option<U>) {
+        self.user_data = data;
+        self.user_data_text = None;
+    }
+
+    /// Returns the text of the file (only collecting it out of the rope once)
+    /// along with the user data, if there is any.
+    fn text_and_user_data(&mut self) -> (Option<&str>, Option<&mut U>) {
+        let user_data = match self.user_data {
+            Some(ref mut u) => u,
+            None => return (None, None),
+        };
+        let text = match self.kind {
+            FileKind::Text(ref t) => {
+                Some(&**self.user_data_text.get_or_insert_with(|| t.text.to_string()))
+            }
+            FileKind::Binary(_) => None,
+        };
+        (text, Some(user_data))
+    }
+
+    /// Whether the client has changed or opened the file, in which case it is
+    /// responsible for the text and the file mustn't be evicted.
+    fn owned_by_client(&self) -> bool {
+        match self.kind {
+            FileKind::Text(ref t) => t.changed || t.version.is_some(),
+            FileKind::Binary(_) => false,
+        }
+    }
+
     fn contents(&self) -> FileContents {
         match self.kind {
-            FileKind::Text(ref t) => FileContents::Text(t.text.clone()),
+            FileKind::Text(ref t) => FileContents::Text(t.text.to_string()),
             FileKind::Binary(ref b) => FileContents::Binary(b.clone()),
         }
     }
 
-    fn make_change(&mut self, changes: &[&Change]) -> Result<(), Error> {
+    fn make_change(&mut self, changes: &[&Change], strict: bool) -> Result<(), Error> {
         match self.kind {
             FileKind::Text(ref mut t) => {
                 self.user_data = None;
-                t.make_change(changes)
+                self.user_data_text = None;
+                t.make_change(changes, strict)
             }
             FileKind::Binary(_) => Err(Error::BadFileKind),
         }
     }
 
-    fn load_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<&str, Error> {
+    fn load_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<String, Error> {
         match self.kind {
             FileKind::Text(ref t) => t.load_line(line),
             FileKind::Binary(_) => Err(Error::BadFileKind),
@@ -673,14 +1062,14 @@ impl<U> File<U> {
         &self,
         line_start: span::Row<span::ZeroIndexed>,
         line_end: span::Row<span::ZeroIndexed>,
-    ) -> Result<&str, Error> {
+    ) -> Result<String, Error> {
         match self.kind {
             FileKind::Text(ref t) => t.load_lines(line_start, line_end),
             FileKind::Binary(_) => Err(Error::BadFileKind),
         }
     }
 
-    fn load_range(&self, range: span::Range<span::ZeroIndexed>) -> Result<&str, Error> {
+    fn load_range(&self, range: span::Range<span::ZeroIndexed>) -> Result<String, Error> {
         match self.kind {
             FileKind::Text(ref t) => t.load_range(range),
             FileKind::Binary(_) => Err(Error::BadFileKind),
@@ -706,117 +1095,124 @@ impl<U> File<U> {
 }
 
 impl TextFile {
-    fn make_change(&mut self, changes: &[&Change]) -> Result<(), Error> {
+    fn new(text: &str, version: Option<u64>) -> TextFile {
+        TextFile { text: Rope::from(text), changed: false, version }
+    }
+
+    fn make_change(&mut self, changes: &[&Change], strict: bool) -> Result<(), Error> {
         trace!("TextFile::make_change");
+        self.check_versions(changes, strict)?;
         for c in changes {
             trace!("TextFile::make_change: {:?}", c);
-            let new_text = match **c {
-                Change::ReplaceText { span: ref vfs_span, ref text } => {
+            match **c {
+                Change::ReplaceText { span: ref vfs_span, ref text, .. } => {
                     let (span, len) = (vfs_span.span(), vfs_span.len());
 
-                    let range = {
-                        let first_line = self.load_line(span.range.row_start)?;
-                        let byte_start = self.line_indices[span.range.row_start.0 as usize]
-                            + vfs_span.byte_in_str(first_line, span.range.col_start)? as u32;
-
-                        let byte_end = if let Some(len) = len {
-                            // if `len` exists, the replaced portion of text
-                            // is `len` chars starting from row_start/col_start.
-                            byte_start
-                                + vfs_span.byte_in_str(
-                                    &self.text[byte_start as usize..],
-                                    span::Column::new_zero_indexed(len as u32),
-                                )? as u32
-                        } else {
-                            // if no `len`, fall back to using row_end/col_end
-                            // for determining the tail end of replaced text.
-                            let last_line = self.load_line(span.range.row_end)?;
-                            self.line_indices[span.range.row_end.0 as usize]
-                                + vfs_span.byte_in_str(last_line, span.range.col_end)? as u32
-                        };
+                    let first_line = self.load_line(span.range.row_start)?;
+                    let byte_start = self.line_start(span.range.row_start.0)?
+                        + vfs_span.byte_in_str(&first_line, span.range.col_start)?;
 
-                        (byte_start, byte_end)
+                    let byte_end = if let Some(len) = len {
+                        // if `len` exists, the replaced portion of text
+                        // is `len` chars starting from row_start/col_start.
+                        let metric = match vfs_span {
+                            VfsSpan::UnicodeScalarValue(..) => Metric::Chars,
+                            VfsSpan::Utf16CodeUnit(..) => Metric::Utf16,
+                        };
+                        let start = self.text.byte_to(metric, byte_start);
+                        try_opt_loc!(self.text.to_byte(metric, start + len as usize))
+                    } else {
+                        // if no `len`, fall back to using row_end/col_end
+                        // for determining the tail end of replaced text.
+                        let last_line = self.load_line(span.range.row_end)?;
+                        self.line_start(span.range.row_end.0)?
+                            + vfs_span.byte_in_str(&last_line, span.range.col_end)?
                     };
-                    let mut new_text = self.text[..range.0 as usize].to_owned();
-                    new_text.push_str(text);
-                    new_text.push_str(&self.text[range.1 as usize..]);
-                    new_text
-                }
-                Change::AddFile { ref text, .. } => text.to_owned(),
-            };
 
-            self.text = new_text;
-            self.line_indices = make_line_indices(&self.text);
+                    if byte_end < byte_start {
+                        return Err(Error::BadLocation);
+                    }
+                    self.text.replace(byte_start..byte_end, text);
+                }
+                Change::AddFile { ref text, .. } => self.text = Rope::from(&**text),
+            }
+            self.version = c.version();
         }
 
         self.changed = true;
         Ok(())
     }
 
-    fn load_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<&str, Error> {
-        let start = *try_opt_loc!(self.line_indices.get(line.0 as usize));
-        let end = *try_opt_loc!(self.line_indices.get(line.0 as usize + 1));
-
-        if (end as usize) <= self.text.len() && start <= end {
-            Ok(&self.text[start as usize..end as usize])
-        } else {
-            Err(Error::BadLocation)
+    /// Checks that each versioned change is newer than the version of the file
+    /// before it or, if `strict`, follows on from it exactly. Changes after the
+    /// first with the same version are part of the same edit, so don't need a
+    /// new version.
+    fn check_versions(&self, changes: &[&Change], strict: bool) -> Result<(), Error> {
+        let mut version = self.version;
+        let mut edit_version = None;
+        for c in changes {
+            if let (Some(file_version), Some(change_version)) = (version, c.version()) {
+                let in_order = if strict {
+                    change_version == file_version + 1
+                } else {
+                    change_version > file_version
+                };
+                if !in_order && edit_version != Some(change_version) {
+                    return Err(Error::UnexpectedVersion(
+                        c.file().to_owned(),
+                        file_version,
+                        change_version,
+                    ));
+                }
+            }
+            version = c.version();
+            edit_version = c.version();
         }
+        Ok(())
+    }
+
+    /// Returns the byte offset of the start of `line`. The line after the
+    /// last line starts at the end of the text.
+    fn line_start(&self, line: u32) -> Result<usize, Error> {
+        Ok(try_opt_loc!(self.text.line_to_byte(line as usize)))
+    }
+
+    fn load_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<String, Error> {
+        let start = self.line_start(line.0)?;
+        let end = self.line_start(line.0 + 1)?;
+
+        Ok(try_opt_loc!(self.text.slice(start..end)))
     }
 
     fn load_lines(
         &self,
         line_start: span::Row<span::ZeroIndexed>,
         line_end: span::Row<span::ZeroIndexed>,
-    ) -> Result<&str, Error> {
-        let line_start = line_start.0 as usize;
-        let mut line_end = line_end.0 as usize;
-        if line_end >= self.line_indices.len() {
-            line_end = self.line_indices.len() - 1;
-        }
+    ) -> Result<String, Error> {
+        let last_line = self.text.newlines() as u32 + 1;
+        let line_end = line_end.0.min(last_line);
 
-        let start = (*try_opt_loc!(self.line_indices.get(line_start))) as usize;
-        let end = (*try_opt_loc!(self.line_indices.get(line_end))) as usize;
+        let start = self.line_start(line_start.0)?;
+        let end = self.line_start(line_end)?;
 
-        if (end) <= self.text.len() && start <= end {
-            Ok(&self.text[start..end])
-        } else {
-            Err(Error::BadLocation)
-        }
+        Ok(try_opt_loc!(self.text.slice(start..end)))
     }
 
-    fn load_range(&self, range: span::Range<span::ZeroIndexed>) -> Result<&str, Error> {
-        let line_start = range.row_start.0 as usize;
-        let mut line_end = range.row_end.0 as usize;
-        if line_end >= self.line_indices.len() {
-            line_end = self.line_indices.len() - 1;
-        }
+    fn load_range(&self, range: span::Range<span::ZeroIndexed>) -> Result<String, Error> {
+        let last_line = self.text.newlines() as u32 + 1;
+        let line_end = range.row_end.0.min(last_line);
 
-        let start = (*try_opt_loc!(self.line_indices.get(line_start))) as usize;
-        let start = start + range.col_start.0 as usize;
-        let end = (*try_opt_loc!(self.line_indices.get(line_end))) as usize;
-        let end = end + range.col_end.0 as usize;
+        let start = self.line_start(range.row_start.0)? + range.col_start.0 as usize;
+        let end = self.line_start(line_end)? + range.col_end.0 as usize;
 
-        if (end) <= self.text.len() && start <= end {
-            Ok(&self.text[start..end])
-        } else {
-            Err(Error::BadLocation)
-        }
+        Ok(try_opt_loc!(self.text.slice(start..end)))
     }
 
-    fn for_each_line<F>(&self, mut f: F) -> Result<(), Error>
+    fn for_each_line<F>(&self, f: F) -> Result<(), Error>
     where
         F: FnMut(&str, usize) -> Result<(), Error>,
     {
-        let mut line_iter = self.line_indices.iter();
-        let mut start = *line_iter.next().unwrap() as usize;
-        for (i, idx) in line_iter.enumerate() {
-            let idx = *idx as usize;
-            f(&self.text[start..idx], i)?;
-            start = idx;
-        }
-
-        Ok(())
+        self.text.for_each_line(f)
     }
 }
 
@@ -852,65 +1248,6 @@ fn byte_in_str_utf16(s: &str, c: span::Column<span::ZeroIndexed>) -> Result<usiz
     Err(Error::InternalError("UTF-16 code unit offset is not at `str` char boundary"))
 }
 
-trait FileLoader {
-    fn read<U>(file_name: &Path) -> Result<File<U>, Error>;
-    fn write(file_name: &Path, file: &FileKind) -> Result<(), Error>;
-}
-
-struct RealFileLoader;
-
-impl FileLoader for RealFileLoader {
-    fn read<U>(file_name: &Path) -> Result<File<U>, Error> {
-        let mut file = match fs::File::open(file_name) {
-            Ok(f) => f,
-            Err(_) => {
-                return Err(Error::Io(
-                    Some(file_name.to_owned()),
-                    Some(format!("Could not open file: {}", file_name.display())),
-                ));
-            }
-        };
-        let mut buf = vec![];
-        if file.read_to_end(&mut buf).is_err() {
-            return Err(Error::Io(
-                Some(file_name.to_owned()),
-                Some(format!("Could not read file: {}", file_name.display())),
-            ));
-        }
-
-        match String::from_utf8(buf) {
-            Ok(s) => Ok(File {
-                kind: FileKind::Text(TextFile {
-                    line_indices: make_line_indices(&s),
-                    text: s,
-                    changed: false,
-                }),
-                user_data: None,
-            }),
-            Err(e) => Ok(File { kind: FileKind::Binary(e.into_bytes()), user_data: None }),
-        }
-    }
-
-    fn write(file_name: &Path, file: &FileKind) -> Result<(), Error> {
-        use std::io::Write;
-
-        macro_rules! try_io {
-            ($e:expr) => {
-                match $e {
-                    Ok(e) => e,
-                    Err(e) => {
-                        return Err(Error::Io(Some(file_name.to_owned()), Some(e.to_string())));
-                    }
-                }
-            };
-        }
-
-        let mut out = try_io!(::std::fs::File::create(file_name));
-        try_io!(out.write_all(file.as_bytes()));
-        Ok(())
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use span::Column;
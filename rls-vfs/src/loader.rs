@@ -0,0 +1,175 @@
+//! Backends from which the VFS loads files it has not yet cached, and to which
+//! it writes files back.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::Error;
+
+/// Reads and writes the contents of files for the VFS. The VFS decides whether
+/// the contents are text, so loaders only deal in bytes.
+pub trait FileLoader: Send + Sync {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error>;
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), Error>;
+}
+
+impl<L: FileLoader + ?Sized> FileLoader for Box<L> {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        (**self).read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), Error> {
+        (**self).write(path, contents)
+    }
+}
+
+// Lets the creator of a VFS keep a handle to its loader, e.g., to change the
+// files of a `MemoryFileLoader` from a test.
+impl<L: FileLoader + ?Sized> FileLoader for Arc<L> {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        (**self).read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), Error> {
+        (**self).write(path, contents)
+    }
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::Io(Some(path.to_owned()), Some(format!("Could not open file: {}", path.display())))
+}
+
+/// Loads files from the real file system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RealFileLoader;
+
+impl FileLoader for RealFileLoader {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let mut file = fs::File::open(path).map_err(|_| not_found(path))?;
+        let mut buf = vec![];
+        if file.read_to_end(&mut buf).is_err() {
+            return Err(Error::Io(
+                Some(path.to_owned()),
+                Some(format!("Could not read file: {}", path.display())),
+            ));
+        }
+        Ok(buf)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), Error> {
+        fs::File::create(path)
+            .and_then(|mut out| out.write_all(contents))
+            .map_err(|e| Error::Io(Some(path.to_owned()), Some(e.to_string())))
+    }
+}
+
+/// An in-memory file system. Files which have not been inserted don't exist.
+#[derive(Debug, Default)]
+pub struct MemoryFileLoader {
+    files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl MemoryFileLoader {
+    pub fn new() -> MemoryFileLoader {
+        MemoryFileLoader::default()
+    }
+
+    /// Creates or replaces the file at `path`.
+    pub fn insert<P: Into<PathBuf>, C: Into<Vec<u8>>>(&self, path: P, contents: C) {
+        self.files.lock().unwrap().insert(path.into(), contents.into());
+    }
+
+    /// Removes the file at `path`, returning its contents if it existed.
+    pub fn remove(&self, path: &Path) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().remove(path)
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    /// Returns the paths of all files, in no particular order.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.files.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+impl FileLoader for MemoryFileLoader {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        self.files.lock().unwrap().get(path).cloned().ok_or_else(|| not_found(path))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), Error> {
+        self.insert(path, contents);
+        Ok(())
+    }
+}
+
+/// In-memory files on top of a read-only directory. Reads see the in-memory
+/// file if there is one and the file in the directory otherwise. Writes only
+/// ever change the in-memory files. Paths outside the directory can be
+/// neither read nor written.
+///
+/// The directory is read using the real file system by default, or any other
+/// loader given to `with_base`.
+#[derive(Debug)]
+pub struct OverlayFileLoader<L = RealFileLoader> {
+    root: PathBuf,
+    overlay: MemoryFileLoader,
+    base: L,
+}
+
+impl OverlayFileLoader {
+    pub fn new<P: Into<PathBuf>>(root: P) -> OverlayFileLoader {
+        OverlayFileLoader::with_base(root, RealFileLoader)
+    }
+}
+
+impl<L: FileLoader> OverlayFileLoader<L> {
+    pub fn with_base<P: Into<PathBuf>>(root: P, base: L) -> OverlayFileLoader<L> {
+        OverlayFileLoader { root: root.into(), overlay: MemoryFileLoader::new(), base }
+    }
+
+    /// The directory which is overlaid.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The in-memory files, which shadow those of the base.
+    pub fn overlay(&self) -> &MemoryFileLoader {
+        &self.overlay
+    }
+
+    pub fn base(&self) -> &L {
+        &self.base
+    }
+
+    fn check_path(&self, path: &Path) -> Result<(), Error> {
+        if path.starts_with(&self.root) {
+            Ok(())
+        } else {
+            Err(Error::Io(
+                Some(path.to_owned()),
+                Some(format!("File is outside of {}", self.root.display())),
+            ))
+        }
+    }
+}
+
+impl<L: FileLoader> FileLoader for OverlayFileLoader<L> {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        self.check_path(path)?;
+        match self.overlay.read(path) {
+            Ok(contents) => Ok(contents),
+            Err(_) => self.base.read(path),
+        }
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), Error> {
+        self.check_path(path)?;
+        self.overlay.write(path, contents)
+    }
+}
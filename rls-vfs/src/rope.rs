@@ -0,0 +1,610 @@
+//! A rope for the text of files in the VFS.
+//!
+//! The text is kept in chunks at the leaves of a balanced binary tree, where
+//! every node records the number of bytes, chars, UTF-16 code units and
+//! newlines below it. Edits only rebuild the path to the edited chunks, and
+//! finding the start of a line or converting between offsets only walks down
+//! one path, so both are O(log n) in the size of the file. Nodes are immutable
+//! and shared, so cloning a rope is cheap.
+
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The maximum size of a chunk, in bytes. Chunks may only be larger if they
+/// consist of a single char.
+const MAX_CHUNK: usize = 1024;
+
+#[derive(Clone)]
+pub(crate) struct Rope {
+    root: Arc<Node>,
+}
+
+enum Node {
+    Leaf { text: String, info: Info },
+    Branch { left: Arc<Node>, right: Arc<Node>, info: Info, height: u32 },
+}
+
+/// The sizes of some text in various units.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Info {
+    bytes: usize,
+    chars: usize,
+    utf16: usize,
+    newlines: usize,
+}
+
+/// Units in which text can be measured, other than bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Metric {
+    /// Unicode scalar values.
+    Chars,
+    /// UTF-16 code units.
+    Utf16,
+}
+
+impl Info {
+    fn of(text: &str) -> Info {
+        let mut info = Info { bytes: text.len(), ..Info::default() };
+        for c in text.chars() {
+            info.chars += 1;
+            info.utf16 += c.len_utf16();
+            if c == '\n' {
+                info.newlines += 1;
+            }
+        }
+        info
+    }
+
+    fn add(self, other: Info) -> Info {
+        Info {
+            bytes: self.bytes + other.bytes,
+            chars: self.chars + other.chars,
+            utf16: self.utf16 + other.utf16,
+            newlines: self.newlines + other.newlines,
+        }
+    }
+
+    fn get(&self, metric: Metric) -> usize {
+        match metric {
+            Metric::Chars => self.chars,
+            Metric::Utf16 => self.utf16,
+        }
+    }
+}
+
+impl Metric {
+    fn of(self, c: char) -> usize {
+        match self {
+            Metric::Chars => 1,
+            Metric::Utf16 => c.len_utf16(),
+        }
+    }
+}
+
+impl Node {
+    fn leaf(text: String) -> Arc<Node> {
+        let info = Info::of(&text);
+        Arc::new(Node::Leaf { text, info })
+    }
+
+    fn branch(left: Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+        let info = left.info().add(right.info());
+        let height = left.height().max(right.height()) + 1;
+        Arc::new(Node::Branch { left, right, info, height })
+    }
+
+    fn info(&self) -> Info {
+        match *self {
+            Node::Leaf { info, .. } | Node::Branch { info, .. } => info,
+        }
+    }
+
+    fn height(&self) -> u32 {
+        match *self {
+            Node::Leaf { .. } => 0,
+            Node::Branch { height, .. } => height,
+        }
+    }
+
+    /// Builds a balanced tree from `text`, or `None` if `text` is empty.
+    fn from_str(text: &str) -> Option<Arc<Node>> {
+        let mut leaves = vec![];
+        let mut rest = text;
+        while !rest.is_empty() {
+            let mut end = rest.len().min(MAX_CHUNK);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            if end == 0 {
+                end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+            leaves.push(Node::leaf(rest[..end].to_owned()));
+            rest = &rest[end..];
+        }
+        Node::from_leaves(&leaves)
+    }
+
+    fn from_leaves(leaves: &[Arc<Node>]) -> Option<Arc<Node>> {
+        match leaves.len() {
+            0 => None,
+            1 => Some(leaves[0].clone()),
+            n => {
+                let (left, right) = leaves.split_at(n / 2);
+                Some(Node::branch(Node::from_leaves(left)?, Node::from_leaves(right)?))
+            }
+        }
+    }
+
+    /// Concatenates two trees, keeping the result balanced.
+    fn join(left: Option<Arc<Node>>, right: Option<Arc<Node>>) -> Option<Arc<Node>> {
+        match (left, right) {
+            (Some(left), Some(right)) => Some(Node::join2(left, right)),
+            (left, None) => left,
+            (None, right) => right,
+        }
+    }
+
+    fn join2(left: Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+        if let (Node::Leaf { text: l, .. }, Node::Leaf { text: r, .. }) = (&*left, &*right) {
+            if l.len() + r.len() <= MAX_CHUNK {
+                return Node::leaf(format!("{}{}", l, r));
+            }
+        }
+
+        if left.height() > right.height() + 1 {
+            Node::join_right(&left, right)
+        } else if right.height() > left.height() + 1 {
+            Node::join_left(left, &right)
+        } else {
+            Node::branch(left, right)
+        }
+    }
+
+    /// Joins `right` onto the right spine of the taller `left`.
+    fn join_right(left: &Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+        let (ll, lr) = left.children();
+        let joined = if lr.height() <= right.height() + 1 {
+            Node::join2(lr.clone(), right)
+        } else {
+            Node::join_right(lr, right)
+        };
+        if joined.height() <= ll.height() + 1 {
+            Node::branch(ll.clone(), joined)
+        } else {
+            // `joined` is too tall, rotate left. If its inner child is the
+            // taller one, we need a double rotation.
+            let (jl, jr) = joined.children();
+            if jl.height() > jr.height() {
+                let (jll, jlr) = jl.children();
+                Node::branch(
+                    Node::branch(ll.clone(), jll.clone()),
+                    Node::branch(jlr.clone(), jr.clone()),
+                )
+            } else {
+                Node::branch(Node::branch(ll.clone(), jl.clone()), jr.clone())
+            }
+        }
+    }
+
+    /// Joins `left` onto the left spine of the taller `right`.
+    fn join_left(left: Arc<Node>, right: &Arc<Node>) -> Arc<Node> {
+        let (rl, rr) = right.children();
+        let joined = if rl.height() <= left.height() + 1 {
+            Node::join2(left, rl.clone())
+        } else {
+            Node::join_left(left, rl)
+        };
+        if joined.height() <= rr.height() + 1 {
+            Node::branch(joined, rr.clone())
+        } else {
+            let (jl, jr) = joined.children();
+            if jr.height() > jl.height() {
+                let (jrl, jrr) = jr.children();
+                Node::branch(
+                    Node::branch(jl.clone(), jrl.clone()),
+                    Node::branch(jrr.clone(), rr.clone()),
+                )
+            } else {
+                Node::branch(jl.clone(), Node::branch(jr.clone(), rr.clone()))
+            }
+        }
+    }
+
+    fn children(&self) -> (&Arc<Node>, &Arc<Node>) {
+        match self {
+            Node::Branch { left, right, .. } => (left, right),
+            Node::Leaf { .. } => unreachable!("a taller tree has no leaf at its root"),
+        }
+    }
+
+    /// Splits the tree at byte offset `at`, which must be a char boundary.
+    fn split(node: &Arc<Node>, at: usize) -> (Option<Arc<Node>>, Option<Arc<Node>>) {
+        if at == 0 {
+            return (None, Some(node.clone()));
+        }
+        if at >= node.info().bytes {
+            return (Some(node.clone()), None);
+        }
+        match &**node {
+            Node::Leaf { text, .. } => {
+                (Some(Node::leaf(text[..at].to_owned())), Some(Node::leaf(text[at..].to_owned())))
+            }
+            Node::Branch { left, right, .. } => {
+                let left_bytes = left.info().bytes;
+                if at < left_bytes {
+                    let (ll, lr) = Node::split(left, at);
+                    (ll, Node::join(lr, Some(right.clone())))
+                } else {
+                    let (rl, rr) = Node::split(right, at - left_bytes);
+                    (Node::join(Some(left.clone()), rl), rr)
+                }
+            }
+        }
+    }
+
+    fn is_char_boundary(&self, at: usize) -> bool {
+        match self {
+            Node::Leaf { text, .. } => text.is_char_boundary(at),
+            Node::Branch { left, right, .. } => {
+                let left_bytes = left.info().bytes;
+                if at <= left_bytes {
+                    left.is_char_boundary(at)
+                } else {
+                    right.is_char_boundary(at - left_bytes)
+                }
+            }
+        }
+    }
+}
+
+impl Rope {
+    /// The length of the text in bytes.
+    pub(crate) fn len(&self) -> usize {
+        self.root.info().bytes
+    }
+
+    /// The number of newlines in the text.
+    pub(crate) fn newlines(&self) -> usize {
+        self.root.info().newlines
+    }
+
+    /// Returns the byte offset of the start of line `line`, i.e., just after
+    /// the preceding newline. Line `newlines() + 1` starts at the end of the
+    /// text, any later lines don't exist.
+    pub(crate) fn line_to_byte(&self, line: usize) -> Option<usize> {
+        if line == 0 {
+            return Some(0);
+        }
+        let info = self.root.info();
+        if line > info.newlines {
+            return if line == info.newlines + 1 { Some(info.bytes) } else { None };
+        }
+
+        let (mut node, mut line, mut offset) = (&self.root, line, 0);
+        loop {
+            match &**node {
+                Node::Branch { left, right, .. } => {
+                    let left_info = left.info();
+                    if line <= left_info.newlines {
+                        node = left;
+                    } else {
+                        line -= left_info.newlines;
+                        offset += left_info.bytes;
+                        node = right;
+                    }
+                }
+                Node::Leaf { text, .. } => {
+                    let (i, _) = text.match_indices('\n').nth(line - 1)?;
+                    return Some(offset + i + 1);
+                }
+            }
+        }
+    }
+
+    /// Returns the number of `metric` units before byte offset `byte`, which
+    /// must be a char boundary.
+    pub(crate) fn byte_to(&self, metric: Metric, byte: usize) -> usize {
+        let (mut node, mut byte, mut count) = (&self.root, byte, 0);
+        loop {
+            match &**node {
+                Node::Branch { left, right, .. } => {
+                    let left_info = left.info();
+                    if byte <= left_info.bytes {
+                        node = left;
+                    } else {
+                        byte -= left_info.bytes;
+                        count += left_info.get(metric);
+                        node = right;
+                    }
+                }
+                Node::Leaf { text, .. } => {
+                    let byte = byte.min(text.len());
+                    return count + text[..byte].chars().map(|c| metric.of(c)).sum::<usize>();
+                }
+            }
+        }
+    }
+
+    /// Returns the byte offset after `n` `metric` units, or `None` if that is
+    /// past the end of the text or in the middle of a char.
+    pub(crate) fn to_byte(&self, metric: Metric, n: usize) -> Option<usize> {
+        if n > self.root.info().get(metric) {
+            return None;
+        }
+
+        let (mut node, mut n, mut offset) = (&self.root, n, 0);
+        loop {
+            match &**node {
+                Node::Branch { left, right, .. } => {
+                    let left_info = left.info();
+                    if n <= left_info.get(metric) {
+                        node = left;
+                    } else {
+                        n -= left_info.get(metric);
+                        offset += left_info.bytes;
+                        node = right;
+                    }
+                }
+                Node::Leaf { text, .. } => {
+                    let mut count = 0;
+                    for (i, c) in text.char_indices() {
+                        if count >= n {
+                            return if count == n { Some(offset + i) } else { None };
+                        }
+                        count += metric.of(c);
+                    }
+                    return if count == n { Some(offset + text.len()) } else { None };
+                }
+            }
+        }
+    }
+
+    /// Returns the text in the byte range `range`, or `None` if the range is
+    /// out of bounds or not on char boundaries.
+    pub(crate) fn slice(&self, range: Range<usize>) -> Option<String> {
+        if range.start > range.end
+            || range.end > self.len()
+            || !self.root.is_char_boundary(range.start)
+            || !self.root.is_char_boundary(range.end)
+        {
+            return None;
+        }
+
+        let mut result = String::with_capacity(range.end - range.start);
+        push_range(&self.root, range, &mut result);
+        Some(result)
+    }
+
+    /// Replaces the text in the byte range `range` with `text`. The range must
+    /// be in bounds and on char boundaries.
+    pub(crate) fn replace(&mut self, range: Range<usize>, text: &str) {
+        assert!(range.start <= range.end && range.end <= self.len());
+        assert!(self.root.is_char_boundary(range.start) && self.root.is_char_boundary(range.end));
+
+        let (before, rest) = Node::split(&self.root, range.start);
+        let after = match rest {
+            Some(rest) => Node::split(&rest, range.end - range.start).1,
+            None => None,
+        };
+        let root = Node::join(Node::join(before, Node::from_str(text)), after);
+        self.root = root.unwrap_or_else(|| Node::leaf(String::new()));
+    }
+
+    /// Iterates over the chunks of text in the rope, in order.
+    pub(crate) fn chunks(&self) -> Chunks<'_> {
+        Chunks { stack: vec![&self.root] }
+    }
+
+    /// Calls `f` with each line of the text and its index. Lines include
+    /// their newline. There is always a last line after the last newline,
+    /// which may be empty.
+    pub(crate) fn for_each_line<F, E>(&self, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(&str, usize) -> Result<(), E>,
+    {
+        let mut line = String::new();
+        let mut index = 0;
+        for chunk in self.chunks() {
+            let mut rest = chunk;
+            while let Some(i) = rest.find('\n') {
+                line.push_str(&rest[..=i]);
+                f(&line, index)?;
+                line.clear();
+                index += 1;
+                rest = &rest[i + 1..];
+            }
+            line.push_str(rest);
+        }
+        f(&line, index)
+    }
+}
+
+/// Pushes the text of `node` in the byte range `range` onto `out`, skipping
+/// the subtrees outside of the range.
+fn push_range(node: &Node, range: Range<usize>, out: &mut String) {
+    if range.start >= range.end {
+        return;
+    }
+    match node {
+        Node::Leaf { text, .. } => out.push_str(&text[range]),
+        Node::Branch { left, right, .. } => {
+            let left_bytes = left.info().bytes;
+            if range.start < left_bytes {
+                push_range(left, range.start..range.end.min(left_bytes), out);
+            }
+            if range.end > left_bytes {
+                push_range(
+                    right,
+                    range.start.saturating_sub(left_bytes)..range.end - left_bytes,
+                    out,
+                );
+            }
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Rope {
+    fn from(text: &'a str) -> Rope {
+        Rope { root: Node::from_str(text).unwrap_or_else(|| Node::leaf(String::new())) }
+    }
+}
+
+impl fmt::Display for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in self.chunks() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_string(), f)
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Rope {
+    fn eq(&self, other: &&'a str) -> bool {
+        if self.len() != other.len() {
+            return false;
+        }
+        let mut rest = other.as_bytes();
+        for chunk in self.chunks() {
+            let (head, tail) = rest.split_at(chunk.len());
+            if head != chunk.as_bytes() {
+                return false;
+            }
+            rest = tail;
+        }
+        true
+    }
+}
+
+pub(crate) struct Chunks<'a> {
+    stack: Vec<&'a Arc<Node>>,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        while let Some(node) = self.stack.pop() {
+            match &**node {
+                Node::Branch { left, right, .. } => {
+                    self.stack.push(right);
+                    self.stack.push(left);
+                }
+                Node::Leaf { text, .. } if text.is_empty() => {}
+                Node::Leaf { text, .. } => return Some(text),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_balanced(node: &Node) {
+        if let Node::Branch { left, right, .. } = node {
+            let (l, r) = (left.height(), right.height());
+            assert!(l.max(r) - l.min(r) <= 1, "unbalanced: {} vs {}", l, r);
+            check_balanced(left);
+            check_balanced(right);
+        }
+    }
+
+    fn line_starts(text: &str) -> Vec<usize> {
+        let mut result = vec![0];
+        result.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        result.push(text.len());
+        result
+    }
+
+    #[test]
+    fn line_to_byte() {
+        let text = "foo\nbar\n\nbaz";
+        let rope = Rope::from(text);
+        let starts: Vec<_> = (0..=rope.newlines() + 1).map(|l| rope.line_to_byte(l)).collect();
+        assert_eq!(starts, vec![Some(0), Some(4), Some(8), Some(9), Some(12)]);
+        assert_eq!(rope.line_to_byte(5), None);
+    }
+
+    #[test]
+    fn converts_offsets() {
+        let rope = Rope::from("a😢b\nc");
+        assert_eq!(rope.byte_to(Metric::Chars, 5), 2);
+        assert_eq!(rope.byte_to(Metric::Utf16, 5), 3);
+        assert_eq!(rope.to_byte(Metric::Chars, 2), Some(5));
+        assert_eq!(rope.to_byte(Metric::Utf16, 3), Some(5));
+        // We can't index into the middle of 😢.
+        assert_eq!(rope.to_byte(Metric::Utf16, 2), None);
+        assert_eq!(rope.to_byte(Metric::Chars, 5), Some(8));
+        assert_eq!(rope.to_byte(Metric::Chars, 6), None);
+    }
+
+    #[test]
+    fn slices_across_chunks() {
+        let text: String = (0..1000).map(|i| format!("line {}\n", i)).collect();
+        let rope = Rope::from(&*text);
+        assert!(rope.chunks().count() > 1);
+        assert_eq!(rope.slice(1000..5000).unwrap(), &text[1000..5000]);
+        assert_eq!(rope.slice(0..text.len()).unwrap(), text);
+        assert_eq!(rope.slice(std::ops::Range { start: 5000, end: 1000 }), None);
+        assert_eq!(rope.slice(0..text.len() + 1), None);
+    }
+
+    #[test]
+    fn edits_match_string() {
+        let mut text: String = (0..500).map(|i| format!("{} ünïcödé 😢\n", i)).collect();
+        let mut rope = Rope::from(&*text);
+
+        // A simple LCG, so the test is deterministic.
+        let mut seed = 42u64;
+        let mut random = |bound: usize| {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            (seed >> 33) as usize % bound.max(1)
+        };
+        let boundary = |text: &str, mut i: usize| {
+            while !text.is_char_boundary(i) {
+                i -= 1;
+            }
+            i
+        };
+
+        for round in 0..2000 {
+            let start = boundary(&text, random(text.len() + 1));
+            let end = boundary(&text, start + random(text.len() - start + 1).min(64));
+            let insert = match round % 4 {
+                0 => String::new(),
+                1 => "x".to_owned(),
+                2 => "new\nlines\n😢".to_owned(),
+                _ => "y".repeat(random(3000)),
+            };
+            text.replace_range(start..end, &insert);
+            rope.replace(start..end, &insert);
+        }
+
+        assert_eq!(rope.to_string(), text);
+        check_balanced(&rope.root);
+        let info = Info::of(&text);
+        assert_eq!(rope.root.info(), info);
+        let starts = line_starts(&text);
+        for (line, start) in starts.iter().enumerate() {
+            assert_eq!(rope.line_to_byte(line), Some(*start));
+        }
+
+        let mut lines = vec![];
+        rope.for_each_line(|line, i| -> Result<(), ()> {
+            assert_eq!(i, lines.len());
+            lines.push(line.to_owned());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(lines.len(), starts.len() - 1);
+        assert_eq!(lines.concat(), text);
+    }
+}
@@ -1,9 +1,10 @@
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use span::{self, Column, Position, Row};
 
 use super::{
-    make_line_indices, Change, Error, File, FileContents, FileKind, FileLoader, TextFile,
+    CacheStats, Change, Error, FileContents, FileLoader, MemoryFileLoader, OverlayFileLoader, Vfs,
     VfsInternal, VfsSpan,
 };
 
@@ -12,19 +13,14 @@ type Span = span::Span<span::ZeroIndexed>;
 struct MockFileLoader;
 
 impl FileLoader for MockFileLoader {
-    fn read<U>(file_name: &Path) -> Result<File<U>, Error> {
-        let text = format!("{}\nHello\nWorld\nHello, World!\n", file_name.display());
-        let text_file = TextFile { line_indices: make_line_indices(&text), text, changed: false };
-        Ok(File { kind: FileKind::Text(text_file), user_data: None })
+    fn read(&self, file_name: &Path) -> Result<Vec<u8>, Error> {
+        Ok(format!("{}\nHello\nWorld\nHello, World!\n", file_name.display()).into_bytes())
     }
 
-    fn write(file_name: &Path, file: &FileKind) -> Result<(), Error> {
-        if let FileKind::Text(ref text_file) = *file {
-            if file_name.display().to_string() == "foo" {
-                // TODO: is this test useful still?
-                assert_eq!(text_file.changed, false);
-                assert_eq!(text_file.text, "foo\nHfooo\nWorld\nHello, World!\n");
-            }
+    fn write(&self, file_name: &Path, contents: &[u8]) -> Result<(), Error> {
+        if file_name.display().to_string() == "foo" {
+            // TODO: is this test useful still?
+            assert_eq!(contents, &b"foo\nHfooo\nWorld\nHello, World!\n"[..]);
         }
         Ok(())
     }
@@ -50,6 +46,7 @@ fn make_change(with_len: bool) -> Change {
             len,
         ),
         text: "foo".to_owned(),
+        version: None,
     }
 }
 
@@ -73,11 +70,21 @@ fn make_change_2(with_len: bool) -> Change {
             len,
         ),
         text: "aye carumba".to_owned(),
+        version: None,
+    }
+}
+
+fn versioned(change: Change, v: u64) -> Change {
+    match change {
+        Change::AddFile { file, text, .. } => Change::AddFile { file, text, version: Some(v) },
+        Change::ReplaceText { span, text, .. } => {
+            Change::ReplaceText { span, text, version: Some(v) }
+        }
     }
 }
 
 fn test_has_changes(with_len: bool) {
-    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
 
     assert!(!vfs.has_changes());
     vfs.load_file(&Path::new("foo")).unwrap();
@@ -102,7 +109,7 @@ fn test_has_changes_with_len() {
 
 #[test]
 fn test_cached_files() {
-    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
     assert!(vfs.get_cached_files().is_empty());
     vfs.load_file(&Path::new("foo")).unwrap();
     vfs.load_file(&Path::new("bar")).unwrap();
@@ -112,9 +119,34 @@ fn test_cached_files() {
     assert!(files[Path::new("bar")] == "bar\nHello\nWorld\nHello, World!\n");
 }
 
+#[test]
+fn test_snapshot() {
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
+    vfs.set_file(&Path::new("foo"), "foo\nHello\nWorld\nHello, World!\n", Some(1));
+    let snapshot = vfs.snapshot();
+    assert_eq!(snapshot.version(&Path::new("foo")), Some(1));
+
+    // Changes to the VFS don't change the snapshot.
+    vfs.on_changes(&[versioned(make_change(false), 2)]).unwrap();
+    vfs.load_file(&Path::new("bar")).unwrap();
+    assert_eq!(
+        snapshot.load_file(&Path::new("foo")).unwrap(),
+        "foo\nHello\nWorld\nHello, World!\n"
+    );
+    assert_eq!(snapshot.load_line(&Path::new("foo"), Row::new_zero_indexed(1)).unwrap(), "Hello\n");
+    assert_eq!(snapshot.version(&Path::new("foo")), Some(1));
+    assert_eq!(snapshot.load_file(&Path::new("bar")), Err(Error::FileNotCached));
+
+    let snapshot = vfs.snapshot();
+    assert_eq!(snapshot.files().count(), 2);
+    assert_eq!(snapshot.version(&Path::new("foo")), Some(2));
+    assert_eq!(snapshot.version(&Path::new("bar")), None);
+    assert_eq!(snapshot.to_map(), vfs.get_cached_files());
+}
+
 #[test]
 fn test_flush_file() {
-    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
     // Flushing an uncached-file should succeed.
     vfs.flush_file(&Path::new("foo")).unwrap();
     vfs.load_file(&Path::new("foo")).unwrap();
@@ -123,7 +155,7 @@ fn test_flush_file() {
 }
 
 fn test_changes(with_len: bool) {
-    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
 
     vfs.on_changes(&[make_change(with_len)]).unwrap();
     let files = vfs.get_cached_files();
@@ -160,8 +192,12 @@ fn test_changes_with_len() {
 
 #[test]
 fn test_change_add_file() {
-    let vfs = VfsInternal::<MockFileLoader, ()>::new();
-    let new_file = Change::AddFile { file: PathBuf::from("foo"), text: "Hello, World!".to_owned() };
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
+    let new_file = Change::AddFile {
+        file: PathBuf::from("foo"),
+        text: "Hello, World!".to_owned(),
+        version: None,
+    };
     vfs.on_changes(&[new_file]).unwrap();
 
     let files = vfs.get_cached_files();
@@ -169,8 +205,77 @@ fn test_change_add_file() {
     assert_eq!(files[&PathBuf::from("foo")], "Hello, World!");
 }
 
+#[test]
+fn test_change_add_file_version() {
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
+    let new_file = Change::AddFile {
+        file: PathBuf::from("foo"),
+        text: "foo\nHello\nWorld\nHello, World!\n".to_owned(),
+        version: Some(1),
+    };
+    vfs.on_changes(&[new_file]).unwrap();
+    assert_eq!(vfs.version(&Path::new("foo")), Ok(Some(1)));
+    assert_eq!(vfs.version(&Path::new("bar")), Err(Error::FileNotCached));
+}
+
+#[test]
+fn test_change_versions() {
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
+    vfs.set_file(&Path::new("foo"), "foo\nHello\nWorld\nHello, World!\n", Some(1));
+
+    // Changes in the same edit share a version.
+    vfs.on_changes(&[versioned(make_change(false), 2), versioned(make_change_2(false), 2)])
+        .unwrap();
+    assert_eq!(vfs.version(&Path::new("foo")), Ok(Some(2)));
+    let text = vfs.load_file(&Path::new("foo")).unwrap();
+
+    // Duplicate and out of order changes are rejected.
+    for v in &[1, 2] {
+        assert_eq!(
+            vfs.on_changes(&[versioned(make_change(false), *v)]),
+            Err(Error::UnexpectedVersion(PathBuf::from("foo"), 2, *v))
+        );
+    }
+    assert_eq!(vfs.load_file(&Path::new("foo")).unwrap(), text);
+
+    // A bad version part way through rejects the whole edit.
+    assert_eq!(
+        vfs.on_changes(&[versioned(make_change(false), 3), versioned(make_change(false), 2)]),
+        Err(Error::UnexpectedVersion(PathBuf::from("foo"), 3, 2))
+    );
+    assert_eq!(vfs.load_file(&Path::new("foo")).unwrap(), text);
+    assert_eq!(vfs.version(&Path::new("foo")), Ok(Some(2)));
+
+    // Versions only have to increase.
+    vfs.on_changes(&[versioned(make_change(false), 4)]).unwrap();
+    assert_eq!(vfs.version(&Path::new("foo")), Ok(Some(4)));
+
+    // Unversioned changes are always applied, and clear the version.
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    assert_eq!(vfs.version(&Path::new("foo")), Ok(None));
+    vfs.on_changes(&[versioned(make_change(false), 7)]).unwrap();
+    assert_eq!(vfs.version(&Path::new("foo")), Ok(Some(7)));
+}
+
+#[test]
+fn test_strict_versions() {
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
+    vfs.set_strict_versions(true);
+    vfs.set_file(&Path::new("foo"), "foo\nHello\nWorld\nHello, World!\n", Some(1));
+
+    // Gapped changes are rejected.
+    assert_eq!(
+        vfs.on_changes(&[versioned(make_change(false), 3)]),
+        Err(Error::UnexpectedVersion(PathBuf::from("foo"), 1, 3))
+    );
+    assert_eq!(vfs.version(&Path::new("foo")), Ok(Some(1)));
+    vfs.on_changes(&[versioned(make_change(false), 2), versioned(make_change_2(false), 2)])
+        .unwrap();
+    assert_eq!(vfs.version(&Path::new("foo")), Ok(Some(2)));
+}
+
 fn test_user_data(with_len: bool) {
-    let vfs = VfsInternal::<MockFileLoader, i32>::new();
+    let vfs = VfsInternal::<_, i32>::new(MockFileLoader);
 
     // New files have no user data.
     vfs.load_file(&Path::new("foo")).unwrap();
@@ -279,7 +384,7 @@ fn test_user_data_with_len() {
 }
 
 fn test_write(with_len: bool) {
-    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
 
     vfs.on_changes(&[make_change(with_len)]).unwrap();
     vfs.write_file(&Path::new("foo")).unwrap();
@@ -301,7 +406,7 @@ fn test_write_with_len() {
 
 #[test]
 fn test_clear() {
-    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
     vfs.load_file(&Path::new("foo")).unwrap();
     vfs.load_file(&Path::new("bar")).unwrap();
     assert!(vfs.get_cached_files().len() == 2);
@@ -311,9 +416,9 @@ fn test_clear() {
 
 #[test]
 fn test_wide_utf8() {
-    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
     let changes = [
-        Change::AddFile { file: PathBuf::from("foo"), text: String::from("😢") },
+        Change::AddFile { file: PathBuf::from("foo"), text: String::from("😢"), version: None },
         Change::ReplaceText {
             span: VfsSpan::from_usv(
                 Span::from_positions(
@@ -324,6 +429,7 @@ fn test_wide_utf8() {
                 Some(1),
             ),
             text: "".into(),
+            version: None,
         },
     ];
 
@@ -334,9 +440,9 @@ fn test_wide_utf8() {
 
 #[test]
 fn test_wide_utf16() {
-    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
     let changes = [
-        Change::AddFile { file: PathBuf::from("foo"), text: String::from("😢") },
+        Change::AddFile { file: PathBuf::from("foo"), text: String::from("😢"), version: None },
         Change::ReplaceText {
             span: VfsSpan::from_utf16(
                 Span::from_positions(
@@ -347,6 +453,7 @@ fn test_wide_utf16() {
                 Some(2),
             ),
             text: "".into(),
+            version: None,
         },
     ];
 
@@ -354,3 +461,164 @@ fn test_wide_utf16() {
 
     assert_eq!(vfs.load_file(&Path::new("foo")).unwrap(), FileContents::Text("".to_owned()),);
 }
+
+#[test]
+fn test_memory_loader() {
+    let loader = Arc::new(MemoryFileLoader::new());
+    loader.insert("foo", "Hello, World!");
+    loader.insert("bin", vec![0xff, 0xfe]);
+    let vfs = Vfs::<()>::with_loader(loader.clone());
+
+    assert_eq!(
+        vfs.load_file(&Path::new("foo")).unwrap(),
+        FileContents::Text("Hello, World!".to_owned())
+    );
+    assert_eq!(vfs.load_file(&Path::new("bin")).unwrap(), FileContents::Binary(vec![0xff, 0xfe]));
+    match vfs.load_file(&Path::new("bar")) {
+        Err(Error::Io(Some(path), _)) => assert_eq!(path, Path::new("bar")),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    vfs.set_file(&Path::new("bar"), "Goodbye");
+    vfs.write_file(&Path::new("bar")).unwrap();
+    assert_eq!(loader.read(&Path::new("bar")).unwrap(), b"Goodbye");
+    assert!(!vfs.has_changes());
+}
+
+#[test]
+fn test_overlay_loader() {
+    let base = MemoryFileLoader::new();
+    base.insert("/root/foo", "base foo");
+    base.insert("/root/bar", "base bar");
+    base.insert("/other/baz", "base baz");
+    let loader = OverlayFileLoader::with_base("/root", base);
+    loader.overlay().insert("/root/foo", "overlay foo");
+
+    assert_eq!(loader.read(&Path::new("/root/foo")).unwrap(), b"overlay foo");
+    assert_eq!(loader.read(&Path::new("/root/bar")).unwrap(), b"base bar");
+    assert!(loader.read(&Path::new("/root/baz")).is_err());
+
+    // Writes don't reach the base.
+    loader.write(&Path::new("/root/bar"), b"new bar").unwrap();
+    assert_eq!(loader.read(&Path::new("/root/bar")).unwrap(), b"new bar");
+    assert_eq!(loader.base().read(&Path::new("/root/bar")).unwrap(), b"base bar");
+
+    // Files outside the root can't be seen or changed.
+    assert!(loader.read(&Path::new("/other/baz")).is_err());
+    assert!(loader.write(&Path::new("/other/baz"), b"new baz").is_err());
+    assert!(!loader.overlay().contains(&Path::new("/other/baz")));
+}
+
+#[test]
+fn test_overlay_loader_dir() {
+    let dir = std::env::temp_dir().join(format!("rls-vfs-test-overlay-{}", std::process::id()));
+    let src = dir.join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    let (lib, main, manifest) = (src.join("lib.rs"), src.join("main.rs"), dir.join("Cargo.toml"));
+    std::fs::write(&lib, "lib on disk").unwrap();
+    std::fs::write(&manifest, "[package]").unwrap();
+
+    let vfs = Vfs::<()>::with_loader(OverlayFileLoader::new(&src));
+    assert_eq!(vfs.load_file(&lib).unwrap(), FileContents::Text("lib on disk".to_owned()));
+
+    vfs.set_file(&lib, "lib in memory");
+    vfs.write_file(&lib).unwrap();
+    vfs.set_file(&main, "main in memory");
+    vfs.write_file(&main).unwrap();
+    let outside = vfs.load_file(&manifest);
+
+    let on_disk = std::fs::read_to_string(&lib).unwrap();
+    let main_exists = main.exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(on_disk, "lib on disk");
+    assert!(!main_exists);
+    assert!(outside.is_err());
+}
+
+#[test]
+fn test_memory_limit() {
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
+    // Each file is 30 bytes, so the limit fits two.
+    vfs.set_memory_limit(Some(70));
+    vfs.load_file(&Path::new("foo")).unwrap();
+    vfs.load_file(&Path::new("bar")).unwrap();
+    vfs.load_file(&Path::new("foo")).unwrap();
+    vfs.load_file(&Path::new("baz")).unwrap();
+
+    // `bar` was the least recently used.
+    let files = vfs.get_cached_files();
+    assert_eq!(files.len(), 2);
+    assert!(files.contains_key(Path::new("foo")));
+    assert!(files.contains_key(Path::new("baz")));
+    assert_eq!(
+        vfs.cache_stats(),
+        CacheStats { files: 2, bytes: 60, hits: 1, misses: 3, evictions: 1 }
+    );
+    assert_eq!(vfs.cache_stats().hit_rate(), 0.25);
+
+    // Files the client is responsible for are kept, even over the limit.
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    vfs.set_file(&Path::new("qux"), "qux\nHello\nWorld\nHello, World!\n", Some(1));
+    vfs.set_memory_limit(Some(0));
+    let files = vfs.get_cached_files();
+    assert_eq!(files.len(), 2);
+    assert!(files.contains_key(Path::new("foo")));
+    assert!(files.contains_key(Path::new("qux")));
+
+    vfs.set_memory_limit(None);
+    vfs.load_file(&Path::new("bar")).unwrap();
+    vfs.load_file(&Path::new("baz")).unwrap();
+    assert_eq!(vfs.cache_stats().files, 4);
+}
+
+#[test]
+fn test_file_closed() {
+    let vfs = VfsInternal::<_, ()>::new(MockFileLoader);
+    vfs.set_memory_limit(Some(0));
+    vfs.set_file(&Path::new("foo"), "foo\nHello\nWorld\nHello, World!\n", Some(1));
+    vfs.set_file(&Path::new("bar"), "bar\nGoodbye\n", Some(1));
+    assert_eq!(vfs.get_cached_files().len(), 2);
+
+    // `foo` is the same as on disk, so can be evicted once closed.
+    vfs.file_closed(&Path::new("foo"));
+    vfs.file_closed(&Path::new("bar"));
+    let files = vfs.get_cached_files();
+    assert_eq!(files.len(), 1);
+    assert!(files.contains_key(Path::new("bar")));
+    assert_eq!(vfs.version(&Path::new("bar")), Ok(None));
+}
+
+#[cfg(all(feature = "watcher", target_os = "linux"))]
+#[test]
+fn test_watcher() {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let dir = std::env::temp_dir().join(format!("rls-vfs-test-watcher-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (foo, bar) = (dir.join("foo.rs"), dir.join("bar.rs"));
+    std::fs::write(&foo, "foo").unwrap();
+    std::fs::write(&bar, "bar").unwrap();
+
+    let vfs = Vfs::<()>::new();
+    let (sender, receiver) = mpsc::channel();
+    vfs.watch(sender).unwrap();
+    vfs.load_file(&foo).unwrap();
+    vfs.load_file(&bar).unwrap();
+    vfs.set_file_with_version(&bar, "bar in memory", 1);
+
+    // Neither a file the client is responsible for, nor one which isn't
+    // cached, is reported.
+    std::fs::write(&bar, "bar on disk").unwrap();
+    std::fs::write(dir.join("baz.rs"), "baz on disk").unwrap();
+    std::fs::write(&foo, "foo on disk").unwrap();
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(foo.clone()));
+    assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Files changed in memory are the client's responsibility.
+    let files = vfs.get_cached_files();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[&bar], "bar in memory");
+}
@@ -0,0 +1,165 @@
+//! Watches the directories of cached files using inotify, so that the VFS
+//! doesn't keep serving stale text for files which changed on disk.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, Thread};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::File;
+
+// How often the watcher thread checks whether the VFS has been dropped.
+const POLL_TIMEOUT_MS: libc::c_int = 1000;
+
+type PendingFiles = Mutex<HashMap<PathBuf, Vec<Thread>>>;
+type Files<U> = Mutex<HashMap<PathBuf, File<U>>>;
+
+pub(crate) struct Watcher {
+    inotify: Arc<Mutex<Inotify>>,
+    dirs: Arc<Mutex<Dirs>>,
+}
+
+#[derive(Default)]
+struct Dirs {
+    by_watch: HashMap<WatchDescriptor, PathBuf>,
+    watched: HashSet<PathBuf>,
+}
+
+impl Watcher {
+    /// Starts a thread which evicts files which change on disk and sends
+    /// their paths to `subscriber`. The thread exits once the VFS is dropped.
+    pub(crate) fn start<U: Send + 'static>(
+        pending_files: Weak<PendingFiles>,
+        files: Weak<Files<U>>,
+        subscriber: Sender<PathBuf>,
+    ) -> io::Result<Watcher> {
+        let inotify = Arc::new(Mutex::new(Inotify::init()?));
+        let dirs = Arc::new(Mutex::new(Dirs::default()));
+
+        let (thread_inotify, thread_dirs) = (Arc::clone(&inotify), Arc::clone(&dirs));
+        thread::Builder::new().name("rls-vfs-watcher".to_owned()).spawn(move || {
+            run(&thread_inotify, &thread_dirs, &pending_files, &files, &subscriber)
+        })?;
+
+        Ok(Watcher { inotify, dirs })
+    }
+
+    /// Watches the directory containing `file`, if it isn't already watched.
+    pub(crate) fn watch(&self, file: &Path) {
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut dirs = self.dirs.lock().unwrap();
+        if dirs.watched.contains(dir) {
+            return;
+        }
+
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO;
+        match self.inotify.lock().unwrap().add_watch(dir, mask) {
+            Ok(wd) => {
+                dirs.by_watch.insert(wd, dir.to_owned());
+                dirs.watched.insert(dir.to_owned());
+            }
+            Err(e) => debug!("could not watch {}: {}", dir.display(), e),
+        }
+    }
+}
+
+fn run<U>(
+    inotify: &Mutex<Inotify>,
+    dirs: &Mutex<Dirs>,
+    pending_files: &Weak<PendingFiles>,
+    files: &Weak<Files<U>>,
+    subscriber: &Sender<PathBuf>,
+) {
+    // The descriptor stays open for as long as we hold `inotify`.
+    let fd = inotify.lock().unwrap().as_raw_fd();
+    let mut buffer = [0; 4096];
+    loop {
+        // Wait without holding the lock, so that watches can be added
+        // meanwhile.
+        let mut poll_fd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, POLL_TIMEOUT_MS) };
+
+        let (pending_files, files) = match (pending_files.upgrade(), files.upgrade()) {
+            (Some(pending_files), Some(files)) => (pending_files, files),
+            _ => return,
+        };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            debug!("watcher stopped, polling failed: {}", e);
+            return;
+        }
+        if ready == 0 {
+            continue;
+        }
+
+        // Don't hold the inotify lock while locking anything else.
+        let events: Vec<_> = match inotify.lock().unwrap().read_events(&mut buffer) {
+            Ok(events) => events.map(|event| event.into_owned()).collect(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => {
+                debug!("watcher stopped, reading events failed: {}", e);
+                return;
+            }
+        };
+
+        let mut changed = vec![];
+        let mut overflowed = false;
+        {
+            let mut dirs = dirs.lock().unwrap();
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    overflowed = true;
+                } else if event.mask.contains(EventMask::IGNORED) {
+                    // The directory was deleted or unmounted.
+                    if let Some(dir) = dirs.by_watch.remove(&event.wd) {
+                        dirs.watched.remove(&dir);
+                    }
+                } else if event.mask.contains(EventMask::ISDIR) {
+                    continue;
+                } else if let (Some(dir), Some(name)) = (dirs.by_watch.get(&event.wd), event.name) {
+                    changed.push(dir.join(name));
+                }
+            }
+        }
+        if overflowed {
+            // We've missed events, so any file might have changed.
+            changed.extend(files.lock().unwrap().keys().cloned());
+        }
+
+        for path in changed {
+            if evict(&pending_files, &files, &path) {
+                // Nobody may be listening, but we still need to evict files.
+                let _ = subscriber.send(path);
+            }
+        }
+    }
+}
+
+/// Evicts the file at `path` if it is cached, unless the client is responsible
+/// for it. Returns whether the file was evicted.
+fn evict<U>(pending_files: &PendingFiles, files: &Files<U>, path: &Path) -> bool {
+    let _pending_files = pending_files.lock().unwrap();
+    let mut files = files.lock().unwrap();
+    match files.get(path) {
+        Some(file) if !file.owned_by_client() => {
+            files.remove(path);
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,98 @@
+//! Hierarchical document symbols, for the outline of a file.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use log::debug;
+use rls_analysis::{DefKind, Id, SymbolResult};
+
+use crate::actions::folding_range;
+use crate::actions::InitActionContext;
+use crate::lsp_data::{
+    ls_util, source_kind_from_def_kind, symbol_deprecated, DocumentSymbol, Position, Range,
+};
+
+/// Nests `symbols`, which must all be in `file_path`, under their parents.
+pub fn document_symbols(
+    ctx: &InitActionContext,
+    file_path: &Path,
+    symbols: Vec<SymbolResult>,
+) -> Vec<DocumentSymbol> {
+    let text = ctx.file_text(&ctx.snapshot(), file_path).unwrap_or_else(|e| {
+        debug!("document_symbols: couldn't load {:?}: {:?}", file_path, e);
+        String::new()
+    });
+    let lines: Vec<&str> = text.lines().collect();
+    let scan = folding_range::scan(&text);
+
+    let ids: HashSet<Id> = symbols.iter().map(|s| s.id).collect();
+    let mut roots = vec![];
+    let mut children: HashMap<Id, Vec<SymbolResult>> = HashMap::new();
+    for symbol in symbols {
+        match symbol.parent.filter(|parent| ids.contains(parent)) {
+            Some(parent) => children.entry(parent).or_insert_with(Vec::new).push(symbol),
+            None => roots.push(symbol),
+        }
+    }
+
+    let mut builder = Builder { ctx, scan: &scan, lines: &lines, children };
+    builder.build_all(roots)
+}
+
+struct Builder<'a> {
+    ctx: &'a InitActionContext,
+    scan: &'a folding_range::Scan,
+    lines: &'a [&'a str],
+    children: HashMap<Id, Vec<SymbolResult>>,
+}
+
+impl<'a> Builder<'a> {
+    fn build_all(&mut self, mut symbols: Vec<SymbolResult>) -> Vec<DocumentSymbol> {
+        symbols.sort_by_key(|s| (s.span.range.row_start, s.span.range.col_start));
+        symbols.into_iter().map(|s| self.build(s)).collect()
+    }
+
+    fn build(&mut self, symbol: SymbolResult) -> DocumentSymbol {
+        let selection_range = ls_util::rls_to_range(symbol.span.range);
+        let range = match self.scan.item_extent(self.lines, &symbol) {
+            Some((start, end)) => Range {
+                start: Position::new(u64::from(start.0), u64::from(start.1)),
+                end: Position::new(u64::from(end.0), u64::from(end.1)),
+            },
+            None => selection_range,
+        };
+        // Clients require the selection range to be within the range.
+        let range = if range.start <= selection_range.start && selection_range.end <= range.end {
+            range
+        } else {
+            selection_range
+        };
+
+        let def = self.ctx.analysis.get_def(symbol.id).ok();
+        let detail = def.as_ref().and_then(|def| symbol_detail(def.kind, &def.value));
+        let deprecated = def.as_ref().and_then(|def| symbol_deprecated(&def.attributes));
+
+        // Taking the children out of the map also guards against cycles.
+        let children = self.children.remove(&symbol.id).map(|children| self.build_all(children));
+
+        DocumentSymbol {
+            name: symbol.name,
+            detail,
+            kind: source_kind_from_def_kind(symbol.kind),
+            deprecated,
+            range,
+            selection_range,
+            children,
+        }
+    }
+}
+
+/// The detail shown next to a symbol, e.g., the signature of a function or
+/// the type of a field.
+fn symbol_detail(kind: DefKind, value: &str) -> Option<String> {
+    match kind {
+        // The value of a module is its file name.
+        DefKind::Mod => None,
+        _ => Some(value.trim()).filter(|value| !value.is_empty()).map(str::to_owned),
+    }
+}
@@ -0,0 +1,518 @@
+//! Folding ranges for items, `use` blocks and comments.
+//!
+//! Ranges are computed from the current text of the file in the VFS, using
+//! the save-analysis data only to tell where items are. That way the results
+//! don't go out of date while the analysis is stale or still being built.
+
+use std::cmp::Reverse;
+use std::path::Path;
+
+use log::debug;
+use rls_analysis::{DefKind, SymbolResult};
+
+use crate::actions::InitActionContext;
+use crate::lsp_data::{FoldingRange, FoldingRangeKind};
+
+/// A (row, column) position in a file, with the column counted in chars.
+pub(crate) type TextPosition = (u32, u32);
+
+/// A `{` or `;` outside of any parentheses or brackets.
+#[derive(Debug, PartialEq, Eq)]
+struct Delimiter {
+    row: u32,
+    col: u32,
+    kind: DelimiterKind,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum DelimiterKind {
+    /// An opening brace, with the position of the matching closing brace.
+    Brace {
+        close: Option<TextPosition>,
+    },
+    Semicolon,
+}
+
+impl Delimiter {
+    fn close_row(&self) -> Option<u32> {
+        match self.kind {
+            DelimiterKind::Brace { close } => close.map(|(row, _)| row),
+            DelimiterKind::Semicolon => None,
+        }
+    }
+}
+
+/// A matching pair of parentheses, brackets or braces.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BracketPair {
+    pub(crate) open: TextPosition,
+    pub(crate) close: TextPosition,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Comment {
+    /// A `///` or `//!` comment on a single row.
+    Doc { row: u32, inner: bool },
+    /// A `/* */` comment.
+    Block { start_row: u32, end_row: u32 },
+}
+
+/// Tokens of a file relevant for folding.
+#[derive(Debug, Default)]
+pub(crate) struct Scan {
+    delimiters: Vec<Delimiter>,
+    comments: Vec<Comment>,
+    brackets: Vec<BracketPair>,
+}
+
+impl Scan {
+    /// Returns the position of the end of the item whose name ends at `pos`,
+    /// i.e., of the closing brace of its body or of its terminating semicolon.
+    pub(crate) fn item_end(&self, pos: TextPosition) -> Option<TextPosition> {
+        let delimiter = self.delimiters.iter().find(|d| (d.row, d.col) >= pos)?;
+        match delimiter.kind {
+            DelimiterKind::Brace { close } => close,
+            DelimiterKind::Semicolon => Some((delimiter.row, delimiter.col)),
+        }
+    }
+
+    /// Returns the text of the item `symbol`, from the start of the row of its
+    /// name to the end of its body, or `None` if `symbol` is not an item or the
+    /// text doesn't match the (possibly stale) analysis.
+    pub(crate) fn item_extent(
+        &self,
+        lines: &[&str],
+        symbol: &SymbolResult,
+    ) -> Option<(TextPosition, TextPosition)> {
+        match symbol.kind {
+            DefKind::Local | DefKind::Field | DefKind::TupleVariant | DefKind::StructVariant => {
+                return None
+            }
+            _ => {}
+        }
+        let range = symbol.span.range;
+        let line = lines.get(range.row_start.0 as usize)?;
+        if !line.contains(&symbol.name) {
+            return None;
+        }
+        let indent = line.chars().take_while(|c| c.is_whitespace()).count() as u32;
+        let end = self.item_end((range.row_end.0, range.col_end.0))?;
+        Some(((range.row_start.0, indent), (end.0, end.1 + 1)))
+    }
+
+    /// Returns the bracket pairs which enclose `pos`, innermost first.
+    pub(crate) fn enclosing_brackets(&self, pos: TextPosition) -> Vec<&BracketPair> {
+        let mut result: Vec<_> =
+            self.brackets.iter().filter(|b| b.open < pos && pos <= b.close).collect();
+        result.sort_by_key(|b| Reverse(b.open));
+        result
+    }
+}
+
+pub fn folding_ranges(ctx: &InitActionContext, file_path: &Path) -> Vec<FoldingRange> {
+    let text = match ctx.file_text(&ctx.snapshot(), file_path) {
+        Ok(text) => text,
+        Err(e) => {
+            debug!("folding_ranges: couldn't load {:?}: {:?}", file_path, e);
+            return vec![];
+        }
+    };
+
+    let lines: Vec<&str> = text.lines().collect();
+    let scan = scan(&text);
+
+    let symbols = ctx.analysis.symbols(file_path).unwrap_or_else(|_| vec![]);
+    let item_ranges = symbols.iter().filter_map(|symbol| {
+        let row = symbol.span.range.row_start.0;
+        // The analysis may be out of date, in which case the item is likely
+        // not where we think it is anymore.
+        if !lines.get(row as usize).map_or(false, |line| line.contains(&symbol.name)) {
+            return None;
+        }
+        // If the body can't be found in the text (e.g., it is being edited),
+        // we still know where the children of the item are.
+        let end_row = item_end_row(&scan, symbol).or_else(|| {
+            let child_rows = ctx.analysis.for_each_child_def(symbol.id, |_, def| {
+                Some(def.span.range.row_end.0).filter(|_| def.span.file == symbol.span.file)
+            });
+            child_rows.ok()?.into_iter().flatten().max()
+        })?;
+        folding_range(row, end_row, None)
+    });
+
+    let mut ranges: Vec<_> = item_ranges.chain(text_folding_ranges(&scan, &lines)).collect();
+    // Prefer the longest range starting on a given line.
+    ranges.sort_by(|a, b| a.start_line.cmp(&b.start_line).then(b.end_line.cmp(&a.end_line)));
+    ranges.dedup_by_key(|range| range.start_line);
+    ranges
+}
+
+/// Returns the row of the closing brace of the body of `symbol`, if it has one.
+fn item_end_row(scan: &Scan, symbol: &SymbolResult) -> Option<u32> {
+    match symbol.kind {
+        DefKind::Function
+        | DefKind::Method
+        | DefKind::Struct
+        | DefKind::Enum
+        | DefKind::Union
+        | DefKind::Trait
+        | DefKind::Mod => {}
+        _ => return None,
+    }
+    let range = symbol.span.range;
+    next_delimiter(scan, range.row_end.0, range.col_end.0).and_then(Delimiter::close_row)
+}
+
+/// Returns the first delimiter at or after the given position.
+fn next_delimiter(scan: &Scan, row: u32, col: u32) -> Option<&Delimiter> {
+    scan.delimiters.iter().find(|d| (d.row, d.col) >= (row, col))
+}
+
+/// Computes ranges for `impl` blocks, `use` blocks and comments, which don't
+/// need the analysis.
+fn text_folding_ranges(scan: &Scan, lines: &[&str]) -> Vec<FoldingRange> {
+    let mut ranges = vec![];
+
+    // `use` declarations on consecutive rows.
+    let mut imports: Option<(u32, u32)> = None;
+    for (row, line) in lines.iter().enumerate() {
+        let row = row as u32;
+        let line = strip_visibility(line.trim_start());
+        if starts_with_keyword(line, "impl") || line.starts_with("unsafe impl") {
+            let end_row = next_delimiter(scan, row, 0).and_then(Delimiter::close_row);
+            ranges.extend(end_row.and_then(|end_row| folding_range(row, end_row, None)));
+        }
+
+        if let Some((start_row, end_row)) = imports {
+            if row <= end_row {
+                continue;
+            }
+            if !line.starts_with("use ") {
+                ranges.extend(folding_range(start_row, end_row, Some(FoldingRangeKind::Imports)));
+                imports = None;
+                continue;
+            }
+        }
+        if line.starts_with("use ") {
+            let end_row = scan
+                .delimiters
+                .iter()
+                .find(|d| d.row >= row && d.kind == DelimiterKind::Semicolon)
+                .map_or(row, |d| d.row);
+            let start_row = imports.map_or(row, |(start_row, _)| start_row);
+            imports = Some((start_row, end_row));
+        }
+    }
+    if let Some((start_row, end_row)) = imports {
+        ranges.extend(folding_range(start_row, end_row, Some(FoldingRangeKind::Imports)));
+    }
+
+    // Runs of doc comments of the same kind and block comments.
+    let comment_range =
+        |start_row, end_row| folding_range(start_row, end_row, Some(FoldingRangeKind::Comment));
+    let mut doc_run: Option<(u32, u32, bool)> = None;
+    for comment in &scan.comments {
+        match *comment {
+            Comment::Doc { row, inner } => match doc_run {
+                Some((start_row, end_row, run_inner))
+                    if end_row + 1 == row && run_inner == inner =>
+                {
+                    doc_run = Some((start_row, row, inner));
+                }
+                _ => {
+                    if let Some((start_row, end_row, _)) = doc_run {
+                        ranges.extend(comment_range(start_row, end_row));
+                    }
+                    doc_run = Some((row, row, inner));
+                }
+            },
+            Comment::Block { start_row, end_row } => {
+                if let Some((start_row, end_row, _)) = doc_run.take() {
+                    ranges.extend(comment_range(start_row, end_row));
+                }
+                ranges.extend(comment_range(start_row, end_row));
+            }
+        }
+    }
+    if let Some((start_row, end_row, _)) = doc_run {
+        ranges.extend(comment_range(start_row, end_row));
+    }
+
+    ranges
+}
+
+fn starts_with_keyword(line: &str, keyword: &str) -> bool {
+    line.starts_with(keyword) && !line[keyword.len()..].starts_with(is_ident_char)
+}
+
+fn strip_visibility(line: &str) -> &str {
+    if !line.starts_with("pub") {
+        return line;
+    }
+    let rest = &line[3..];
+    let rest = if rest.starts_with('(') {
+        match rest.find(')') {
+            Some(idx) => &rest[idx + 1..],
+            None => return line,
+        }
+    } else {
+        rest
+    };
+    if rest.starts_with(char::is_whitespace) {
+        rest.trim_start()
+    } else {
+        line
+    }
+}
+
+fn folding_range(
+    start_row: u32,
+    end_row: u32,
+    kind: Option<FoldingRangeKind>,
+) -> Option<FoldingRange> {
+    if start_row >= end_row {
+        return None;
+    }
+    Some(FoldingRange {
+        start_line: u64::from(start_row),
+        start_character: None,
+        end_line: u64::from(end_row),
+        end_character: None,
+        kind,
+    })
+}
+
+/// Finds the brackets, semicolons and comments in `text`, skipping over
+/// string and char literals.
+pub(crate) fn scan(text: &str) -> Scan {
+    let chars: Vec<char> = text.chars().collect();
+    let mut scan = Scan::default();
+    // Unclosed brackets, along with the index into `scan.delimiters` for
+    // braces outside of any parentheses or brackets.
+    let mut open_brackets: Vec<(char, TextPosition, Option<usize>)> = vec![];
+    let mut nesting = 0u32;
+    let (mut row, mut col) = (0u32, 0u32);
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let next = chars.get(idx + 1).copied();
+        let prev_is_ident = idx > 0 && is_ident_char(chars[idx - 1]);
+        let end = match (chars[idx], next) {
+            ('/', Some('/')) => {
+                let end =
+                    chars[idx..].iter().position(|&c| c == '\n').map_or(chars.len(), |n| idx + n);
+                let comment: String = chars[idx..end].iter().collect();
+                if (comment.starts_with("///") && !comment.starts_with("////"))
+                    || comment.starts_with("//!")
+                {
+                    scan.comments.push(Comment::Doc { row, inner: comment.starts_with("//!") });
+                }
+                end
+            }
+            ('/', Some('*')) => {
+                // Block comments can be nested.
+                let mut depth = 0;
+                let mut i = idx;
+                while i < chars.len() {
+                    match (chars[i], chars.get(i + 1)) {
+                        ('/', Some('*')) => {
+                            depth += 1;
+                            i += 2;
+                        }
+                        ('*', Some('/')) => {
+                            depth -= 1;
+                            i += 2;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => i += 1,
+                    }
+                }
+                let end = i.min(chars.len());
+                let rows = chars[idx..end].iter().filter(|&&c| c == '\n').count() as u32;
+                scan.comments.push(Comment::Block { start_row: row, end_row: row + rows });
+                end
+            }
+            ('r', Some('"')) | ('r', Some('#'))
+                if !prev_is_ident || is_byte_prefix(&chars, idx) =>
+            {
+                let hashes = chars[idx + 1..].iter().take_while(|&&c| c == '#').count();
+                if chars.get(idx + 1 + hashes) != Some(&'"') {
+                    idx += 1;
+                    col += 1;
+                    continue;
+                }
+                let mut i = idx + hashes + 2;
+                while i < chars.len()
+                    && !(chars[i] == '"'
+                        && chars[i + 1..].iter().take(hashes).filter(|&&c| c == '#').count()
+                            == hashes)
+                {
+                    i += 1;
+                }
+                (i + 1 + hashes).min(chars.len())
+            }
+            ('"', _) => {
+                let mut i = idx + 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                (i + 1).min(chars.len())
+            }
+            // A char literal, as opposed to a lifetime (e.g. `'a`).
+            ('\'', Some('\\')) => chars[idx + 2..]
+                .iter()
+                .position(|&c| c == '\'')
+                .map_or(chars.len(), |n| idx + n + 3),
+            ('\'', Some(_)) if chars.get(idx + 2) == Some(&'\'') => idx + 3,
+            (c, _) => {
+                match c {
+                    '(' | '[' => {
+                        nesting += 1;
+                        open_brackets.push((c, (row, col), None));
+                    }
+                    '{' => {
+                        let delimiter = if nesting == 0 {
+                            let kind = DelimiterKind::Brace { close: None };
+                            scan.delimiters.push(Delimiter { row, col, kind });
+                            Some(scan.delimiters.len() - 1)
+                        } else {
+                            None
+                        };
+                        open_brackets.push((c, (row, col), delimiter));
+                    }
+                    ')' | ']' | '}' => {
+                        let expected = match c {
+                            ')' => '(',
+                            ']' => '[',
+                            _ => '{',
+                        };
+                        // Brackets left unclosed (e.g., while typing) are
+                        // dropped, unmatched closing brackets are ignored.
+                        if open_brackets.iter().any(|b| b.0 == expected) {
+                            while let Some((open_char, open, delimiter)) = open_brackets.pop() {
+                                if open_char != '{' {
+                                    nesting -= 1;
+                                }
+                                if open_char != expected {
+                                    continue;
+                                }
+                                if let Some(idx) = delimiter {
+                                    let close = Some((row, col));
+                                    scan.delimiters[idx].kind = DelimiterKind::Brace { close };
+                                }
+                                scan.brackets.push(BracketPair { open, close: (row, col) });
+                                break;
+                            }
+                        }
+                    }
+                    ';' if nesting == 0 => {
+                        let kind = DelimiterKind::Semicolon;
+                        scan.delimiters.push(Delimiter { row, col, kind });
+                    }
+                    '\n' => {
+                        row += 1;
+                        col = 0;
+                        idx += 1;
+                        continue;
+                    }
+                    _ => {}
+                }
+                idx += 1;
+                col += 1;
+                continue;
+            }
+        };
+
+        // Keep track of the position after skipping a comment or a literal.
+        for &c in &chars[idx..end] {
+            if c == '\n' {
+                row += 1;
+                col = 0;
+            } else {
+                col += 1;
+            }
+        }
+        idx = end;
+    }
+
+    scan
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// `true` if the `r` at `idx` starts a raw byte string (`br"..."`).
+fn is_byte_prefix(chars: &[char], idx: usize) -> bool {
+    idx > 0 && chars[idx - 1] == 'b' && (idx == 1 || !is_ident_char(chars[idx - 2]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ranges(text: &str) -> Vec<(u64, u64, Option<FoldingRangeKind>)> {
+        let lines: Vec<&str> = text.lines().collect();
+        text_folding_ranges(&scan(text), &lines)
+            .into_iter()
+            .map(|r| (r.start_line, r.end_line, r.kind))
+            .collect()
+    }
+
+    fn delimiters(scan: &Scan) -> Vec<(u32, u32, Option<u32>)> {
+        scan.delimiters.iter().map(|d| (d.row, d.col, d.close_row())).collect()
+    }
+
+    #[test]
+    fn matches_braces() {
+        let scan = scan("fn foo() {\n    let s = \"}\";\n    '}';\n    { }\n}\nstruct Bar;\n");
+        assert_eq!(
+            delimiters(&scan),
+            vec![(0, 9, Some(4)), (1, 15, None), (2, 7, None), (3, 4, Some(3)), (5, 10, None)]
+        );
+    }
+
+    #[test]
+    fn ignores_delimiters_in_parens_and_raw_strings() {
+        let scan = scan("fn foo(x: [u8; 4]) {\n    r#\"{\"#;\n}\n");
+        assert_eq!(delimiters(&scan), vec![(0, 19, Some(2)), (1, 10, None)]);
+    }
+
+    #[test]
+    fn recovers_from_unbalanced_brackets() {
+        let scan = scan("fn foo() {\n    bar(\n}\nfn baz() { [) }\n");
+        assert_eq!(delimiters(&scan), vec![(0, 9, Some(2)), (3, 9, Some(3))]);
+        assert_eq!(
+            scan.enclosing_brackets((3, 12)),
+            vec![&BracketPair { open: (3, 9), close: (3, 14) }]
+        );
+    }
+
+    #[test]
+    fn folds_imports() {
+        let text = "use a;\npub use b::{\n    c,\n};\n\nuse d;\nfn main() {}\n";
+        assert_eq!(ranges(text), vec![(0, 3, Some(FoldingRangeKind::Imports))]);
+    }
+
+    #[test]
+    fn folds_impls() {
+        let text = "impl Foo {\n    fn foo() {}\n}\nunsafe impl Send for Foo {}\nimpls! {\n}\n";
+        assert_eq!(ranges(text), vec![(0, 2, None)]);
+    }
+
+    #[test]
+    fn folds_comments() {
+        let text =
+            "//! Crate\n//! docs\n/// Item\n/// docs\n// ///\nfn foo() {}\n/* a\n/* b */\n*/\n";
+        assert_eq!(
+            ranges(text),
+            vec![
+                (0, 1, Some(FoldingRangeKind::Comment)),
+                (2, 3, Some(FoldingRangeKind::Comment)),
+                (6, 8, Some(FoldingRangeKind::Comment)),
+            ]
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use std::path::{Path, PathBuf};
 
 use log::*;
-use rls_analysis::{Def, DefKind};
+use rls_analysis::{Attribute, Def, DefKind};
 use rls_span::{Column, Range, Row, Span, ZeroIndexed};
 use rls_vfs::{self as vfs, Vfs};
 use rustfmt_nightly::NewlineStyle;
@@ -447,6 +447,83 @@ fn tooltip_static_const_decl(
     create_tooltip(the_type, doc_url, context, docs)
 }
 
+fn tooltip_macro(ctx: &InitActionContext, def: &Def, doc_url: Option<String>) -> Vec<MarkedString> {
+    debug!("tooltip_macro: {}", def.name);
+
+    let vfs = &ctx.vfs;
+
+    let the_type = def_decl(def, &vfs, || def.value.trim().into());
+    let docs = def_docs(def, &vfs);
+    let context = None;
+
+    create_tooltip(the_type, doc_url, context, docs)
+}
+
+/// Describes the attributes of a def which are worth showing in a tooltip,
+/// i.e., whether the def is deprecated. Rustc expands `#[derive]` before
+/// save-analysis and omits the derived impls, so we can't show derives.
+fn attribute_notes(attributes: &[Attribute]) -> Option<String> {
+    let notes: Vec<String> = attributes
+        .iter()
+        .filter(|attr| attr.name() == "deprecated")
+        .map(deprecation_note)
+        .collect();
+    if notes.is_empty() {
+        None
+    } else {
+        Some(notes.join("\n\n"))
+    }
+}
+
+/// Formats `#[deprecated]`, `#[deprecated = "note"]` or
+/// `#[deprecated(since = "version", note = "note")]`.
+fn deprecation_note(attr: &Attribute) -> String {
+    let (since, note) = match attr.args() {
+        Some(args) => (attribute_arg(args, "since"), attribute_arg(args, "note")),
+        None => (None, attr.value.find('=').and_then(|pos| quoted(&attr.value[pos + 1..]))),
+    };
+
+    let mut result = "**Deprecated**".to_owned();
+    if let Some(since) = since {
+        result.push_str(&format!(" since {}", since));
+    }
+    if let Some(note) = note {
+        result.push_str(&format!(": {}", note));
+    }
+    result
+}
+
+/// Returns the value of a `key = "value"` argument of an attribute.
+fn attribute_arg<'a>(args: &'a str, key: &str) -> Option<&'a str> {
+    let mut rest = args;
+    while let Some(pos) = rest.find(key) {
+        let is_start = rest[..pos].trim_end().ends_with(',') || rest[..pos].trim().is_empty();
+        let after = rest[pos + key.len()..].trim_start();
+        if is_start && after.starts_with('=') {
+            return quoted(&after[1..]);
+        }
+        rest = &rest[pos + key.len()..];
+    }
+    None
+}
+
+/// Returns the contents of the string literal at the start of `text`.
+fn quoted(text: &str) -> Option<&str> {
+    let text = text.trim_start();
+    if !text.starts_with('"') {
+        return None;
+    }
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            '\\' => escaped = !escaped,
+            '"' if !escaped => return Some(&text[1..i]),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
 fn empty_to_none(s: String) -> Option<String> {
     if s.trim().is_empty() {
         None
@@ -628,6 +705,8 @@ fn racer_match_to_def(ctx: &InitActionContext, m: &racer::Match) -> Option<Def>
             distro_crate: false,
             parent: None,
             docs,
+            sig: None,
+            attributes: vec![],
         };
         trace!(
             "racer_match_to_def: Def {{ kind: {:?}, span: {:?}, name: {:?}, \
@@ -833,8 +912,9 @@ pub fn tooltip(
     });
 
     let doc_url = analysis.doc_url(&hover_span).ok();
+    let notes = hover_span_def.as_ref().ok().and_then(|def| attribute_notes(&def.attributes));
 
-    let contents = if let Ok(def) = hover_span_def {
+    let mut contents = if let Ok(def) = hover_span_def {
         if def.kind == DefKind::Local && def.span == hover_span && def.qualname.contains('$') {
             tooltip_local_variable_decl(&ctx, &def, doc_url)
         } else if def.kind == DefKind::Local
@@ -863,6 +943,7 @@ pub fn tooltip(
                     tooltip_static_const_decl(&ctx, &def, doc_url)
                 }
                 DefKind::Type => tooltip_type(&ctx, &def, doc_url),
+                DefKind::Macro => tooltip_macro(&ctx, &def, doc_url),
                 _ => {
                     debug!(
                         "tooltip: ignoring def: \
@@ -882,6 +963,11 @@ pub fn tooltip(
         debug!("tooltip: def is empty");
         Vec::default()
     };
+    if let Some(notes) = notes {
+        // Right after the declaration.
+        let pos = contents.len().min(1);
+        contents.insert(pos, MarkedString::from_markdown(notes));
+    }
     debug!("tooltip: contents.len: {}", contents.len());
     Ok(Tooltip { contents, range: hover_span.range })
 }
@@ -922,6 +1008,29 @@ pub mod test {
             .to_string()
     }
 
+    fn attribute(value: &str) -> Attribute {
+        let row = Row::new_zero_indexed(0);
+        let col = Column::new_zero_indexed(0);
+        Attribute { value: value.to_owned(), span: Span::new(row, row, col, col, "lib.rs") }
+    }
+
+    #[test]
+    fn test_attribute_notes() {
+        assert_eq!(attribute_notes(&[attribute("inline")]), None);
+        assert_eq!(attribute_notes(&[attribute("deprecated")]), Some("**Deprecated**".into()));
+        assert_eq!(
+            attribute_notes(&[attribute(r#"deprecated = "use `bar` instead""#)]),
+            Some("**Deprecated**: use `bar` instead".into())
+        );
+        assert_eq!(
+            attribute_notes(&[
+                attribute("must_use"),
+                attribute(r#"deprecated(since = "0.10.2", note = "use \"bar\", not this")"#),
+            ]),
+            Some("**Deprecated** since 0.10.2: use \\\"bar\\\", not this".into())
+        );
+    }
+
     #[test]
     fn test_noindent() {
         let lines = noindent(
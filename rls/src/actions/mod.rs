@@ -7,7 +7,7 @@ use crate::Span;
 use log::{debug, error, info, trace};
 use rls_analysis::AnalysisHost;
 use rls_span as span;
-use rls_vfs::{FileContents, Vfs};
+use rls_vfs::{FileContents, Snapshot, Vfs};
 use serde_json::{self, json};
 use url::Url;
 use walkdir::WalkDir;
@@ -49,6 +49,8 @@ macro_rules! parse_file_path {
 }
 
 pub mod diagnostics;
+pub mod document_symbol;
+pub mod folding_range;
 pub mod format;
 pub mod hover;
 pub mod notifications;
@@ -56,6 +58,9 @@ pub mod post_build;
 pub mod progress;
 pub mod requests;
 pub mod run;
+pub mod selection_range;
+pub mod semantic_tokens;
+pub mod signature_help;
 pub mod work_pool;
 
 /// Persistent context shared across all requests and notifications.
@@ -146,7 +151,11 @@ pub struct InitActionContext {
     // waiting to mutate the client state.
     pub quiescent: Arc<AtomicBool>,
 
-    prev_changes: Arc<Mutex<HashMap<PathBuf, u64>>>,
+    // The semantic tokens last sent for each file, so that we can respond to
+    // delta requests.
+    semantic_tokens: Arc<Mutex<HashMap<PathBuf, lsp_data::SemanticTokens>>>,
+    // The files from which the current analysis was computed.
+    analysis_snapshot: Arc<Mutex<Option<Snapshot>>>,
 
     config: Arc<Mutex<Config>>,
     jobs: Arc<Mutex<Jobs>>,
@@ -206,7 +215,8 @@ impl InitActionContext {
             active_build_count: Arc::new(AtomicUsize::new(0)),
             shown_cargo_error: Arc::new(AtomicBool::new(false)),
             quiescent: Arc::new(AtomicBool::new(false)),
-            prev_changes: Arc::default(),
+            semantic_tokens: Arc::default(),
+            analysis_snapshot: Arc::default(),
             client_capabilities: Arc::new(client_capabilities),
             client_supports_cmd_run,
             client_use_change_watched: false,
@@ -233,6 +243,30 @@ impl InitActionContext {
         }
     }
 
+    /// Returns an immutable view of the files in the VFS, so that a request
+    /// reading files several times sees the same text each time.
+    pub fn snapshot(&self) -> Snapshot {
+        self.vfs.snapshot()
+    }
+
+    /// Returns the files from which the current analysis was computed, if
+    /// any, so that analysis results can be matched to the text they describe.
+    pub fn analysis_snapshot(&self) -> Option<Snapshot> {
+        self.analysis_snapshot.lock().unwrap().clone()
+    }
+
+    /// Returns the text of a file from `snapshot`, or from disk if the file
+    /// isn't in the snapshot.
+    fn file_text(&self, snapshot: &Snapshot, file_path: &Path) -> Result<String, rls_vfs::Error> {
+        if snapshot.contains(file_path) {
+            return snapshot.load_file(file_path);
+        }
+        match self.vfs.load_file(file_path)? {
+            FileContents::Text(text) => Ok(text),
+            FileContents::Binary(_) => Err(rls_vfs::Error::BadFileKind),
+        }
+    }
+
     pub fn racer_cache(&self) -> racer::FileCache {
         struct RacerVfs(Arc<Vfs>);
         impl racer::FileLoader for RacerVfs {
@@ -309,6 +343,7 @@ impl InitActionContext {
             if let Some(init_config) = init_options.settings.map(|s| s.rust) {
                 config.update(init_config);
             }
+            self.vfs.set_memory_limit(config.vfs_memory_limit());
             config.needs_inference()
         };
 
@@ -324,11 +359,37 @@ impl InitActionContext {
             });
         }
 
+        if !self.client_capabilities.watched_files_support {
+            self.watch_vfs();
+        }
+
         if !init_options.omit_init_build {
             self.build_current_project(BuildPriority::Cargo, out);
         }
     }
 
+    /// Watches the files in the VFS for changes on disk, for clients which
+    /// can't tell us about them. Changed files are included in the next build.
+    #[cfg(target_os = "linux")]
+    fn watch_vfs(&self) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        if let Err(e) = self.vfs.watch(sender) {
+            debug!("Couldn't watch the VFS: {}", e);
+            return;
+        }
+
+        let build_queue = self.build_queue.clone();
+        thread::spawn(move || {
+            for file in receiver {
+                // The file isn't versioned, so the next build clears it.
+                build_queue.mark_file_dirty(file, 0);
+            }
+        });
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn watch_vfs(&self) {}
+
     fn build<O: Output>(&self, project_path: &Path, priority: BuildPriority, out: &O) {
         let (job, token) = ConcurrentJob::new();
         self.add_job(job);
@@ -346,8 +407,10 @@ impl InitActionContext {
                 shown_cargo_error: Arc::clone(&self.shown_cargo_error),
                 active_build_count: Arc::clone(&self.active_build_count),
                 crate_blacklist: config.crate_blacklist.as_ref().clone(),
-                notifier: Box::new(BuildDiagnosticsNotifier::new(out.clone())),
+                notifier: Arc::new(BuildDiagnosticsNotifier::new(out.clone())),
                 blocked_threads: vec![],
+                snapshot: None,
+                analysis_snapshot: Arc::clone(&self.analysis_snapshot),
                 _token: token,
             }
         };
@@ -386,37 +449,6 @@ impl InitActionContext {
         self.active_build_count.load(Ordering::SeqCst) == 0
     }
 
-    /// See docs on VersionOrdering
-    fn check_change_version(&self, file_path: &Path, version_num: u64) -> VersionOrdering {
-        let file_path = file_path.to_owned();
-        let mut prev_changes = self.prev_changes.lock().unwrap();
-
-        if prev_changes.contains_key(&file_path) {
-            let prev_version = prev_changes[&file_path];
-            if version_num <= prev_version {
-                debug!(
-                    "Out of order or duplicate change {:?}, prev: {}, current: {}",
-                    file_path, prev_version, version_num,
-                );
-
-                if version_num == prev_version {
-                    return VersionOrdering::Duplicate;
-                } else {
-                    return VersionOrdering::OutOfOrder;
-                }
-            }
-        }
-
-        prev_changes.insert(file_path, version_num);
-        VersionOrdering::Ok
-    }
-
-    fn reset_change_version(&self, file_path: &Path) {
-        let file_path = file_path.to_owned();
-        let mut prev_changes = self.prev_changes.lock().unwrap();
-        prev_changes.remove(&file_path);
-    }
-
     fn convert_pos_to_span(&self, file_path: PathBuf, pos: Position) -> Span {
         trace!("convert_pos_to_span: {:?} {:?}", file_path, pos);
 
@@ -453,23 +485,6 @@ fn edition_from_manifest<P: AsRef<Path>>(manifest_path: P) -> Option<Edition> {
     }
 }
 
-/// Some notifications come with sequence numbers, we check that these are in
-/// order. However, clients might be buggy about sequence numbers so we do cope
-/// with them being wrong.
-///
-/// This enum defines the state of sequence numbers.
-#[derive(Eq, PartialEq, Debug, Clone, Copy)]
-pub enum VersionOrdering {
-    /// Sequence number is in order (note that we don't currently check that
-    /// sequence numbers are sequential, but we probably should).
-    Ok,
-    /// This indicates the client sent us multiple copies of the same notification
-    /// and some should be ignored.
-    Duplicate,
-    /// Just plain wrong sequence number. No obvious way for us to recover.
-    OutOfOrder,
-}
-
 /// Represents a text cursor between characters, pointing at the next character
 /// in the buffer.
 type Column = span::Column<span::ZeroIndexed>;
@@ -1,6 +1,6 @@
 //! One-way notifications that the RLS receives from the client.
 
-use crate::actions::{FileWatch, InitActionContext, VersionOrdering};
+use crate::actions::{FileWatch, InitActionContext};
 use crate::Span;
 use log::{debug, trace, warn};
 use rls_vfs::{Change, VfsSpan};
@@ -15,7 +15,7 @@ use lsp_types::notification::ShowMessage;
 
 pub use crate::lsp_data::notification::{
     Cancel, DidChangeConfiguration, DidChangeTextDocument, DidChangeWatchedFiles,
-    DidOpenTextDocument, DidSaveTextDocument, Initialized,
+    DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument, Initialized,
 };
 
 use crate::server::{BlockingNotificationAction, Notification, Output};
@@ -55,8 +55,11 @@ impl BlockingNotificationAction for DidOpenTextDocument {
     ) -> Result<(), ()> {
         trace!("on_open: {:?}", params.text_document.uri);
         let file_path = parse_file_path!(&params.text_document.uri, "on_open")?;
-        ctx.reset_change_version(&file_path);
-        ctx.vfs.set_file(&file_path, &params.text_document.text);
+        ctx.vfs.set_file_with_version(
+            &file_path,
+            &params.text_document.text,
+            params.text_document.version,
+        );
         Ok(())
     }
 }
@@ -77,18 +80,6 @@ impl BlockingNotificationAction for DidChangeTextDocument {
         let file_path = parse_file_path!(&params.text_document.uri, "on_change")?;
         let version_num = params.text_document.version.unwrap();
 
-        match ctx.check_change_version(&file_path, version_num) {
-            VersionOrdering::Ok => {}
-            VersionOrdering::Duplicate => return Ok(()),
-            VersionOrdering::OutOfOrder => {
-                out.notify(Notification::<ShowMessage>::new(ShowMessageParams {
-                    typ: MessageType::Warning,
-                    message: format!("Out of order change in {:?}", file_path),
-                }));
-                return Ok(());
-            }
-        }
-
         let changes: Vec<Change> = params
             .content_changes
             .iter()
@@ -102,13 +93,39 @@ impl BlockingNotificationAction for DidChangeTextDocument {
                             i.range_length,
                         ),
                         text: i.text.clone(),
+                        version: Some(version_num),
                     }
                 } else {
-                    Change::AddFile { file: file_path.clone(), text: i.text.clone() }
+                    Change::AddFile {
+                        file: file_path.clone(),
+                        text: i.text.clone(),
+                        version: Some(version_num),
+                    }
                 }
             })
             .collect();
-        ctx.vfs.on_changes(&changes).expect("error committing to VFS");
+        match ctx.vfs.on_changes(&changes) {
+            Ok(()) => {}
+            // Clients may send us multiple copies of the same notification.
+            Err(rls_vfs::Error::UnexpectedVersion(_, file_version, change_version))
+                if file_version == change_version =>
+            {
+                return Ok(());
+            }
+            // No obvious way for us to recover.
+            Err(rls_vfs::Error::UnexpectedVersion(_, file_version, change_version)) => {
+                debug!(
+                    "Out of order change {:?}, prev: {}, current: {}",
+                    file_path, file_version, change_version,
+                );
+                out.notify(Notification::<ShowMessage>::new(ShowMessageParams {
+                    typ: MessageType::Warning,
+                    message: format!("Out of order change in {:?}", file_path),
+                }));
+                return Ok(());
+            }
+            Err(e) => panic!("error committing to VFS: {:?}", e),
+        }
 
         ctx.build_queue.mark_file_dirty(file_path, version_num);
 
@@ -163,6 +180,7 @@ impl BlockingNotificationAction for DidChangeConfiguration {
         };
 
         let unstable_features = new_config.unstable_features;
+        ctx.vfs.set_memory_limit(new_config.vfs_memory_limit());
 
         {
             let mut config = ctx.config.lock().unwrap();
@@ -226,6 +244,22 @@ impl BlockingNotificationAction for DidChangeConfiguration {
     }
 }
 
+impl BlockingNotificationAction for DidCloseTextDocument {
+    fn handle<O: Output>(
+        params: Self::Params,
+        ctx: &mut InitActionContext,
+        _out: O,
+    ) -> Result<(), ()> {
+        let file_path = parse_file_path!(&params.text_document.uri, "on_close")?;
+
+        // The client forgets the semantic tokens of a closed document.
+        ctx.semantic_tokens.lock().unwrap().remove(&file_path);
+        ctx.vfs.file_closed(&file_path);
+
+        Ok(())
+    }
+}
+
 impl BlockingNotificationAction for DidSaveTextDocument {
     fn handle<O: Output>(
         params: DidSaveTextDocumentParams,
@@ -24,6 +24,7 @@ use log::{trace, warn};
 use lsp_types::DiagnosticSeverity;
 use rls_analysis::AnalysisHost;
 use rls_data::Analysis;
+use rls_vfs::Snapshot;
 use url::Url;
 
 pub type BuildResults = HashMap<PathBuf, Vec<(Diagnostic, Vec<Suggestion>)>>;
@@ -39,8 +40,12 @@ pub struct PostBuildHandler {
     pub related_information_support: bool,
     pub shown_cargo_error: Arc<AtomicBool>,
     pub active_build_count: Arc<AtomicUsize>,
-    pub notifier: Box<dyn DiagnosticsNotifier>,
+    pub notifier: Arc<dyn DiagnosticsNotifier>,
     pub blocked_threads: Vec<thread::Thread>,
+    /// The files the build compiled, set by the build queue.
+    pub snapshot: Option<Snapshot>,
+    /// The files the current analysis was computed from.
+    pub analysis_snapshot: Arc<Mutex<Option<Snapshot>>>,
     pub _token: JobToken,
 }
 
@@ -66,6 +71,11 @@ impl PostBuildHandler {
             }
             BuildResult::Squashed => {
                 trace!("build - Squashed");
+                // Only set if there was nothing to build, in which case the
+                // current analysis is up to date.
+                for t in self.blocked_threads {
+                    t.unpark();
+                }
                 self.active_build_count.fetch_sub(1, Ordering::SeqCst);
             }
             BuildResult::Err(cause, cmd) => {
@@ -171,6 +181,18 @@ impl PostBuildHandler {
         self.emit_notifications(&results);
     }
 
+    /// Returns a publisher for the diagnostics which are emitted while the
+    /// build is still running.
+    pub fn early_diagnostics(&self) -> EarlyDiagnostics {
+        EarlyDiagnostics {
+            notifier: Arc::clone(&self.notifier),
+            show_warnings: self.show_warnings,
+            related_information_support: self.related_information_support,
+            seen: HashSet::new(),
+            results: HashMap::new(),
+        }
+    }
+
     fn reload_analysis_from_disk(&self, cwd: &Path) {
         self.analysis
             .reload_with_blacklist(&self.project_path, cwd, &self.crate_blacklist.0[..])
@@ -200,18 +222,54 @@ impl PostBuildHandler {
 
     fn emit_notifications(&self, build_results: &BuildResults) {
         for (path, diagnostics) in build_results {
-            let params = PublishDiagnosticsParams {
-                uri: Url::from_file_path(path).unwrap(),
-                diagnostics: diagnostics
-                    .iter()
-                    .map(|(diag, _)| diag)
-                    .filter(|diag| {
-                        self.show_warnings || diag.severity != Some(DiagnosticSeverity::Warning)
-                    })
-                    .cloned()
-                    .collect(),
-            };
+            let params = publish_params(path, diagnostics, self.show_warnings);
+            self.notifier.notify_publish_diagnostics(params);
+        }
+    }
+}
+
+fn publish_params(
+    path: &Path,
+    diagnostics: &[(Diagnostic, Vec<Suggestion>)],
+    show_warnings: bool,
+) -> PublishDiagnosticsParams {
+    PublishDiagnosticsParams {
+        uri: Url::from_file_path(path).unwrap(),
+        diagnostics: diagnostics
+            .iter()
+            .map(|(diag, _)| diag)
+            .filter(|diag| show_warnings || diag.severity != Some(DiagnosticSeverity::Warning))
+            .cloned()
+            .collect(),
+    }
+}
 
+/// Publishes diagnostics as rustc emits them, so that errors show up before
+/// the build finishes. Once it has, the post-build handler publishes the
+/// complete set of diagnostics, which replaces these.
+pub struct EarlyDiagnostics {
+    notifier: Arc<dyn DiagnosticsNotifier>,
+    show_warnings: bool,
+    related_information_support: bool,
+    // Crates can be compiled more than once, e.g., with and without cfg(test).
+    seen: HashSet<String>,
+    results: BuildResults,
+}
+
+impl EarlyDiagnostics {
+    pub fn publish(&mut self, cwd: &Path, message: &str) {
+        if !self.seen.insert(message.to_owned()) {
+            return;
+        }
+        let diagnostics = match parse_diagnostics(message, cwd, self.related_information_support) {
+            Some(ParsedDiagnostics { diagnostics }) => diagnostics,
+            None => return,
+        };
+
+        for (file_path, diagnostics) in diagnostics {
+            let file_diagnostics = self.results.entry(file_path.clone()).or_insert_with(Vec::new);
+            file_diagnostics.extend(diagnostics);
+            let params = publish_params(&file_path, file_diagnostics, self.show_warnings);
             self.notifier.notify_publish_diagnostics(params);
         }
     }
@@ -351,7 +409,64 @@ impl Job {
             trace!("reloading from memory: {:?}", self.cwd);
             self.handler.reload_analysis_from_memory(&self.cwd, self.analysis);
         }
+        if let Some(snapshot) = self.handler.snapshot.clone() {
+            *self.handler.analysis_snapshot.lock().unwrap() = Some(snapshot);
+        }
 
         self.handler.finalize();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Notifier(Mutex<Vec<PublishDiagnosticsParams>>);
+
+    impl DiagnosticsNotifier for Notifier {
+        fn notify_begin_diagnostics(&self) {}
+        fn notify_publish_diagnostics(&self, params: PublishDiagnosticsParams) {
+            self.0.lock().unwrap().push(params);
+        }
+        fn notify_error_diagnostics(&self, _: String) {}
+        fn notify_end_diagnostics(&self) {}
+    }
+
+    fn read_fixture(name: &str) -> String {
+        let path = Path::new(env!("FIXTURES_DIR")).join("compiler_message").join(name);
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn early_diagnostics_are_published_once() {
+        let notifier = Arc::new(Notifier::default());
+        let mut early = EarlyDiagnostics {
+            notifier: Arc::clone(&notifier) as Arc<dyn DiagnosticsNotifier>,
+            show_warnings: true,
+            related_information_support: false,
+            seen: HashSet::new(),
+            results: HashMap::new(),
+        };
+        let cwd = Path::new("/test");
+        let mismatched_types = read_fixture("mismatched-types.json");
+        let not_mut = read_fixture("not-mut.json");
+
+        early.publish(cwd, &mismatched_types);
+        // E.g., the same crate compiled again with cfg(test).
+        early.publish(cwd, &mismatched_types);
+        let published = notifier.0.lock().unwrap().clone();
+        assert_eq!(published.len(), 1);
+        let first_len = published[0].diagnostics.len();
+        assert!(first_len > 0);
+
+        // Later diagnostics for the same file are published along with the
+        // earlier ones.
+        early.publish(cwd, &not_mut);
+        let published = notifier.0.lock().unwrap().clone();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[1].uri, published[0].uri);
+        assert!(published[1].diagnostics.len() > first_len);
+        assert_eq!(published[1].diagnostics[..first_len], published[0].diagnostics[..]);
+    }
+}
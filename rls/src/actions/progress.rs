@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::server::{Notification, Output};
@@ -16,13 +17,16 @@ pub trait ProgressNotifier: Send {
 pub enum ProgressUpdate {
     Message(String),
     Percentage(f64),
+    /// A diagnostic in rustc's JSON format, emitted before the compilation
+    /// finished, along with the directory rustc ran in.
+    Diagnostic(PathBuf, String),
 }
 
 /// Trait for communication of diagnostics (i.e., build results) back to the rest of
 /// the RLS (and on to the client).
 // This trait only really exists to work around the object safety rules (Output
 // is not object-safe).
-pub trait DiagnosticsNotifier: Send {
+pub trait DiagnosticsNotifier: Send + Sync {
     fn notify_begin_diagnostics(&self);
     fn notify_publish_diagnostics(&self, _: PublishDiagnosticsParams);
     fn notify_error_diagnostics(&self, msg: String);
@@ -70,6 +74,8 @@ impl<O: Output> ProgressNotifier for BuildProgressNotifier<O> {
         match update {
             ProgressUpdate::Message(s) => params.message = Some(s),
             ProgressUpdate::Percentage(p) => params.percentage = Some(p),
+            // Published by the post-build handler instead.
+            ProgressUpdate::Diagnostic(..) => return,
         }
         self.out.notify(Notification::<Progress>::new(params));
     }
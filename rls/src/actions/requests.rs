@@ -15,8 +15,13 @@ use rustfmt_nightly::{Edition as RustfmtEdition, FileLines, FileName, Range as R
 use serde_derive::{Deserialize, Serialize};
 use url::Url;
 
+use crate::actions::document_symbol;
+use crate::actions::folding_range;
 use crate::actions::hover;
 use crate::actions::run::collect_run_actions;
+use crate::actions::selection_range;
+use crate::actions::semantic_tokens;
+use crate::actions::signature_help;
 use crate::actions::InitActionContext;
 use crate::build::Edition;
 use crate::lsp_data;
@@ -24,13 +29,21 @@ use crate::lsp_data::request::ApplyWorkspaceEdit;
 pub use crate::lsp_data::request::{
     CodeActionRequest as CodeAction, CodeLensRequest, Completion,
     DocumentHighlightRequest as DocumentHighlight, DocumentSymbolRequest as Symbols,
-    ExecuteCommand, Formatting, GotoDefinition as Definition, GotoImplementation as Implementation,
+    ExecuteCommand, FoldingRangeRequest as FoldingRange, Formatting, GotoDefinition as Definition,
+    GotoImplementation as Implementation, GotoTypeDefinition as TypeDefinition,
     HoverRequest as Hover, RangeFormatting, References, Rename,
-    ResolveCompletionItem as ResolveCompletion, WorkspaceSymbol,
+    ResolveCompletionItem as ResolveCompletion, SelectionRangeRequest as SelectionRange,
+    SignatureHelpRequest as SignatureHelp, WorkspaceSymbol,
 };
 use crate::lsp_data::*;
+pub use crate::lsp_data::{
+    CallHierarchyIncomingCalls, CallHierarchyOutgoingCalls, CallHierarchyPrepare,
+    SemanticTokensFull, SemanticTokensFullDelta, SemanticTokensRange, TypeHierarchyPrepare,
+    TypeHierarchySubtypes, TypeHierarchySupertypes,
+};
 use crate::server;
 use crate::server::{Ack, Output, Request, RequestAction, ResponseError, ResponseWithMessage};
+use crate::Span;
 
 /// The result of a deglob action for a single wildcard import.
 ///
@@ -65,6 +78,7 @@ impl RequestAction for WorkspaceSymbol {
             // for the same location, fix that up.
             .unique_by(|d| (d.span.clone(), d.name.clone()))
             .map(|d| SymbolInformation {
+                deprecated: symbol_deprecated(&d.attributes),
                 name: d.name,
                 kind: source_kind_from_def_kind(d.kind),
                 location: ls_util::rls_to_location(&d.span),
@@ -72,47 +86,138 @@ impl RequestAction for WorkspaceSymbol {
                     .parent
                     .and_then(|id| analysis.get_def(id).ok())
                     .map(|parent| parent.name),
-                deprecated: None,
             })
             .collect())
     }
 }
 
 impl RequestAction for Symbols {
-    type Response = Vec<SymbolInformation>;
+    type Response = DocumentSymbolResponse;
 
     fn fallback_response() -> Result<Self::Response, ResponseError> {
-        Ok(vec![])
+        Ok(DocumentSymbolResponse::Flat(vec![]))
     }
 
     fn handle(
         ctx: InitActionContext,
         params: Self::Params,
     ) -> Result<Self::Response, ResponseError> {
-        let analysis = ctx.analysis;
+        let analysis = &ctx.analysis;
 
         let file_path = parse_file_path!(&params.text_document.uri, "symbols")?;
 
         let symbols = analysis.symbols(&file_path).unwrap_or_else(|_| vec![]);
-
-        Ok(symbols
+        let symbols = symbols
             .into_iter()
             .filter(|s| !s.name.is_empty()) // HACK: VS Code chokes on empty names
             .filter(|s| {
                 let range = ls_util::rls_to_range(s.span.range);
                 range.start != range.end
-            })
-            .map(|s| SymbolInformation {
-                name: s.name,
-                kind: source_kind_from_def_kind(s.kind),
-                location: ls_util::rls_to_location(&s.span),
-                container_name: s
-                    .parent
-                    .and_then(|id| analysis.get_def(id).ok())
-                    .map(|parent| parent.name),
-                deprecated: None,
-            })
-            .collect())
+            });
+
+        if ctx.client_capabilities.hierarchical_document_symbol_support {
+            let symbols = symbols.collect();
+            let symbols = document_symbol::document_symbols(&ctx, &file_path, symbols);
+            return Ok(DocumentSymbolResponse::Nested(symbols));
+        }
+
+        Ok(DocumentSymbolResponse::Flat(
+            symbols
+                .map(|s| SymbolInformation {
+                    deprecated: analysis
+                        .attributes(s.id)
+                        .ok()
+                        .and_then(|attributes| symbol_deprecated(&attributes)),
+                    name: s.name,
+                    kind: source_kind_from_def_kind(s.kind),
+                    location: ls_util::rls_to_location(&s.span),
+                    container_name: s
+                        .parent
+                        .and_then(|id| analysis.get_def(id).ok())
+                        .map(|parent| parent.name),
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl RequestAction for FoldingRange {
+    type Response = Vec<lsp_data::FoldingRange>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "folding_range")?;
+        Ok(folding_range::folding_ranges(&ctx, &file_path))
+    }
+}
+
+impl RequestAction for SelectionRange {
+    type Response = Vec<lsp_data::SelectionRange>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "selection_range")?;
+        Ok(selection_range::selection_ranges(&ctx, &file_path, &params.positions))
+    }
+}
+
+impl RequestAction for SemanticTokensFull {
+    type Response = Option<lsp_data::SemanticTokens>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "semantic_tokens")?;
+        Ok(Some(semantic_tokens::full(&ctx, &file_path)))
+    }
+}
+
+impl RequestAction for SemanticTokensFullDelta {
+    type Response = Option<lsp_data::SemanticTokensFullDeltaResult>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "semantic_tokens")?;
+        Ok(Some(semantic_tokens::full_delta(&ctx, &file_path, &params.previous_result_id)))
+    }
+}
+
+impl RequestAction for SemanticTokensRange {
+    type Response = Option<lsp_data::SemanticTokens>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "semantic_tokens")?;
+        Ok(Some(semantic_tokens::range(&ctx, &file_path, params.range)))
     }
 }
 
@@ -136,6 +241,21 @@ impl RequestAction for Hover {
     }
 }
 
+impl RequestAction for SignatureHelp {
+    type Response = Option<lsp_data::SignatureHelp>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        signature_help::signature_help(&ctx, &params)
+    }
+}
+
 impl RequestAction for Implementation {
     type Response = Vec<Location>;
 
@@ -210,6 +330,355 @@ impl RequestAction for Definition {
     }
 }
 
+impl RequestAction for TypeDefinition {
+    type Response = Vec<Location>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        // Save-analysis thread.
+        let file_path = parse_file_path!(&params.text_document.uri, "goto_type_def")?;
+        let span = ctx.convert_pos_to_span(file_path.clone(), params.position);
+
+        if let Ok(def) = ctx.analysis.type_def(&span) {
+            let result = vec![ls_util::rls_to_location(&def.span)];
+            trace!("goto_type_def (compiler): {:?}", result);
+            Ok(result)
+        } else {
+            let racer_enabled = {
+                let config = ctx.config.lock().unwrap();
+                config.racer_completion
+            };
+            if racer_enabled {
+                let cache = ctx.racer_cache();
+                let session = ctx.racer_session(&cache);
+                let location = pos_to_racer_location(params.position);
+
+                let r = racer::find_definition(&file_path, location, &session)
+                    .and_then(|rm| racer_type_match(rm, &file_path, &session))
+                    .and_then(|rm| location_from_racer_match(&rm))
+                    .map(|l| vec![l])
+                    .unwrap_or_default();
+
+                trace!("goto_type_def (Racer): {:?}", r);
+                Ok(r)
+            } else {
+                Self::fallback_response()
+            }
+        }
+    }
+}
+
+impl RequestAction for CallHierarchyPrepare {
+    type Response = Vec<CallHierarchyItem>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "call_hierarchy")?;
+        let span = ctx.convert_pos_to_span(file_path, params.position);
+
+        let item = ctx.analysis.id(&span).ok().and_then(|id| call_hierarchy_item(&ctx, id));
+        Ok(item.into_iter().collect())
+    }
+}
+
+impl RequestAction for CallHierarchyIncomingCalls {
+    type Response = Vec<CallHierarchyIncomingCall>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let id = call_hierarchy_item_id(&ctx, &params.item)?;
+        let callers = ctx.analysis.callers(id).unwrap_or_else(|_| vec![]);
+        let snapshot = ctx.snapshot();
+
+        Ok(callers
+            .into_iter()
+            .filter_map(|(caller, spans)| {
+                // Analysis attributes refs to the function before them, so
+                // check that they're calls within its body.
+                let def = ctx.analysis.get_def(caller).ok()?;
+                let text = ctx.file_text(&snapshot, &def.span.file).ok()?;
+                let spans = calls_in_body(&text, &def.span, spans);
+                if spans.is_empty() {
+                    return None;
+                }
+                Some(CallHierarchyIncomingCall {
+                    from: call_hierarchy_item(&ctx, caller)?,
+                    from_ranges: spans.iter().map(|s| ls_util::rls_to_range(s.range)).collect(),
+                })
+            })
+            .collect())
+    }
+}
+
+impl RequestAction for CallHierarchyOutgoingCalls {
+    type Response = Vec<CallHierarchyOutgoingCall>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let id = call_hierarchy_item_id(&ctx, &params.item)?;
+        let callees = ctx.analysis.get_def(id).ok().map_or(vec![], |def| callees(&ctx, &def));
+
+        Ok(callees
+            .into_iter()
+            .filter_map(|(callee, spans)| {
+                Some(CallHierarchyOutgoingCall {
+                    to: call_hierarchy_item(&ctx, callee)?,
+                    from_ranges: spans.iter().map(|s| ls_util::rls_to_range(s.range)).collect(),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Creates a call hierarchy item for the function or method `id`.
+fn call_hierarchy_item(ctx: &InitActionContext, id: rls_analysis::Id) -> Option<CallHierarchyItem> {
+    let def = ctx.analysis.get_def(id).ok()?;
+    match def.kind {
+        data::DefKind::Function | data::DefKind::Method | data::DefKind::ForeignFunction => {}
+        _ => return None,
+    }
+
+    let location = ls_util::rls_to_location(&def.span);
+    Some(CallHierarchyItem {
+        name: def.name,
+        kind: source_kind_from_def_kind(def.kind),
+        detail: Some(def.qualname),
+        uri: location.uri,
+        // Save-analysis doesn't record the span of the whole item.
+        range: location.range,
+        selection_range: location.range,
+    })
+}
+
+/// Returns the functions called from the body of the function `def`, along
+/// with the spans of the calls.
+fn callees(ctx: &InitActionContext, def: &rls_analysis::Def) -> Vec<(rls_analysis::Id, Vec<Span>)> {
+    let text = match ctx.file_text(&ctx.snapshot(), &def.span.file) {
+        Ok(text) => text,
+        Err(e) => {
+            debug!("callees: couldn't load {:?}: {:?}", def.span.file, e);
+            return vec![];
+        }
+    };
+    let body = match fn_body(&text, &def.span) {
+        Some(body) => body,
+        None => return vec![],
+    };
+
+    let callees = ctx.analysis.callees(&body).unwrap_or_else(|_| vec![]);
+    callees
+        .into_iter()
+        .filter_map(|(callee, spans)| {
+            let spans = calls_in_body(&text, &def.span, spans);
+            if spans.is_empty() {
+                None
+            } else {
+                Some((callee, spans))
+            }
+        })
+        .collect()
+}
+
+/// Returns the body of the function whose name is at `name` in `text`, from
+/// the end of its name to its closing brace.
+fn fn_body(text: &str, name: &Span) -> Option<Span> {
+    let range = name.range;
+    let end = folding_range::scan(text).item_end((range.row_end.0, range.col_end.0))?;
+    Some(Span::new(
+        range.row_end,
+        span::Row::new_zero_indexed(end.0),
+        range.col_end,
+        span::Column::new_zero_indexed(end.1 + 1),
+        name.file.clone(),
+    ))
+}
+
+/// Returns the `refs` which are calls within the body of the function whose
+/// name is at `name` in `text`.
+fn calls_in_body(text: &str, name: &Span, refs: Vec<Span>) -> Vec<Span> {
+    let body = match fn_body(text, name) {
+        Some(body) => body,
+        None => return vec![],
+    };
+    let lines: Vec<&str> = text.lines().collect();
+    let (start, end) = (body.range.start(), body.range.end());
+
+    refs.into_iter()
+        .filter(|r| r.file == body.file && r.range.start() >= start && r.range.end() <= end)
+        .filter(|r| is_call(&lines, r))
+        .collect()
+}
+
+/// Returns whether the ref at `span` is called, i.e., is followed by the
+/// arguments of a call (possibly after generic arguments), rather than being
+/// used as a value or a path.
+fn is_call(lines: &[&str], span: &Span) -> bool {
+    let range = span.range;
+    let line = match lines.get(range.row_end.0 as usize) {
+        Some(line) => line,
+        None => return false,
+    };
+    let mut rest = line.chars().skip(range.col_end.0 as usize).skip_while(|c| c.is_whitespace());
+    match rest.next() {
+        Some('(') => true,
+        // A turbofish, e.g., `foo::<T>()`.
+        Some(':') => {
+            let rest: String = rest.collect();
+            let rest = match rest.strip_prefix(":<") {
+                Some(rest) => rest,
+                None => return false,
+            };
+            let mut depth = 1;
+            let close = rest.find(|c| {
+                match c {
+                    '<' => depth += 1,
+                    '>' => depth -= 1,
+                    _ => {}
+                }
+                depth == 0
+            });
+            close.map_or(false, |close| rest[close + 1..].trim_start().starts_with('('))
+        }
+        _ => false,
+    }
+}
+
+/// Finds the def of a call hierarchy item previously returned to the client.
+fn call_hierarchy_item_id(
+    ctx: &InitActionContext,
+    item: &CallHierarchyItem,
+) -> Result<rls_analysis::Id, ResponseError> {
+    let file_path = parse_file_path!(&item.uri, "call_hierarchy")?;
+    let span = ctx.convert_pos_to_span(file_path, item.selection_range.start);
+    ctx.analysis.id(&span).map_err(|_| ResponseError::Empty)
+}
+
+impl RequestAction for TypeHierarchyPrepare {
+    type Response = Vec<TypeHierarchyItem>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "type_hierarchy")?;
+        let span = ctx.convert_pos_to_span(file_path, params.position);
+
+        let item = ctx.analysis.id(&span).ok().and_then(|id| type_hierarchy_item(&ctx, id));
+        Ok(item.into_iter().collect())
+    }
+}
+
+impl RequestAction for TypeHierarchySupertypes {
+    type Response = Vec<TypeHierarchyItem>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let id = type_hierarchy_item_id(&ctx, &params.item)?;
+        let analysis = &ctx.analysis;
+        let ids = match analysis.get_def(id).map(|def| def.kind) {
+            Ok(data::DefKind::Trait) => analysis.supertraits(id),
+            _ => analysis.implemented_traits(id),
+        };
+
+        let ids = ids.unwrap_or_else(|_| vec![]);
+        Ok(ids.into_iter().filter_map(|id| type_hierarchy_item(&ctx, id)).collect())
+    }
+}
+
+impl RequestAction for TypeHierarchySubtypes {
+    type Response = Vec<TypeHierarchyItem>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let id = type_hierarchy_item_id(&ctx, &params.item)?;
+        let analysis = &ctx.analysis;
+
+        let subtraits = analysis.subtraits(id).unwrap_or_else(|_| vec![]);
+        let implementors = analysis.implementors(id).unwrap_or_else(|_| vec![]);
+        Ok(subtraits
+            .into_iter()
+            .chain(implementors)
+            .filter_map(|id| type_hierarchy_item(&ctx, id))
+            .collect())
+    }
+}
+
+/// Creates a type hierarchy item for the type or trait `id`.
+fn type_hierarchy_item(ctx: &InitActionContext, id: rls_analysis::Id) -> Option<TypeHierarchyItem> {
+    let def = ctx.analysis.get_def(id).ok()?;
+    match def.kind {
+        data::DefKind::Trait
+        | data::DefKind::Struct
+        | data::DefKind::Enum
+        | data::DefKind::Union
+        | data::DefKind::Tuple
+        | data::DefKind::Type
+        | data::DefKind::ExternType => {}
+        _ => return None,
+    }
+
+    let location = ls_util::rls_to_location(&def.span);
+    Some(TypeHierarchyItem {
+        name: def.name,
+        kind: source_kind_from_def_kind(def.kind),
+        detail: Some(def.qualname),
+        uri: location.uri,
+        // Save-analysis doesn't record the span of the whole item.
+        range: location.range,
+        selection_range: location.range,
+    })
+}
+
+/// Finds the def of a type hierarchy item previously returned to the client.
+fn type_hierarchy_item_id(
+    ctx: &InitActionContext,
+    item: &TypeHierarchyItem,
+) -> Result<rls_analysis::Id, ResponseError> {
+    let file_path = parse_file_path!(&item.uri, "type_hierarchy")?;
+    let span = ctx.convert_pos_to_span(file_path, item.selection_range.start);
+    ctx.analysis.id(&span).map_err(|_| ResponseError::Empty)
+}
+
 impl RequestAction for References {
     type Response = Vec<Location>;
 
@@ -225,11 +694,21 @@ impl RequestAction for References {
             parse_file_path!(&params.text_document_position.text_document.uri, "find_all_refs")?;
         let span = ctx.convert_pos_to_span(file_path, params.text_document_position.position);
 
-        let result =
+        // Macros are used across crates, so look for their invocations in all
+        // crates rather than only in the crate of the def.
+        let invocations = ctx.analysis.find_macro_invocations(&span).unwrap_or_default();
+        let result = if !invocations.is_empty() {
+            let decl = match ctx.analysis.goto_def(&span) {
+                Ok(decl) if params.context.include_declaration => Some(decl),
+                _ => None,
+            };
+            decl.into_iter().chain(invocations).collect()
+        } else {
             match ctx.analysis.find_all_refs(&span, params.context.include_declaration, false) {
                 Ok(t) => t,
                 _ => vec![],
-            };
+            }
+        };
 
         Ok(result.iter().map(|item| ls_util::rls_to_location(item)).collect())
     }
@@ -783,6 +1262,65 @@ fn location_from_racer_match(a_match: &racer::Match) -> Option<Location> {
     })
 }
 
+/// Resolves a Racer match to the match of its type. Racer doesn't infer types,
+/// so this only works for types themselves and for bindings, arguments and
+/// fields which are explicitly annotated with a type.
+fn racer_type_match(
+    a_match: racer::Match,
+    file_path: &Path,
+    session: &racer::Session<'_>,
+) -> Option<racer::Match> {
+    if is_racer_type(&a_match.mtype) {
+        return Some(a_match);
+    }
+
+    let ty = racer_type_annotation(&a_match.contextstr)?;
+    let name = ty.rsplit("::").next()?;
+    racer::complete_fully_qualified_name(ty, file_path, session)
+        .find(|m| m.matchstr == name && is_racer_type(&m.mtype))
+}
+
+fn is_racer_type(mtype: &racer::MatchType) -> bool {
+    match mtype {
+        racer::MatchType::Struct(_)
+        | racer::MatchType::Union(_)
+        | racer::MatchType::Enum(_)
+        | racer::MatchType::Type
+        | racer::MatchType::Trait => true,
+        _ => false,
+    }
+}
+
+/// Extracts the path of the annotated type from a binding, argument or field,
+/// e.g., `std::vec::Vec` from `let x: &mut std::vec::Vec<u8> = ...`.
+fn racer_type_annotation(context: &str) -> Option<&str> {
+    // The first `:` which isn't part of a path separator.
+    let bytes = context.as_bytes();
+    let colon = (0..bytes.len()).find(|&i| {
+        bytes[i] == b':' && bytes.get(i + 1) != Some(&b':') && (i == 0 || bytes[i - 1] != b':')
+    })?;
+
+    let mut ty = context[colon + 1..].trim_start();
+    loop {
+        ty = if ty.starts_with('&') {
+            ty[1..].trim_start()
+        } else if ty.starts_with("mut ") {
+            ty[4..].trim_start()
+        } else if ty.starts_with('\'') {
+            ty.find(char::is_whitespace).map(|i| ty[i..].trim_start())?
+        } else {
+            break;
+        };
+    }
+
+    let end = ty.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':')).unwrap_or(ty.len());
+    if end == 0 {
+        None
+    } else {
+        Some(&ty[..end])
+    }
+}
+
 impl RequestAction for CodeLensRequest {
     type Response = Vec<CodeLens>;
 
@@ -828,4 +1366,54 @@ mod test {
             "arc, bow, curve, Arc, Bow, Curve, ARC",
         );
     }
+
+    #[test]
+    fn test_racer_type_annotation() {
+        assert_eq!(racer_type_annotation("let x = Foo::new();"), None);
+        assert_eq!(racer_type_annotation("let x: Foo = Foo::new();"), Some("Foo"));
+        assert_eq!(racer_type_annotation("x: &mut foo::Bar<u8>"), Some("foo::Bar"));
+        assert_eq!(racer_type_annotation("f: u32,"), Some("u32"));
+        assert_eq!(racer_type_annotation("x: &'a Foo"), Some("Foo"));
+        assert_eq!(racer_type_annotation("t: (u8, u8)"), None);
+    }
+
+    #[test]
+    fn test_calls_in_body() {
+        let text = "fn foo() {}\n\nfn bar() {\n    foo();\n    let f = foo;\n}\n\n\
+                    static F: fn() = foo;\n\nmod tests {\n    use super::foo;\n}\n";
+        let span = |row, col_start, col_end| {
+            Span::new(
+                span::Row::new_zero_indexed(row),
+                span::Row::new_zero_indexed(row),
+                span::Column::new_zero_indexed(col_start),
+                span::Column::new_zero_indexed(col_end),
+                "main.rs",
+            )
+        };
+        let bar = span(2, 3, 6);
+        // The refs to `foo` which are after `bar`, as analysis attributes them.
+        let refs = vec![span(3, 4, 7), span(4, 12, 15), span(7, 17, 20), span(10, 15, 18)];
+        assert_eq!(calls_in_body(text, &bar, refs), vec![span(3, 4, 7)]);
+    }
+
+    #[test]
+    fn test_is_call() {
+        let lines =
+            ["    foo(1);", "    let f = foo;", "    foo::<Vec<u8>> ();", "    foo::bar();"];
+        let span = |row, col_start, col_end| {
+            Span::new(
+                span::Row::new_zero_indexed(row),
+                span::Row::new_zero_indexed(row),
+                span::Column::new_zero_indexed(col_start),
+                span::Column::new_zero_indexed(col_end),
+                "main.rs",
+            )
+        };
+        assert!(is_call(&lines, &span(0, 4, 7)));
+        assert!(!is_call(&lines, &span(1, 12, 15)));
+        assert!(is_call(&lines, &span(2, 4, 7)));
+        // A path through a module.
+        assert!(!is_call(&lines, &span(3, 4, 7)));
+        assert!(is_call(&lines, &span(3, 9, 12)));
+    }
 }
@@ -0,0 +1,212 @@
+//! Selection ranges, which let clients expand the selection from an identifier
+//! to the expressions and items enclosing it.
+
+use std::path::Path;
+
+use log::debug;
+use rls_analysis::Id;
+use rls_span::{Column, Row, Span};
+
+use crate::actions::folding_range::{self, Scan, TextPosition};
+use crate::actions::InitActionContext;
+use crate::lsp_data::{Position, Range, SelectionRange};
+
+/// The text of an item, from the start of the row of its name to the end of
+/// its body.
+#[derive(Debug)]
+struct Item {
+    id: Id,
+    start: TextPosition,
+    end: TextPosition,
+}
+
+/// Returns a selection range for each of `positions`, in the same order.
+pub fn selection_ranges(
+    ctx: &InitActionContext,
+    file_path: &Path,
+    positions: &[Position],
+) -> Vec<SelectionRange> {
+    let text = match ctx.file_text(&ctx.snapshot(), file_path) {
+        Ok(text) => text,
+        Err(e) => {
+            debug!("selection_ranges: couldn't load {:?}: {:?}", file_path, e);
+            return vec![];
+        }
+    };
+
+    let lines: Vec<&str> = text.lines().collect();
+    let scan = folding_range::scan(&text);
+    let items = items(ctx, file_path, &scan, &lines);
+    let file_end =
+        lines.last().map_or((0, 0), |line| (lines.len() as u32 - 1, line.chars().count() as u32));
+
+    positions
+        .iter()
+        .map(|pos| {
+            let pos = (pos.line as u32, pos.character as u32);
+
+            let mut ranges = vec![];
+            ranges.extend(ident_range(ctx, file_path, &lines, pos));
+            for bracket in scan.enclosing_brackets(pos) {
+                let (open, close) = (bracket.open, bracket.close);
+                // First the contents, then the contents with the brackets.
+                ranges.push(((open.0, open.1 + 1), close));
+                ranges.push((open, (close.0, close.1 + 1)));
+            }
+            ranges.extend(item_ranges(ctx, &items, pos));
+            // The whole file, i.e., the module.
+            ranges.push(((0, 0), file_end));
+
+            nest_ranges(pos, ranges)
+        })
+        .collect()
+}
+
+/// Returns the identifier at `pos`, falling back to the word at `pos` if the
+/// analysis doesn't know about any identifier there.
+fn ident_range(
+    ctx: &InitActionContext,
+    file_path: &Path,
+    lines: &[&str],
+    pos: TextPosition,
+) -> Option<(TextPosition, TextPosition)> {
+    let span = Span::new(
+        Row::new_zero_indexed(pos.0),
+        Row::new_zero_indexed(pos.0),
+        Column::new_zero_indexed(pos.1),
+        Column::new_zero_indexed(pos.1 + 1),
+        file_path,
+    );
+    let ident = ctx.analysis.idents(&span).ok().and_then(|idents| {
+        idents
+            .into_iter()
+            .map(|ident| ident.span.range)
+            .find(|range| range.col_start.0 <= pos.1 && pos.1 <= range.col_end.0)
+    });
+    if let Some(range) = ident {
+        return Some(((range.row_start.0, range.col_start.0), (range.row_end.0, range.col_end.0)));
+    }
+
+    let line = lines.get(pos.0 as usize)?;
+    let (start, end) = super::find_word_at_pos(line, Column::new_zero_indexed(pos.1));
+    Some(((pos.0, start.0), (pos.0, end.0)))
+}
+
+/// Finds the text of the items in the file, as far as it matches the (possibly
+/// stale) analysis.
+fn items(ctx: &InitActionContext, file_path: &Path, scan: &Scan, lines: &[&str]) -> Vec<Item> {
+    let symbols = ctx.analysis.symbols(file_path).unwrap_or_else(|_| vec![]);
+    symbols
+        .into_iter()
+        .filter_map(|symbol| {
+            let (start, end) = scan.item_extent(lines, &symbol)?;
+            Some(Item { id: symbol.id, start, end })
+        })
+        .collect()
+}
+
+/// Returns the ranges of the items enclosing `pos`, including the items which
+/// are parents of the innermost one.
+fn item_ranges(
+    ctx: &InitActionContext,
+    items: &[Item],
+    pos: TextPosition,
+) -> Vec<(TextPosition, TextPosition)> {
+    let enclosing: Vec<&Item> =
+        items.iter().filter(|item| item.start <= pos && pos <= item.end).collect();
+    let mut result: Vec<_> = enclosing.iter().map(|item| (item.start, item.end)).collect();
+
+    let innermost = enclosing.iter().max_by_key(|item| item.start);
+    let mut parent =
+        innermost.and_then(|item| ctx.analysis.get_def(item.id).ok()).and_then(|def| def.parent);
+    while let Some(id) = parent {
+        result.extend(items.iter().filter(|item| item.id == id).map(|item| (item.start, item.end)));
+        parent = ctx.analysis.get_def(id).ok().and_then(|def| def.parent);
+    }
+
+    result
+}
+
+/// Nests the given ranges, each enclosing `pos`, into a selection range,
+/// dropping any ranges which don't enclose the previous ones.
+fn nest_ranges(pos: TextPosition, mut ranges: Vec<(TextPosition, TextPosition)>) -> SelectionRange {
+    // Innermost ranges first.
+    ranges.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    let mut nested: Vec<(TextPosition, TextPosition)> = vec![];
+    for range in ranges {
+        let encloses = match nested.last() {
+            Some(last) => range != *last && range.0 <= last.0 && last.1 <= range.1,
+            None => range.0 <= pos && pos <= range.1,
+        };
+        if encloses {
+            nested.push(range);
+        }
+    }
+    if nested.is_empty() {
+        nested.push((pos, pos));
+    }
+
+    let to_range = |(start, end): (TextPosition, TextPosition)| Range {
+        start: Position::new(u64::from(start.0), u64::from(start.1)),
+        end: Position::new(u64::from(end.0), u64::from(end.1)),
+    };
+    let mut ranges = nested.into_iter().rev();
+    let outermost = SelectionRange { range: to_range(ranges.next().unwrap()), parent: None };
+    ranges.fold(outermost, |parent, range| SelectionRange {
+        range: to_range(range),
+        parent: Some(Box::new(parent)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ranges(selection: &SelectionRange) -> Vec<((u64, u64), (u64, u64))> {
+        let mut result = vec![];
+        let mut next = Some(selection);
+        while let Some(selection) = next {
+            let range = selection.range;
+            result.push((
+                (range.start.line, range.start.character),
+                (range.end.line, range.end.character),
+            ));
+            next = selection.parent.as_deref();
+        }
+        result
+    }
+
+    #[test]
+    fn nests_brackets() {
+        let text = "fn foo() {\n    bar(baz[0]);\n}\n";
+        let scan = folding_range::scan(text);
+        let pos = (1, 12);
+
+        let mut input = vec![((1, 12), (1, 13))];
+        for bracket in scan.enclosing_brackets(pos) {
+            input.push(((bracket.open.0, bracket.open.1 + 1), bracket.close));
+            input.push((bracket.open, (bracket.close.0, bracket.close.1 + 1)));
+        }
+        input.push(((0, 0), (2, 1)));
+
+        assert_eq!(
+            ranges(&nest_ranges(pos, input)),
+            vec![
+                ((1, 12), (1, 13)),
+                ((1, 11), (1, 14)),
+                ((1, 8), (1, 14)),
+                ((1, 7), (1, 15)),
+                ((0, 10), (2, 0)),
+                ((0, 9), (2, 1)),
+                ((0, 0), (2, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_overlapping_ranges() {
+        let input = vec![((0, 4), (0, 8)), ((0, 6), (0, 12)), ((0, 0), (0, 20))];
+        assert_eq!(ranges(&nest_ranges((0, 7), input)), vec![((0, 6), (0, 12)), ((0, 0), (0, 20))]);
+    }
+}
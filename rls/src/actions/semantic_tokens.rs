@@ -0,0 +1,339 @@
+//! Semantic tokens, which classify the identifiers in a file using the ident
+//! index from the analysis so that clients can highlight them semantically.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::debug;
+use rls_analysis::{DefKind, Id, IdentKind};
+
+use crate::actions::InitActionContext;
+use crate::lsp_data::{
+    Range, SemanticTokens, SemanticTokensDelta, SemanticTokensEdit, SemanticTokensFullDeltaResult,
+    SemanticTokensLegend,
+};
+
+/// The kinds of tokens we report, in the order of the legend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenType {
+    Struct,
+    Enum,
+    Trait,
+    Function,
+    Method,
+    Macro,
+    Local,
+    Field,
+    Static,
+    Const,
+}
+
+const TOKEN_TYPES: &[TokenType] = &[
+    TokenType::Struct,
+    TokenType::Enum,
+    TokenType::Trait,
+    TokenType::Function,
+    TokenType::Method,
+    TokenType::Macro,
+    TokenType::Local,
+    TokenType::Field,
+    TokenType::Static,
+    TokenType::Const,
+];
+
+impl TokenType {
+    fn from_def_kind(kind: DefKind) -> Option<TokenType> {
+        match kind {
+            DefKind::Struct | DefKind::Tuple | DefKind::Union => Some(TokenType::Struct),
+            DefKind::Enum => Some(TokenType::Enum),
+            DefKind::Trait => Some(TokenType::Trait),
+            DefKind::Function | DefKind::ForeignFunction => Some(TokenType::Function),
+            DefKind::Method => Some(TokenType::Method),
+            DefKind::Macro => Some(TokenType::Macro),
+            DefKind::Local => Some(TokenType::Local),
+            DefKind::Field => Some(TokenType::Field),
+            DefKind::Static | DefKind::ForeignStatic => Some(TokenType::Static),
+            DefKind::Const => Some(TokenType::Const),
+            DefKind::TupleVariant
+            | DefKind::StructVariant
+            | DefKind::Mod
+            | DefKind::Type
+            | DefKind::ExternType => None,
+        }
+    }
+
+    /// The name of the token type in the legend. Where the LSP has a
+    /// predefined type for the kind, we use that.
+    fn name(self) -> &'static str {
+        match self {
+            TokenType::Struct => "struct",
+            TokenType::Enum => "enum",
+            TokenType::Trait => "interface",
+            TokenType::Function => "function",
+            TokenType::Method => "method",
+            TokenType::Macro => "macro",
+            TokenType::Local => "variable",
+            TokenType::Field => "property",
+            TokenType::Static => "static",
+            TokenType::Const => "constant",
+        }
+    }
+
+    fn index(self) -> u32 {
+        TOKEN_TYPES.iter().position(|ty| *ty == self).unwrap() as u32
+    }
+}
+
+/// Token modifiers, as bits in the order of the legend.
+const DECLARATION: u32 = 1;
+const MUTABLE: u32 = 1 << 1;
+/// The token refers to an item from the standard distribution, e.g., `std`.
+const DEFAULT_LIBRARY: u32 = 1 << 2;
+
+const TOKEN_MODIFIERS: &[&str] = &["declaration", "mutable", "defaultLibrary"];
+
+pub fn legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: TOKEN_TYPES.iter().map(|ty| ty.name().to_owned()).collect(),
+        token_modifiers: TOKEN_MODIFIERS.iter().map(|&m| m.to_owned()).collect(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Token {
+    row: u32,
+    col: u32,
+    len: u32,
+    ty: TokenType,
+    modifiers: u32,
+}
+
+/// Returns the tokens for the whole file, remembering them so that later
+/// requests can be answered with a delta.
+pub fn full(ctx: &InitActionContext, file_path: &Path) -> SemanticTokens {
+    let data = encode(&tokens(ctx, file_path));
+
+    let mut cache = ctx.semantic_tokens.lock().unwrap();
+    let result_id = cache
+        .get(file_path)
+        .and_then(|prev| prev.result_id.as_ref()?.parse::<u64>().ok())
+        .map_or(0, |id| id + 1);
+    let result = SemanticTokens { result_id: Some(result_id.to_string()), data };
+    cache.insert(file_path.to_owned(), result.clone());
+    result
+}
+
+/// Returns the edits from the tokens of the given previous result to the
+/// current tokens, or all tokens if we don't know that result.
+pub fn full_delta(
+    ctx: &InitActionContext,
+    file_path: &Path,
+    previous_result_id: &str,
+) -> SemanticTokensFullDeltaResult {
+    let previous = ctx
+        .semantic_tokens
+        .lock()
+        .unwrap()
+        .get(file_path)
+        .filter(|prev| prev.result_id.as_deref() == Some(previous_result_id))
+        .cloned();
+    let current = full(ctx, file_path);
+
+    match previous {
+        Some(previous) => SemanticTokensFullDeltaResult::TokensDelta(SemanticTokensDelta {
+            result_id: current.result_id,
+            edits: diff(&previous.data, &current.data),
+        }),
+        None => SemanticTokensFullDeltaResult::Tokens(current),
+    }
+}
+
+/// Returns the tokens which overlap `range`.
+pub fn range(ctx: &InitActionContext, file_path: &Path, range: Range) -> SemanticTokens {
+    let start = (range.start.line as u32, range.start.character as u32);
+    let end = (range.end.line as u32, range.end.character as u32);
+    let tokens: Vec<_> = tokens(ctx, file_path)
+        .into_iter()
+        .filter(|t| (t.row, t.col + t.len) > start && (t.row, t.col) < end)
+        .collect();
+    SemanticTokens { result_id: None, data: encode(&tokens) }
+}
+
+/// Classifies the identifiers in the file, ordered by position.
+fn tokens(ctx: &InitActionContext, file_path: &Path) -> Vec<Token> {
+    let idents = match ctx.analysis.file_idents(file_path) {
+        Ok(idents) => idents,
+        Err(e) => {
+            debug!("semantic_tokens: no idents for {:?}: {:?}", file_path, e);
+            return vec![];
+        }
+    };
+
+    // The idents describe the text the analysis was computed from, which may
+    // be older than the text in the VFS.
+    let snapshot = ctx.analysis_snapshot().unwrap_or_else(|| ctx.snapshot());
+    let text = ctx.file_text(&snapshot, file_path).unwrap_or_default();
+    let lines: Vec<&str> = text.lines().collect();
+
+    // Identifiers often refer to the same definition, so only look each one
+    // up once.
+    let mut classes: HashMap<Id, Option<(TokenType, u32)>> = HashMap::new();
+    idents
+        .into_iter()
+        .filter_map(|ident| {
+            let range = ident.span.range;
+            let (ty, mut modifiers) = *classes.entry(ident.id).or_insert_with(|| {
+                let def = ctx.analysis.get_def(ident.id).ok()?;
+                let ty = TokenType::from_def_kind(def.kind)?;
+
+                let mut modifiers = 0;
+                if def.distro_crate {
+                    modifiers |= DEFAULT_LIBRARY;
+                }
+                let is_binding = ty == TokenType::Local || ty == TokenType::Static;
+                if is_binding && def.span.file == file_path {
+                    let def_range = def.span.range;
+                    let line = lines.get(def_range.row_start.0 as usize);
+                    if line.map_or(false, |line| is_mut_binding(line, def_range.col_start.0)) {
+                        modifiers |= MUTABLE;
+                    }
+                }
+                Some((ty, modifiers))
+            })?;
+            if ident.kind == IdentKind::Def {
+                modifiers |= DECLARATION;
+            }
+
+            // LSP counts columns in UTF-16 code units rather than in chars.
+            let line = lines.get(range.row_start.0 as usize).copied().unwrap_or_default();
+            let col = utf16_col(line, range.col_start.0);
+            let col_end = utf16_col(line, range.col_end.0);
+            Some(Token {
+                row: range.row_start.0,
+                col,
+                len: col_end.saturating_sub(col),
+                ty,
+                modifiers,
+            })
+        })
+        .collect()
+}
+
+/// Converts the char column `col` in `line` into UTF-16 code units. Columns
+/// past the end of the line (e.g., if the text is stale) are kept as they are.
+fn utf16_col(line: &str, col: u32) -> u32 {
+    let (mut chars, mut units) = (0, 0);
+    for c in line.chars().take(col as usize) {
+        chars += 1;
+        units += c.len_utf16() as u32;
+    }
+    units + (col - chars)
+}
+
+/// Whether the binding of the name at `col` in `line` is mutable, e.g., the
+/// `x` in `let mut x = 0;`.
+fn is_mut_binding(line: &str, col: u32) -> bool {
+    let prefix: String = line.chars().take(col as usize).collect();
+    let prefix = prefix.trim_end();
+    prefix.ends_with("mut")
+        && !prefix[..prefix.len() - 3].ends_with(|c: char| c.is_alphanumeric() || c == '_')
+        && !prefix[..prefix.len() - 3].trim_end().ends_with('&')
+}
+
+/// Encodes tokens, ordered by position, relative to each other as specified
+/// by the LSP.
+fn encode(tokens: &[Token]) -> Vec<u32> {
+    let mut data = Vec::with_capacity(tokens.len() * 5);
+    let (mut prev_row, mut prev_col) = (0, 0);
+    for token in tokens {
+        let delta_row = token.row - prev_row;
+        let delta_col = if delta_row == 0 { token.col - prev_col } else { token.col };
+        data.extend_from_slice(&[
+            delta_row,
+            delta_col,
+            token.len,
+            token.ty.index(),
+            token.modifiers,
+        ]);
+        prev_row = token.row;
+        prev_col = token.col;
+    }
+    data
+}
+
+/// Computes a single edit which replaces the part of `old` which differs
+/// from `new`.
+fn diff(old: &[u32], new: &[u32]) -> Vec<SemanticTokensEdit> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let deleted = &old[prefix..old.len() - suffix];
+    let inserted = &new[prefix..new.len() - suffix];
+    if deleted.is_empty() && inserted.is_empty() {
+        return vec![];
+    }
+    vec![SemanticTokensEdit {
+        start: prefix as u32,
+        delete_count: deleted.len() as u32,
+        data: if inserted.is_empty() { None } else { Some(inserted.to_vec()) },
+    }]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn token(row: u32, col: u32, len: u32, ty: TokenType) -> Token {
+        Token { row, col, len, ty, modifiers: 0 }
+    }
+
+    #[test]
+    fn encodes_relative_positions() {
+        let tokens = vec![
+            token(1, 4, 3, TokenType::Function),
+            token(1, 10, 1, TokenType::Local),
+            token(3, 2, 5, TokenType::Struct),
+        ];
+        assert_eq!(encode(&tokens), vec![1, 4, 3, 3, 0, 0, 6, 1, 6, 0, 2, 2, 5, 0, 0]);
+    }
+
+    #[test]
+    fn counts_utf16_columns() {
+        assert_eq!(utf16_col("let x = 0;", 4), 4);
+        assert_eq!(utf16_col("let é = 0;", 6), 6);
+        assert_eq!(utf16_col("\"🦀\"; let x = 0;", 8), 9);
+        assert_eq!(utf16_col("x", 3), 3);
+    }
+
+    #[test]
+    fn detects_mut_bindings() {
+        assert!(is_mut_binding("    let mut x = 0;", 12));
+        assert!(is_mut_binding("fn foo(mut x: u32) {}", 11));
+        assert!(is_mut_binding("static mut COUNT: u32 = 0;", 11));
+        assert!(!is_mut_binding("    let x = 0;", 8));
+        assert!(!is_mut_binding("    let not_mut = 0;", 8));
+        assert!(!is_mut_binding("    let &mut x = y;", 13));
+    }
+
+    #[test]
+    fn diffs_tokens() {
+        assert_eq!(diff(&[1, 2, 3], &[1, 2, 3]), vec![]);
+        assert_eq!(
+            diff(&[1, 2, 3, 4, 5], &[1, 2, 9, 9, 4, 5]),
+            vec![SemanticTokensEdit { start: 2, delete_count: 1, data: Some(vec![9, 9]) }]
+        );
+        assert_eq!(
+            diff(&[1, 2, 3, 4, 5], &[1, 5]),
+            vec![SemanticTokensEdit { start: 1, delete_count: 3, data: None }]
+        );
+        assert_eq!(
+            diff(&[1, 1], &[1, 1, 1]),
+            vec![SemanticTokensEdit { start: 2, delete_count: 0, data: Some(vec![1]) }]
+        );
+    }
+}
@@ -0,0 +1,394 @@
+//! Signature help for function and method calls, backed by the signatures
+//! recorded in the save-analysis data.
+
+use log::{debug, trace};
+use rls_analysis::DefKind;
+use rls_span::{Column, Position as RlsPosition, Row, Span, ZeroIndexed};
+use rls_vfs::FileContents;
+
+use crate::actions::hover;
+use crate::actions::InitActionContext;
+use crate::lsp_data::*;
+use crate::server::ResponseError;
+
+/// A call expression enclosing the cursor.
+#[derive(Debug, PartialEq, Eq)]
+struct CallSite {
+    /// Row of the callee identifier.
+    row: u32,
+    /// Start and end columns (in chars) of the callee identifier.
+    col_start: u32,
+    col_end: u32,
+    /// Index of the argument the cursor is currently placed in.
+    active_arg: usize,
+    /// `true` if the callee is called using the method call syntax (`recv.f(..)`).
+    is_method_call: bool,
+}
+
+/// Builds signature help for the call expression enclosing the position in
+/// `params`, if there is one and we know the signature of its callee.
+pub fn signature_help(
+    ctx: &InitActionContext,
+    params: &TextDocumentPositionParams,
+) -> Result<Option<SignatureHelp>, ResponseError> {
+    let file_path = parse_file_path!(&params.text_document.uri, "signature_help")?;
+
+    let text = match ctx.vfs.load_file(&file_path) {
+        Ok(FileContents::Text(text)) => text,
+        Ok(FileContents::Binary(_)) => return Ok(None),
+        Err(e) => {
+            debug!("signature_help: couldn't load {:?}: {:?}", file_path, e);
+            return Ok(None);
+        }
+    };
+
+    let call = match find_call_site(&text, ls_util::position_to_rls(params.position)) {
+        Some(call) => call,
+        None => return Ok(None),
+    };
+    trace!("signature_help: call site: {:?}", call);
+
+    let span = Span::new(
+        Row::new_zero_indexed(call.row),
+        Row::new_zero_indexed(call.row),
+        Column::new_zero_indexed(call.col_start),
+        Column::new_zero_indexed(call.col_end),
+        file_path,
+    );
+
+    let label = match signature_text(ctx, &span) {
+        Some(label) => label,
+        None => return Ok(None),
+    };
+    trace!("signature_help: signature: {:?}", label);
+
+    let offsets = parameter_offsets(&label);
+    let has_self_param = offsets.first().map_or(false, |&(start, end)| {
+        let param = &label[start..end];
+        let param = param.trim_start_matches('&').trim_start_matches("mut ").trim_start();
+        param == "self" || param.starts_with("self:") || param.starts_with("self ")
+    });
+    let active_parameter =
+        if call.is_method_call && has_self_param { call.active_arg + 1 } else { call.active_arg };
+
+    let utf16_len = |s: &str| s.encode_utf16().count() as u64;
+    let parameters = offsets
+        .iter()
+        .map(|&(start, end)| ParameterInformation {
+            label: ParameterLabel::LabelOffsets([
+                utf16_len(&label[..start]),
+                utf16_len(&label[..end]),
+            ]),
+            documentation: None,
+        })
+        .collect();
+
+    let documentation =
+        ctx.analysis.docs(&span).ok().filter(|docs| !docs.trim().is_empty()).map(|docs| {
+            Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: hover::process_docs(&docs),
+            })
+        });
+
+    Ok(Some(SignatureHelp {
+        signatures: vec![SignatureInformation {
+            label,
+            documentation,
+            parameters: Some(parameters),
+        }],
+        active_signature: Some(0),
+        active_parameter: Some(active_parameter as i64),
+    }))
+}
+
+/// Returns the signature of the callee at `span`. Prefers the signature
+/// recorded by save-analysis and falls back to reconstructing it from the
+/// `value` of the def (e.g. `fn (x: u32) -> u32`), which is always recorded.
+fn signature_text(ctx: &InitActionContext, span: &Span<ZeroIndexed>) -> Option<String> {
+    if let Ok(sig) = ctx.analysis.signature(span) {
+        return Some(sig.text);
+    }
+
+    let def = ctx.analysis.id(span).and_then(|id| ctx.analysis.get_def(id)).ok()?;
+    match def.kind {
+        DefKind::Function | DefKind::Method | DefKind::ForeignFunction
+            if def.value.starts_with("fn ") =>
+        {
+            Some(format!("fn {}{}", def.name, &def.value["fn ".len()..]))
+        }
+        _ => None,
+    }
+}
+
+/// Returns byte offsets of every parameter in the signature text `sig`, with
+/// the surrounding whitespace trimmed.
+fn parameter_offsets(sig: &str) -> Vec<(usize, usize)> {
+    let mut offsets = vec![];
+
+    // Skip the generics, since these can contain parentheses themselves
+    // (e.g. `F: Fn(u32)`).
+    let mut angle_depth = 0;
+    let mut params_start = None;
+    let mut prev = ' ';
+    for (idx, c) in sig.char_indices() {
+        match c {
+            '<' => angle_depth += 1,
+            '>' if prev != '-' => angle_depth -= 1,
+            '(' if angle_depth == 0 => {
+                params_start = Some(idx + 1);
+                break;
+            }
+            _ => {}
+        }
+        prev = c;
+    }
+    let params_start = match params_start {
+        Some(start) => start,
+        None => return offsets,
+    };
+
+    let mut push_param = |start: usize, end: usize| {
+        let param = &sig[start..end];
+        let trimmed_start = start + (param.len() - param.trim_start().len());
+        let trimmed_end = end - (param.len() - param.trim_end().len());
+        if trimmed_start < trimmed_end {
+            offsets.push((trimmed_start, trimmed_end));
+        }
+    };
+
+    let mut depth = 0;
+    let mut param_start = params_start;
+    let mut prev = ' ';
+    for (idx, c) in sig[params_start..].char_indices() {
+        let idx = params_start + idx;
+        match c {
+            '(' | '[' | '{' | '<' => depth += 1,
+            '>' if prev == '-' => {}
+            ')' if depth == 0 => {
+                push_param(param_start, idx);
+                break;
+            }
+            ')' | ']' | '}' | '>' => depth -= 1,
+            ',' if depth == 0 => {
+                push_param(param_start, idx);
+                param_start = idx + 1;
+            }
+            _ => {}
+        }
+        prev = c;
+    }
+
+    offsets
+}
+
+/// Returns the innermost call expression enclosing `pos` in `text`, or `None`
+/// if the position is not inside a call argument list.
+fn find_call_site(text: &str, pos: RlsPosition<ZeroIndexed>) -> Option<CallSite> {
+    // Every char preceding the cursor, along with its row and column.
+    let mut prefix: Vec<(u32, u32, char)> = vec![];
+    for (row, line) in text.lines().enumerate().take(pos.row.0 as usize + 1) {
+        let row = row as u32;
+        let line_len = if row == pos.row.0 { pos.col.0 as usize } else { usize::MAX };
+        prefix.extend(line.chars().take(line_len).enumerate().map(|(col, c)| (row, col as u32, c)));
+        prefix.push((row, line.chars().count() as u32, '\n'));
+    }
+    prefix.pop();
+
+    let chars = mask_comments_and_literals(&prefix.iter().map(|&(_, _, c)| c).collect::<Vec<_>>());
+
+    // Find the unmatched opening parenthesis of the call, counting the
+    // arguments preceding the cursor along the way.
+    let mut depth = 0;
+    let mut active_arg = 0;
+    let mut open_paren = None;
+    for (idx, &c) in chars.iter().enumerate().rev() {
+        match c {
+            ')' | ']' | '}' => depth += 1,
+            '(' | '[' | '{' if depth > 0 => depth -= 1,
+            '(' => {
+                open_paren = Some(idx);
+                break;
+            }
+            '[' | '{' => return None,
+            ',' if depth == 0 => active_arg += 1,
+            ';' if depth == 0 => return None,
+            _ => {}
+        }
+    }
+
+    let skip_whitespace = |mut idx: usize| {
+        while idx > 0 && chars[idx - 1].is_whitespace() {
+            idx -= 1;
+        }
+        idx
+    };
+
+    // `idx` always points one past the char currently being considered.
+    let mut idx = skip_whitespace(open_paren?);
+    // Skip the turbofish, e.g. `collect::<Vec<_>>(`.
+    if idx > 0 && chars[idx - 1] == '>' {
+        let mut depth = 0;
+        while idx > 0 {
+            idx -= 1;
+            match chars[idx] {
+                '>' => depth += 1,
+                '<' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+        }
+        if idx < 2 || chars[idx - 1] != ':' || chars[idx - 2] != ':' {
+            return None;
+        }
+        idx = skip_whitespace(idx - 2);
+    }
+
+    let ident_end = idx;
+    let is_ident_char = |c: char| c.is_alphanumeric() || c == '_';
+    while idx > 0 && is_ident_char(chars[idx - 1]) {
+        idx -= 1;
+    }
+    let ident_start = idx;
+    if ident_start == ident_end || chars[ident_start].is_numeric() {
+        return None;
+    }
+
+    let before_ident = skip_whitespace(ident_start);
+    let is_method_call = before_ident > 0 && chars[before_ident - 1] == '.';
+
+    let (row, col_start, _) = prefix[ident_start];
+    let (_, col_end, _) = prefix[ident_end - 1];
+    Some(CallSite { row, col_start, col_end: col_end + 1, active_arg, is_method_call })
+}
+
+/// Replaces every char inside a comment, a string or a char literal with
+/// a space, so that these can't be mistaken for delimiters.
+fn mask_comments_and_literals(chars: &[char]) -> Vec<char> {
+    let mut masked = chars.to_vec();
+    let mut idx = 0;
+    while idx < chars.len() {
+        let next = chars.get(idx + 1).copied();
+        let end = match (chars[idx], next) {
+            ('/', Some('/')) => chars[idx..].iter().position(|&c| c == '\n').map(|n| idx + n),
+            ('/', Some('*')) => (idx + 2..chars.len().saturating_sub(1))
+                .find(|&i| chars[i] == '*' && chars[i + 1] == '/')
+                .map(|i| i + 2),
+            ('"', _) => {
+                let mut i = idx + 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                Some(i + 1)
+            }
+            // A char literal, as opposed to a lifetime (e.g. `'a`).
+            ('\'', Some('\\')) => {
+                chars[idx + 2..].iter().position(|&c| c == '\'').map(|n| idx + n + 3)
+            }
+            ('\'', Some(_)) if chars.get(idx + 2) == Some(&'\'') => Some(idx + 3),
+            _ => {
+                idx += 1;
+                continue;
+            }
+        };
+        // Unterminated comments or literals extend up to the cursor.
+        let end = end.unwrap_or(chars.len()).min(chars.len());
+        for c in &mut masked[idx..end] {
+            if *c != '\n' {
+                *c = ' ';
+            }
+        }
+        idx = end;
+    }
+    masked
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call_site(text: &str) -> Option<CallSite> {
+        assert_eq!(text.matches('$').count(), 1);
+        let cursor = text.find('$').unwrap();
+        let row = text[..cursor].matches('\n').count() as u32;
+        let col = text[..cursor].rsplit('\n').next().unwrap().chars().count() as u32;
+        let pos = RlsPosition::new(Row::new_zero_indexed(row), Column::new_zero_indexed(col));
+        find_call_site(&text.replace('$', ""), pos)
+    }
+
+    fn params(sig: &str) -> Vec<&str> {
+        parameter_offsets(sig).into_iter().map(|(start, end)| &sig[start..end]).collect()
+    }
+
+    #[test]
+    fn finds_function_call() {
+        assert_eq!(
+            call_site("foo($"),
+            Some(CallSite {
+                row: 0,
+                col_start: 0,
+                col_end: 3,
+                active_arg: 0,
+                is_method_call: false
+            })
+        );
+        assert_eq!(
+            call_site("    let x = bar(1, (2, 3), $"),
+            Some(CallSite {
+                row: 0,
+                col_start: 12,
+                col_end: 15,
+                active_arg: 2,
+                is_method_call: false
+            })
+        );
+        assert_eq!(
+            call_site("baz::<Vec<u8>>(a, vec![1, 2]$"),
+            Some(CallSite {
+                row: 0,
+                col_start: 0,
+                col_end: 3,
+                active_arg: 1,
+                is_method_call: false
+            })
+        );
+    }
+
+    #[test]
+    fn finds_method_call_across_lines() {
+        assert_eq!(
+            call_site("x\n    .frob(\n        \"a, (b\",\n        '(',\n  $"),
+            Some(CallSite {
+                row: 1,
+                col_start: 5,
+                col_end: 9,
+                active_arg: 2,
+                is_method_call: true
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_non_calls() {
+        assert_eq!(call_site("foo(); $"), None);
+        assert_eq!(call_site("let x = (1, $"), None);
+        assert_eq!(call_site("println!(\"{}\", $"), None);
+        assert_eq!(call_site("foo(|x| { $"), None);
+    }
+
+    #[test]
+    fn splits_parameters() {
+        assert_eq!(params("fn foo()"), Vec::<&str>::new());
+        assert_eq!(params("fn foo(x: u32) -> u32"), vec!["x: u32"]);
+        assert_eq!(
+            params("fn foo<F: Fn(u8) -> u8>(&self, f: F, m: HashMap<u8, (u8, u8)>)"),
+            vec!["&self", "f: F", "m: HashMap<u8, (u8, u8)>"]
+        );
+        assert_eq!(
+            params("fn bar(f: impl Fn(u8) -> u8, y: u8)"),
+            vec!["f: impl Fn(u8) -> u8", "y: u8"]
+        );
+    }
+}
@@ -2,8 +2,9 @@ use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::ffi::OsString;
 use std::fmt::{self, Write};
-use std::fs::{read_dir, remove_file};
+use std::fs::{self, read_dir, remove_file};
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
@@ -18,11 +19,11 @@ use cargo::core::{
 use cargo::ops::{compile_with_exec, CompileFilter, CompileOptions, Packages};
 use cargo::util::{
     config as cargo_config, errors::ManifestError, homedir, important_paths, CargoResult,
-    ConfigValue, ProcessBuilder,
+    ConfigValue, ProcessBuilder, Sha256,
 };
 use log::{debug, trace, warn};
 use rls_data::Analysis;
-use rls_vfs::Vfs;
+use rls_vfs::Snapshot;
 
 use crate::actions::progress::ProgressUpdate;
 use crate::build::cargo_plan::CargoPlan;
@@ -36,11 +37,12 @@ use crate::lsp_data::{Position, Range};
 pub(super) fn cargo(
     internals: &Internals,
     package_arg: PackageArg,
+    snapshot: &Snapshot,
     progress_sender: Sender<ProgressUpdate>,
 ) -> BuildResult {
     let compilation_cx = Arc::clone(&internals.compilation_cx);
     let config = Arc::clone(&internals.config);
-    let vfs = Arc::clone(&internals.vfs);
+    let snapshot = snapshot.clone();
     let env_lock = Arc::clone(&internals.env_lock);
 
     let diagnostics = Arc::default();
@@ -62,7 +64,7 @@ pub(super) fn cargo(
                 compilation_cx,
                 package_arg,
                 config,
-                vfs,
+                snapshot,
                 env_lock,
                 diagnostics,
                 analysis,
@@ -96,7 +98,7 @@ fn run_cargo(
     compilation_cx: Arc<Mutex<CompilationContext>>,
     package_arg: PackageArg,
     rls_config: Arc<Mutex<Config>>,
-    vfs: Arc<Vfs>,
+    snapshot: Snapshot,
     env_lock: Arc<EnvironmentLock>,
     compiler_messages: Arc<Mutex<Vec<String>>>,
     analysis: Arc<Mutex<Vec<Analysis>>>,
@@ -138,7 +140,7 @@ fn run_cargo(
         compilation_cx,
         package_arg,
         rls_config,
-        vfs,
+        snapshot,
         compiler_messages,
         analysis,
         input_files,
@@ -156,7 +158,7 @@ fn run_cargo_ws(
     compilation_cx: Arc<Mutex<CompilationContext>>,
     package_arg: PackageArg,
     rls_config: Arc<Mutex<Config>>,
-    vfs: Arc<Vfs>,
+    snapshot: Snapshot,
     compiler_messages: Arc<Mutex<Vec<String>>>,
     analysis: Arc<Mutex<Vec<Analysis>>>,
     input_files: Arc<Mutex<HashMap<PathBuf, HashSet<Crate>>>>,
@@ -174,7 +176,7 @@ fn run_cargo_ws(
 
     // TODO: it might be feasible to keep this `CargoOptions` structure cached and regenerate
     // it on every relevant configuration change.
-    let (opts, rustflags, clear_env_rust_log, cfg_test) = {
+    let (opts, rustflags, clear_env_rust_log, cfg_test, config_fingerprint) = {
         // We mustn't lock configuration for the whole build process
         let rls_config = rls_config.lock().unwrap();
 
@@ -192,7 +194,8 @@ fn run_cargo_ws(
             }
         }
 
-        (opts, rustflags, rls_config.clear_env_rust_log, rls_config.cfg_test)
+        let config_fingerprint = config_fingerprint(&rls_config);
+        (opts, rustflags, rls_config.clear_env_rust_log, rls_config.cfg_test, config_fingerprint)
     };
 
     let spec = Packages::from_flags(all, Vec::new(), packages)?;
@@ -250,9 +253,9 @@ fn run_cargo_ws(
     let exec = RlsExecutor::new(
         &ws,
         Arc::clone(&compilation_cx),
-        rls_config,
+        Arc::clone(&rls_config),
         inner_lock,
-        vfs,
+        snapshot,
         compiler_messages,
         analysis,
         input_files,
@@ -293,6 +296,23 @@ fn run_cargo_ws(
         return Err(anyhow::format_err!("error compiling dependent crate"));
     }
 
+    // Persist the build plan, so that it can be reused after a restart.
+    {
+        let mut cx = compilation_cx.lock().unwrap();
+        if let Some(plan) = cx.build_plan.as_cargo_mut() {
+            // Build scripts saw the env before we modified it for Cargo.
+            plan.read_build_script_outputs(|var| restore_env.get_old_var(var));
+        }
+        let target_dir = rls_config.lock().unwrap().target_dir.as_ref().clone();
+        if let (BuildPlan::Cargo(plan), Some(target_dir)) = (&cx.build_plan, target_dir) {
+            let fingerprint = plan_fingerprint(manifest_path, plan, &config_fingerprint);
+            let path = target_dir.join(PERSISTED_PLAN_FILE);
+            if let Err(e) = plan.save(&path, &fingerprint, cx.cwd.as_deref()) {
+                warn!("Couldn't persist the build plan to `{}`: {}", path.display(), e);
+            }
+        }
+    }
+
     Ok(compilation_cx
         .lock()
         .unwrap()
@@ -301,6 +321,64 @@ fn run_cargo_ws(
         .unwrap_or_else(|| restore_env.get_old_cwd().to_path_buf()))
 }
 
+/// File in the target directory which the build plan is persisted to.
+const PERSISTED_PLAN_FILE: &str = "build-plan.json";
+
+/// Loads the build plan persisted by the last Cargo build of the project in
+/// `build_dir`, along with the working directory of its compiler jobs, unless
+/// it's out of date.
+pub(super) fn load_persisted_plan(
+    build_dir: &Path,
+    rls_config: &Config,
+) -> Option<(CargoPlan, Option<PathBuf>)> {
+    let target_dir = rls_config.target_dir.as_ref().as_ref()?;
+    let manifest_path = important_paths::find_root_manifest_for_wd(build_dir).ok()?;
+    let config_fingerprint = config_fingerprint(rls_config);
+
+    CargoPlan::load(&target_dir.join(PERSISTED_PLAN_FILE), &manifest_path, |plan| {
+        plan_fingerprint(&manifest_path, plan, &config_fingerprint)
+    })
+}
+
+/// Fingerprints what a persisted build plan depends on: the lock file and
+/// manifests of the workspace, the compiler and the RLS configuration. Uses
+/// SHA-256, since the fingerprint is stored on disk and has to stay the same
+/// across releases.
+fn plan_fingerprint(manifest_path: &Path, plan: &CargoPlan, config_fingerprint: &str) -> String {
+    let mut hasher = Sha256::new();
+    // Each input is prefixed with its length, so that they can't run together.
+    let mut update = |bytes: Option<&[u8]>| {
+        match bytes {
+            Some(bytes) => hasher.update(&(bytes.len() as u64 + 1).to_le_bytes()).update(bytes),
+            None => hasher.update(&0u64.to_le_bytes()),
+        };
+    };
+
+    // Packages are compiled by the RLS itself unless `$RUSTC` is set, but the
+    // standard library comes from the toolchain, which may have changed either way.
+    update(Some(crate::version().as_bytes()));
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let version = Command::new(rustc).arg("-vV").output().ok();
+    update(version.as_ref().map(|output| &output.stdout[..]));
+
+    let lock_file = manifest_path.with_file_name("Cargo.lock");
+    let files = vec![lock_file, manifest_path.to_owned()].into_iter().chain(plan.manifest_paths());
+    for file in files {
+        update(fs::read(&file).ok().as_deref());
+        update(Some(file.to_string_lossy().as_bytes()));
+    }
+
+    update(Some(config_fingerprint.as_bytes()));
+    hasher.finish_hex()
+}
+
+/// Returns the parts of the RLS configuration that the Cargo build depends on.
+fn config_fingerprint(rls_config: &Config) -> String {
+    let opts = CargoOptions::new(rls_config);
+    let rustflags = prepare_cargo_rustflags(rls_config);
+    format!("{:?} {:?} {}", opts, rustflags, rls_config.cfg_test)
+}
+
 struct RlsExecutor {
     compilation_cx: Arc<Mutex<CompilationContext>>,
     config: Arc<Mutex<Config>>,
@@ -308,7 +386,8 @@ struct RlsExecutor {
     /// and calling the compilation function. This, resulting, inner lock is used to synchronize
     /// env var access during underlying `rustc()` calls during parallel `exec()` callback threads.
     env_lock: environment::InnerLock,
-    vfs: Arc<Vfs>,
+    /// The files to compile, as they were at the start of the build.
+    snapshot: Snapshot,
     analysis: Arc<Mutex<Vec<Analysis>>>,
     /// Packages which are directly a member of the workspace, for which
     /// analysis and diagnostics will be provided.
@@ -331,7 +410,7 @@ impl RlsExecutor {
         compilation_cx: Arc<Mutex<CompilationContext>>,
         config: Arc<Mutex<Config>>,
         env_lock: environment::InnerLock,
-        vfs: Arc<Vfs>,
+        snapshot: Snapshot,
         compiler_messages: Arc<Mutex<Vec<String>>>,
         analysis: Arc<Mutex<Vec<Analysis>>>,
         input_files: Arc<Mutex<HashMap<PathBuf, HashSet<Crate>>>>,
@@ -344,7 +423,7 @@ impl RlsExecutor {
             compilation_cx,
             config,
             env_lock,
-            vfs,
+            snapshot,
             analysis,
             input_files,
             member_packages: Mutex::new(member_packages),
@@ -559,15 +638,19 @@ impl Executor for RlsExecutor {
             cx.build_dir.clone().unwrap()
         };
 
+        // Don't hold the lock while compiling, rustc may be called in parallel.
+        let progress_sender = self.progress_sender.lock().unwrap().clone();
         if let BuildResult::Success(_, mut messages, mut analysis, input_files, success) =
             super::rustc::rustc(
-                &self.vfs,
+                &self.snapshot,
                 &args,
                 &envs,
                 cargo_cmd.get_cwd(),
                 &build_dir,
                 Arc::clone(&self.config),
                 &self.env_lock.as_facade(),
+                &progress_sender,
+                false,
             )
         {
             self.compiler_messages.lock().unwrap().append(&mut messages);
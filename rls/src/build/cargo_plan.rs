@@ -13,30 +13,70 @@
 //! are not exposed via `Executor` trait in Cargo, we simply coalesce every unit
 //! with a same package and crate target kind (e.g. both building and running
 //! build scripts).
-
-use std::collections::{HashMap, HashSet};
+//! The plan is persisted in the target directory after each Cargo build, so
+//! that it can be reused when the RLS is restarted.
+//! To know when Cargo has to rerun build scripts, the plan also keeps track of
+//! their `rerun-if-changed` and `rerun-if-env-changed` directives, as well as
+//! of the libraries proc macros are built into.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
 use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::SystemTime;
 
-use cargo::core::compiler::{CompileKind, CompileMode, Context, Unit};
-use cargo::core::profiles::Profile;
-use cargo::core::{PackageId, Target, TargetKind};
-use cargo::util::ProcessBuilder;
+use cargo::core::compiler::{CompileMode, Context, Unit};
+use cargo::core::{PackageId, Target};
+use cargo::util::{process, ProcessBuilder};
 use log::{error, trace};
+use serde_derive::{Deserialize, Serialize};
 
 use crate::build::plan::{BuildGraph, BuildKey, JobQueue, WorkStatus};
 use crate::build::rustc::src_path;
+use crate::build::rustc_args::RustcArgs;
 use crate::build::PackageArg;
 
 /// Main key type by which `Unit`s will be distinguished in the build plan.
 /// In `Target` we're mostly interested in `TargetKind` (Lib, Bin, ...) and name
 /// (e.g., we can have 2 binary targets with different names).
-#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 pub(crate) struct UnitKey {
     pkg_id: PackageId,
-    target: Target,
-    mode: CompileMode,
+    target: UnitTarget,
+    /// `CompileMode` of the unit, as formatted by `Debug`.
+    mode: String,
+}
+
+impl UnitKey {
+    fn new(pkg_id: PackageId, target: &Target, mode: CompileMode) -> UnitKey {
+        UnitKey { pkg_id, target: target.into(), mode: format!("{:?}", mode) }
+    }
+}
+
+/// The parts of a Cargo `Target` which the build plan needs. Unlike `Target`,
+/// these can be persisted.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub(crate) struct UnitTarget {
+    name: String,
+    /// `TargetKind` of the target, as formatted by `Debug`.
+    kind: String,
+    is_custom_build: bool,
+    src_path: Option<PathBuf>,
+}
+
+impl From<&Target> for UnitTarget {
+    fn from(target: &Target) -> UnitTarget {
+        UnitTarget {
+            name: target.name().to_owned(),
+            kind: format!("{:?}", target.kind()),
+            is_custom_build: target.is_custom_build(),
+            src_path: target.src_path().path().map(ToOwned::to_owned),
+        }
+    }
 }
 
 /// Holds the information how exactly the build will be performed for a given
@@ -54,6 +94,10 @@ pub(crate) struct CargoPlan {
     /// Calculated input files that unit depend on.
     pub(crate) input_files: HashMap<UnitKey, Vec<PathBuf>>,
     pub(crate) file_key_mapping: HashMap<PathBuf, HashSet<UnitKey>>,
+    /// Build script runs, by their units.
+    build_script_runs: HashMap<UnitKey, BuildScriptRun>,
+    /// Libraries which the proc macro units are built into.
+    proc_macro_outputs: HashMap<UnitKey, Vec<PathBuf>>,
     // An object for finding the package which a file belongs to and this inferring
     // a package argument.
     package_map: Option<PackageMap>,
@@ -87,7 +131,7 @@ impl CargoPlan {
         mode: CompileMode,
         cmd: &ProcessBuilder,
     ) {
-        let unit_key = UnitKey { pkg_id: id, target: target.clone(), mode };
+        let unit_key = UnitKey::new(id, target, mode);
         self.compiler_jobs.insert(unit_key, cmd.clone());
     }
 
@@ -111,9 +155,13 @@ impl CargoPlan {
             })
             .collect();
 
-        let unit_key = UnitKey { pkg_id: id, target: target.clone(), mode };
+        let unit_key = UnitKey::new(id, target, mode);
         trace!("Caching these files: {:#?} for {:?} key", &input_files, &unit_key);
 
+        self.insert_input_files(unit_key, input_files);
+    }
+
+    fn insert_input_files(&mut self, unit_key: UnitKey, input_files: Vec<PathBuf>) {
         // Create reverse file -> unit mapping (to be used for dirty unit calculation).
         for file in &input_files {
             self.file_key_mapping.entry(file.to_path_buf()).or_default().insert(unit_key.clone());
@@ -149,6 +197,20 @@ impl CargoPlan {
         // pair of (PackageId, TargetKind), so only first occurrence will be saved.
         self.units.insert(key.clone(), unit.into());
 
+        // Cargo runs build scripts itself, so we only learn what they depend
+        // on from the output it saves once they ran.
+        if unit.mode.is_run_custom_build() {
+            let output = cx.files().build_script_run_dir(unit).join("output");
+            let run = BuildScriptRun::new(output, unit.pkg.root().to_owned());
+            self.build_script_runs.insert(key.clone(), run);
+        }
+        if unit.target.proc_macro() {
+            if let Ok(outputs) = cx.outputs(unit) {
+                let outputs = outputs.iter().map(|output| output.path.clone()).collect();
+                self.proc_macro_outputs.insert(key.clone(), outputs);
+            }
+        }
+
         // Fetch and insert relevant unit dependencies to the forward dep graph.
         let deps = cx.unit_deps(unit);
         let dep_keys: HashSet<UnitKey> = deps
@@ -179,6 +241,28 @@ impl CargoPlan {
         }
     }
 
+    /// Reads the directives of the build scripts, after Cargo ran them.
+    /// `env_var` returns the values of env vars as the build scripts saw them.
+    pub(crate) fn read_build_script_outputs(&mut self, env_var: impl Fn(&str) -> Option<OsString>) {
+        for run in self.build_script_runs.values_mut() {
+            run.read_output(&env_var);
+        }
+    }
+
+    /// Returns the build script which Cargo would rerun, given the modified
+    /// files (which can be empty to only check the env).
+    fn stale_build_script<T: AsRef<Path>>(&self, modified: &[T]) -> Option<&UnitKey> {
+        let stale = self.build_script_runs.iter().find(|(_, run)| run.is_stale(modified));
+        stale.map(|(key, _)| key)
+    }
+
+    /// Returns a library of a proc macro which doesn't exist (anymore), which
+    /// has to be built for the crates using the proc macro to compile.
+    fn missing_proc_macro(&self) -> Option<&Path> {
+        let mut outputs = self.proc_macro_outputs.values().flatten();
+        outputs.find(|output| !output.exists()).map(PathBuf::as_path)
+    }
+
     /// TODO: improve detecting dirty crate targets for a set of dirty file paths.
     /// This uses a lousy heuristic of checking path prefix for a given crate
     /// target to determine whether a given unit (crate target) is dirty. This
@@ -194,20 +278,20 @@ impl CargoPlan {
             .units
             .iter()
             .filter(|(UnitKey { target, .. }, _)| {
-                target.is_custom_build() && target.src_path().is_path()
+                target.is_custom_build && target.src_path.is_some()
             })
-            .map(|(key, unit)| (unit.target.src_path().path().unwrap(), key.clone()))
+            .map(|(key, unit)| (unit.target.src_path.as_deref().unwrap(), key.clone()))
             .collect();
         let other_targets: HashMap<UnitKey, &Path> = self
             .units
             .iter()
-            .filter(|(UnitKey { target, .. }, _)| !target.is_custom_build())
+            .filter(|(UnitKey { target, .. }, _)| !target.is_custom_build)
             .map(|(key, unit)| {
                 (
                     key.clone(),
                     unit.target
-                        .src_path()
-                        .path()
+                        .src_path
+                        .as_deref()
                         .expect("normal targets should have a path")
                         .parent()
                         .expect("no parent for src_path"),
@@ -353,37 +437,332 @@ impl CargoPlan {
             return WorkStatus::NeedsCargo(PackageArg::Packages(needed_packages));
         }
 
+        // Rerunning a build script can change how its package is compiled.
+        if let Some(key) = self.stale_build_script(modified) {
+            trace!("Build script {:?} needs to be rerun", key);
+            return WorkStatus::NeedsCargo(PackageArg::Packages(needed_packages));
+        }
+        if let Some(missing) = self.missing_proc_macro() {
+            trace!("Proc macro `{}` needs to be rebuilt", missing.display());
+            return WorkStatus::NeedsCargo(PackageArg::Packages(needed_packages));
+        }
+
         let dirties = self.fetch_dirty_units(modified);
         trace!("fetch_dirty_units: for files {:?}, these units are dirty: {:?}", modified, dirties,);
 
-        if dirties.iter().any(|UnitKey { target, .. }| *target.kind() == TargetKind::CustomBuild) {
+        if dirties.iter().any(|UnitKey { target, .. }| target.is_custom_build) {
             WorkStatus::NeedsCargo(PackageArg::Packages(needed_packages))
         } else {
-            let graph = self.dirty_rev_dep_graph(&dirties);
-            trace!("Constructed dirty rev dep graph: {:?}", graph);
+            self.queue_dirty_units(&dirties)
+        }
+    }
 
-            if graph.is_empty() {
-                return WorkStatus::NeedsCargo(PackageArg::Default);
+    /// Returns the work needed to rebuild every unit of the plan, e.g., after
+    /// loading it from disk. Build scripts aren't rerun; their outputs from
+    /// the last Cargo build are reused.
+    pub(crate) fn prepare_all_work(&self) -> WorkStatus {
+        if !self.is_ready() || self.package_map.is_none() {
+            return WorkStatus::NeedsCargo(PackageArg::Default);
+        }
+        // Any file may have changed since the plan was made.
+        if self.stale_build_script::<&Path>(&[]).is_some()
+            || self.build_script_runs.values().any(BuildScriptRun::ran_before_changes)
+        {
+            return WorkStatus::NeedsCargo(PackageArg::Default);
+        }
+
+        let units = self.units.keys().filter(|key| !key.target.is_custom_build).cloned().collect();
+        self.queue_dirty_units(&units)
+    }
+
+    fn queue_dirty_units(&self, dirties: &HashSet<UnitKey>) -> WorkStatus {
+        let graph = self.dirty_rev_dep_graph(dirties);
+        trace!("Constructed dirty rev dep graph: {:?}", graph);
+
+        if graph.is_empty() {
+            return WorkStatus::NeedsCargo(PackageArg::Default);
+        }
+
+        let queue = self.topological_sort(&graph);
+        trace!("Topologically sorted dirty graph: {:?} {}", queue, self.is_ready());
+        let jobs: Option<Vec<_>> = queue
+            .into_iter()
+            .map(|x| self.compiler_jobs.get(&x).cloned().map(|job| (x, job)))
+            .collect();
+
+        // It is possible that we want a job which is not in our cache (compiler_jobs),
+        // for example we might be building a workspace with an error in a crate and later
+        // crates within the crate that depend on the erroring one have never been built.
+        // In that case we need to build from scratch so that everything is in our cache, or
+        // we cope with the error. In the error case, jobs will be None.
+        match jobs {
+            None => WorkStatus::NeedsCargo(PackageArg::Default),
+            Some(jobs) => {
+                assert!(!jobs.is_empty());
+                // The sorted queue also contains the dependents of the dirty
+                // units, so order the jobs by the whole graph.
+                WorkStatus::Execute(JobQueue::with_graph(jobs, &self.rev_dep_graph))
             }
+        }
+    }
 
-            let queue = self.topological_sort(&graph);
-            trace!("Topologically sorted dirty graph: {:?} {}", queue, self.is_ready());
-            let jobs: Option<Vec<_>> =
-                queue.iter().map(|x| self.compiler_jobs.get(x).cloned()).collect();
-
-            // It is possible that we want a job which is not in our cache (compiler_jobs),
-            // for example we might be building a workspace with an error in a crate and later
-            // crates within the crate that depend on the erroring one have never been built.
-            // In that case we need to build from scratch so that everything is in our cache, or
-            // we cope with the error. In the error case, jobs will be None.
-            match jobs {
-                None => WorkStatus::NeedsCargo(PackageArg::Default),
-                Some(jobs) => {
-                    assert!(!jobs.is_empty());
-                    WorkStatus::Execute(JobQueue::with_commands(jobs))
-                }
+    /// Returns the manifests of the packages in the workspace.
+    pub(crate) fn manifest_paths(&self) -> Vec<PathBuf> {
+        let package_paths = self.package_map.iter().flat_map(|map| map.package_paths.keys());
+        let mut manifests: Vec<_> = package_paths.map(|dir| dir.join("Cargo.toml")).collect();
+        manifests.sort();
+        manifests
+    }
+
+    /// Writes the plan to `path`, along with the `fingerprint` of everything
+    /// it depends on and the working directory of its compiler jobs.
+    pub(crate) fn save(
+        &self,
+        path: &Path,
+        fingerprint: &str,
+        cwd: Option<&Path>,
+    ) -> io::Result<()> {
+        let units = self
+            .units
+            .iter()
+            .map(|(key, unit)| PersistedUnit {
+                unit: unit.clone(),
+                deps: self.dep_graph.get(key).into_iter().flatten().cloned().collect(),
+                job: self.compiler_jobs.get(key).map(PersistedJob::from),
+                input_files: self.input_files.get(key).cloned(),
+                build_script_run: self.build_script_runs.get(key).cloned(),
+                proc_macro_outputs: self.proc_macro_outputs.get(key).cloned(),
+            })
+            .collect();
+        let persisted = PersistedPlan {
+            fingerprint: fingerprint.to_owned(),
+            cwd: cwd.map(ToOwned::to_owned),
+            built_packages: self.built_packages.clone(),
+            units,
+        };
+
+        // Write to a temporary file first, so that a concurrently running RLS
+        // never reads a partially written plan.
+        let tmp_path = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut file, &persisted)?;
+        file.flush()?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// Reads a plan written by `save` from `path`, returning it along with the
+    /// working directory of its compiler jobs. Returns `None` if there is none,
+    /// or if it was saved with a different `fingerprint`.
+    pub(crate) fn load(
+        path: &Path,
+        manifest_path: &Path,
+        fingerprint: impl FnOnce(&CargoPlan) -> String,
+    ) -> Option<(CargoPlan, Option<PathBuf>)> {
+        let file = File::open(path).ok()?;
+        let persisted: PersistedPlan = match serde_json::from_reader(BufReader::new(file)) {
+            Ok(persisted) => persisted,
+            Err(e) => {
+                error!("Couldn't read the build plan in `{}`: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        let mut plan = CargoPlan::with_packages(manifest_path, persisted.built_packages);
+        if persisted.fingerprint != fingerprint(&plan) {
+            trace!("Build plan in `{}` is out of date", path.display());
+            return None;
+        }
+
+        for PersistedUnit { unit, deps, job, input_files, build_script_run, proc_macro_outputs } in
+            persisted.units
+        {
+            let key = unit.key();
+            plan.rev_dep_graph.entry(key.clone()).or_insert_with(HashSet::new);
+            for dep in &deps {
+                plan.rev_dep_graph.entry(dep.clone()).or_default().insert(key.clone());
+            }
+            plan.dep_graph.insert(key.clone(), deps.into_iter().collect());
+            if let Some(job) = job {
+                plan.compiler_jobs.insert(key.clone(), job.into());
             }
+            if let Some(input_files) = input_files {
+                plan.insert_input_files(key.clone(), input_files);
+            }
+            if let Some(run) = build_script_run {
+                plan.build_script_runs.insert(key.clone(), run);
+            }
+            if let Some(outputs) = proc_macro_outputs {
+                plan.proc_macro_outputs.insert(key.clone(), outputs);
+            }
+            plan.units.insert(key, unit);
         }
+
+        // Make sure the graph doesn't refer to units which aren't in the plan.
+        if plan.rev_dep_graph.keys().any(|key| !plan.units.contains_key(key)) {
+            error!("Build plan in `{}` is inconsistent", path.display());
+            return None;
+        }
+        // Dependencies may have been removed since, e.g., by `cargo clean`.
+        let mut externs = plan.compiler_jobs.values().flat_map(extern_files);
+        if let Some(missing) = externs.find(|file| !file.exists()) {
+            trace!("Build plan in `{}` needs missing `{}`", path.display(), missing.display());
+            return None;
+        }
+
+        Some((plan, persisted.cwd))
+    }
+}
+
+/// A run of a build script, along with what makes Cargo rerun it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct BuildScriptRun {
+    /// File which Cargo saves the output of the build script to.
+    output: PathBuf,
+    /// Root directory of the package, which relative paths are relative to.
+    package_root: PathBuf,
+    /// Files or directories from `cargo:rerun-if-changed` directives.
+    rerun_if_changed: Vec<PathBuf>,
+    /// Env vars from `cargo:rerun-if-env-changed` directives, with the values
+    /// the build script saw.
+    rerun_if_env_changed: BTreeMap<String, Option<OsString>>,
+}
+
+impl BuildScriptRun {
+    fn new(output: PathBuf, package_root: PathBuf) -> BuildScriptRun {
+        BuildScriptRun {
+            output,
+            package_root,
+            rerun_if_changed: vec![],
+            rerun_if_env_changed: BTreeMap::new(),
+        }
+    }
+
+    fn read_output(&mut self, env_var: &impl Fn(&str) -> Option<OsString>) {
+        self.rerun_if_changed.clear();
+        self.rerun_if_env_changed.clear();
+
+        // The build script might not have run, e.g., because of a compile error.
+        let output = match fs::read_to_string(&self.output) {
+            Ok(output) => output,
+            Err(e) => {
+                trace!("Couldn't read build script output `{}`: {}", self.output.display(), e);
+                return;
+            }
+        };
+        for line in output.lines() {
+            if let Some(path) = line.strip_prefix("cargo:rerun-if-changed=") {
+                self.rerun_if_changed.push(self.package_root.join(path));
+            } else if let Some(var) = line.strip_prefix("cargo:rerun-if-env-changed=") {
+                self.rerun_if_env_changed.insert(var.to_owned(), env_var(var));
+            }
+        }
+    }
+
+    /// Returns whether Cargo would rerun the build script, given the modified
+    /// files. Without `rerun-if-changed` directives, Cargo would rerun it on
+    /// any change to its package, but we only do so for the build script
+    /// itself (see `fetch_dirty_units`), not to run Cargo on every change.
+    fn is_stale<T: AsRef<Path>>(&self, modified: &[T]) -> bool {
+        let changed_file = modified
+            .iter()
+            .any(|file| self.rerun_if_changed.iter().any(|path| file.as_ref().starts_with(path)));
+        let changed_env =
+            self.rerun_if_env_changed.iter().any(|(var, value)| env::var_os(var) != *value);
+
+        changed_file || changed_env
+    }
+
+    /// Returns whether any file from `rerun-if-changed` directives changed on
+    /// disk since the build script ran, as Cargo checks it, or whether it
+    /// didn't run at all.
+    fn ran_before_changes(&self) -> bool {
+        let ran = match fs::metadata(&self.output).and_then(|meta| meta.modified()) {
+            Ok(ran) => ran,
+            // Cargo has yet to run it.
+            Err(_) => return true,
+        };
+        self.rerun_if_changed.iter().any(|path| last_modified(path).map_or(true, |time| time > ran))
+    }
+}
+
+/// Returns when a file, or any file in a directory, was last modified.
+fn last_modified(path: &Path) -> io::Result<SystemTime> {
+    let meta = fs::metadata(path)?;
+    let mut last = meta.modified()?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            last = last.max(last_modified(&entry?.path())?);
+        }
+    }
+    Ok(last)
+}
+
+/// Returns the files of the crates passed to a compiler job with `--extern`.
+fn extern_files(job: &ProcessBuilder) -> Vec<PathBuf> {
+    let args: Vec<_> = job.get_args().iter().filter_map(|arg| arg.to_str()).collect();
+    let cwd = job.get_cwd().unwrap_or_else(|| Path::new("."));
+
+    RustcArgs::parse(&args)
+        .externs
+        .iter()
+        .filter_map(|spec| spec.splitn(2, '=').nth(1))
+        .map(|file| cwd.join(file))
+        .collect()
+}
+
+/// A `CargoPlan`, as persisted between runs of the RLS.
+#[derive(Deserialize, Serialize)]
+struct PersistedPlan {
+    fingerprint: String,
+    cwd: Option<PathBuf>,
+    built_packages: HashSet<String>,
+    units: Vec<PersistedUnit>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct PersistedUnit {
+    unit: OwnedUnit,
+    deps: Vec<UnitKey>,
+    job: Option<PersistedJob>,
+    input_files: Option<Vec<PathBuf>>,
+    build_script_run: Option<BuildScriptRun>,
+    proc_macro_outputs: Option<Vec<PathBuf>>,
+}
+
+/// A cached compiler job, as persisted between runs of the RLS.
+#[derive(Deserialize, Serialize)]
+struct PersistedJob {
+    program: OsString,
+    args: Vec<OsString>,
+    envs: BTreeMap<String, Option<OsString>>,
+    cwd: Option<PathBuf>,
+}
+
+impl From<&ProcessBuilder> for PersistedJob {
+    fn from(job: &ProcessBuilder) -> PersistedJob {
+        PersistedJob {
+            program: job.get_program().to_owned(),
+            args: job.get_args().to_owned(),
+            envs: job.get_envs().clone(),
+            cwd: job.get_cwd().map(ToOwned::to_owned),
+        }
+    }
+}
+
+impl From<PersistedJob> for ProcessBuilder {
+    fn from(job: PersistedJob) -> ProcessBuilder {
+        let mut cmd = process(job.program);
+        cmd.args(&job.args);
+        for (key, value) in job.envs {
+            match value {
+                Some(value) => cmd.env(&key, value),
+                None => cmd.env_remove(&key),
+            };
+        }
+        if let Some(cwd) = job.cwd {
+            cmd.cwd(cwd);
+        }
+        cmd
     }
 }
 
@@ -466,35 +845,29 @@ impl PackageMap {
 
 impl From<&Unit> for UnitKey {
     fn from(unit: &Unit) -> UnitKey {
-        UnitKey { pkg_id: unit.pkg.package_id(), target: unit.target.clone(), mode: unit.mode }
+        UnitKey::new(unit.pkg.package_id(), &unit.target, unit.mode)
     }
 }
 
-#[derive(Hash, PartialEq, Eq, Debug, Clone)]
-/// An owned version of `cargo::core::Unit`.
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Deserialize, Serialize)]
+/// An owned version of `cargo::core::Unit`, keeping what the build plan needs.
 pub(crate) struct OwnedUnit {
     pub(crate) id: PackageId,
-    pub(crate) target: Target,
-    pub(crate) profile: Profile,
-    pub(crate) kind: CompileKind,
-    pub(crate) mode: CompileMode,
+    pub(crate) target: UnitTarget,
+    /// `CompileMode` of the unit, as formatted by `Debug`.
+    pub(crate) mode: String,
 }
 
 impl From<&Unit> for OwnedUnit {
     fn from(unit: &Unit) -> OwnedUnit {
-        OwnedUnit {
-            id: unit.pkg.package_id().to_owned(),
-            target: unit.target.clone(),
-            profile: unit.profile,
-            kind: unit.kind,
-            mode: unit.mode,
-        }
+        let UnitKey { pkg_id, target, mode } = UnitKey::from(unit);
+        OwnedUnit { id: pkg_id, target, mode }
     }
 }
 
 impl From<&OwnedUnit> for UnitKey {
     fn from(unit: &OwnedUnit) -> UnitKey {
-        UnitKey { pkg_id: unit.id, target: unit.target.clone(), mode: unit.mode }
+        UnitKey { pkg_id: unit.id, target: unit.target.clone(), mode: unit.mode.clone() }
     }
 }
 
@@ -577,3 +950,58 @@ impl BuildGraph for CargoPlan {
         CargoPlan::prepare_work(self, files)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn persisted_job() {
+        let mut job = process("rustc");
+        job.args(&["--crate-name", "foo", "--extern", "bar=/deps/libbar.rlib", "src/lib.rs"])
+            .env("CARGO_PKG_NAME", "foo")
+            .env_remove("RUST_LOG")
+            .cwd("/foo");
+
+        let persisted = serde_json::to_string(&PersistedJob::from(&job)).unwrap();
+        let job: ProcessBuilder = serde_json::from_str::<PersistedJob>(&persisted).unwrap().into();
+
+        assert_eq!(job.get_program(), "rustc");
+        assert_eq!(job.get_args().len(), 5);
+        assert_eq!(job.get_envs()["CARGO_PKG_NAME"], Some("foo".into()));
+        assert_eq!(job.get_envs()["RUST_LOG"], None);
+        assert_eq!(job.get_cwd(), Some(Path::new("/foo")));
+        assert_eq!(extern_files(&job), vec![PathBuf::from("/deps/libbar.rlib")]);
+    }
+
+    #[test]
+    fn build_script_directives() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        fs::write(
+            &output,
+            "cargo:rustc-cfg=foo\n\
+             cargo:rerun-if-changed=build.rs\n\
+             cargo:rerun-if-changed=proto\n\
+             cargo:rerun-if-env-changed=RLS_TEST_BUILD_SCRIPT_VAR\n",
+        )
+        .unwrap();
+
+        let mut run = BuildScriptRun::new(output, PathBuf::from("/foo"));
+        assert!(!run.ran_before_changes());
+        run.read_output(&|_| None);
+        assert_eq!(
+            run.rerun_if_changed,
+            vec![PathBuf::from("/foo/build.rs"), PathBuf::from("/foo/proto")]
+        );
+        // The files don't exist (anymore).
+        assert!(run.ran_before_changes());
+
+        assert!(!run.is_stale(&["/foo/src/lib.rs"]));
+        assert!(run.is_stale(&["/foo/build.rs"]));
+        assert!(run.is_stale(&["/foo/proto/foo.proto"]));
+
+        run.rerun_if_env_changed.insert("RLS_TEST_BUILD_SCRIPT_VAR".to_owned(), Some("1".into()));
+        assert!(run.is_stale::<&Path>(&[]));
+    }
+}
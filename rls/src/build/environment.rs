@@ -52,6 +52,14 @@ impl<'a> Environment<'a> {
     pub fn get_old_cwd(&self) -> &Path {
         &self.old_cwd
     }
+
+    /// Returns the value of an env var from before it was pushed, if it was.
+    pub fn get_old_var(&self, key: &str) -> Option<OsString> {
+        match self.old_vars.get(key) {
+            Some(value) => value.clone(),
+            None => env::var_os(key),
+        }
+    }
 }
 
 impl<'a> Drop for Environment<'a> {
@@ -12,15 +12,17 @@
 
 use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::io::BufRead;
-use std::io::Read;
+use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
-use crate::build::plan::{BuildGraph, BuildKey, JobQueue, WorkStatus};
+use crate::build::plan::{BuildGraph, BuildKey, Job, JobQueue, WorkStatus};
 use crate::build::rustc::src_path;
+use crate::build::rustc_args::RustcArgs;
 use crate::build::BuildResult;
 
 use cargo::util::{process, ProcessBuilder};
@@ -89,6 +91,25 @@ pub(super) fn build_with_external_cmd<S: AsRef<str>>(
     (BuildResult::Success(build_dir, vec![], analyses, HashMap::default(), false), plan)
 }
 
+/// Reads a build plan in the format emitted by `cargo build --build-plan` from
+/// `path`, which is relative to the build directory unless it's absolute.
+pub(super) fn read_build_plan(path: &Path, build_dir: &Path) -> Result<ExternalPlan, String> {
+    let path = build_dir.join(path);
+    trace!("external::read_build_plan: Attempt to read `{}`", path.display());
+
+    let file = File::open(&path)
+        .map_err(|e| format!("Couldn't open build plan `{}`: {}", path.display(), e))?;
+    let raw: RawPlan = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| format!("Couldn't parse build plan `{}`: {}", path.display(), e))?;
+    if raw.invocations.is_empty() {
+        return Err(format!("Build plan `{}` has no invocations", path.display()));
+    }
+
+    ExternalPlan::try_from_raw(build_dir, raw).map_err(|()| {
+        format!("Build plan `{}` depends on invocations it doesn't contain", path.display())
+    })
+}
+
 /// Reads and deserializes given save-analysis JSON files into corresponding
 /// `rls_data::Analysis` for each file. If an error is encountered, a `String`
 /// with the error message is returned.
@@ -150,6 +171,7 @@ fn plan_from_analysis(analysis: &[Analysis], build_dir: &Path) -> Result<Externa
                 env: Default::default(),
                 links: Default::default(),
                 cwd: Some(cwd),
+                compile_mode: None,
             })
         })
         .collect::<Result<Vec<RawInvocation>, ()>>()?;
@@ -174,6 +196,10 @@ pub(crate) struct RawInvocation {
     pub(crate) env: BTreeMap<String, String>,
     #[serde(default)]
     pub(crate) cwd: Option<PathBuf>,
+    /// What the invocation does, e.g., `"run-custom-build"` for running a
+    /// build script rather than compiling a crate.
+    #[serde(default)]
+    pub(crate) compile_mode: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -184,6 +210,8 @@ pub(crate) struct Invocation {
     links: BTreeMap<PathBuf, PathBuf>,
     command: ProcessBuilder,
     // Parsed data.
+    /// Arguments passed to rustc, or `None` if this runs a build script.
+    rustc_args: Option<RustcArgs>,
     src_path: Option<PathBuf>,
 }
 
@@ -200,13 +228,16 @@ impl BuildKey for Invocation {
     type Key = u64;
 
     // Invocation key is the hash of the program, its arguments and environment.
+    // Arguments to rustc are normalized, so that keys are the same for
+    // equivalent invocations, even across regenerated build plans.
     fn key(&self) -> u64 {
         let mut hash = DefaultHasher::new();
 
         self.command.get_program().hash(&mut hash);
-        let /*mut*/ args = self.command.get_args().to_owned();
-        // args.sort(); // TODO: parse 2-part args (e.g., `["--extern", "a=b"]`)
-        args.hash(&mut hash);
+        match &self.rustc_args {
+            Some(args) => args.hash(&mut hash),
+            None => self.command.get_args().hash(&mut hash),
+        }
         let mut envs: Vec<_> = self.command.get_envs().iter().collect();
         envs.sort();
         envs.hash(&mut hash);
@@ -230,10 +261,36 @@ impl Invocation {
             deps: raw.deps.to_owned(),
             outputs: raw.outputs.to_owned(),
             links: raw.links,
+            rustc_args: match raw.compile_mode.as_deref() {
+                Some("run-custom-build") => None,
+                _ => Some(RustcArgs::parse(&raw.args)),
+            },
             src_path: guess_rustc_src_path(build_dir, &command),
             command,
         }
     }
+
+    fn job(&self) -> Job {
+        match self.rustc_args {
+            Some(_) => Job::Rustc(self.command.clone()),
+            None => Job::Command(self.command.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Invocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.command.get_program().to_string_lossy())?;
+        match &self.rustc_args {
+            Some(args) => write!(f, " {}", args),
+            None => {
+                for arg in self.command.get_args() {
+                    write!(f, " {}", arg.to_string_lossy())?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 impl ExternalPlan {
@@ -274,6 +331,60 @@ impl ExternalPlan {
 
         Ok(ExternalPlan::with_units(units))
     }
+
+    /// Returns the work needed to build every unit of the plan from scratch.
+    pub(crate) fn prepare_all_work(&self) -> WorkStatus {
+        let topo = self.topological_sort(self.units());
+        self.queue(topo)
+    }
+
+    /// Returns the units which are not in `old`, i.e., which are new or whose
+    /// invocations changed.
+    pub(crate) fn changed_units(&self, old: &ExternalPlan) -> Vec<&Invocation> {
+        self.units.iter().filter(|(key, _)| !old.units.contains_key(key)).map(|(_, u)| u).collect()
+    }
+
+    /// Returns the work needed to build this plan after `old` was built, given
+    /// the `modified` files. Units which didn't change, and whose dependencies
+    /// didn't either, are reused, so there's no work if nothing changed.
+    pub(crate) fn prepare_work_since<T: AsRef<Path>>(
+        &self,
+        old: &ExternalPlan,
+        modified: &[T],
+    ) -> WorkStatus {
+        let changed = self.changed_units(old);
+        for unit in &changed {
+            trace!("external::prepare_work_since: Changed invocation `{}`", unit);
+        }
+
+        let mut dirties = self.dirties(modified);
+        dirties.extend(changed);
+        let topo = self.topological_sort(self.with_rev_deps(dirties));
+        self.queue(topo)
+    }
+
+    /// Returns `units` along with every unit (transitively) depending on them.
+    fn with_rev_deps<'a>(&'a self, mut stack: Vec<&'a Invocation>) -> Vec<&'a Invocation> {
+        let mut results = HashSet::new();
+
+        while let Some(key) = stack.pop().map(BuildKey::key) {
+            if results.insert(key) {
+                if let Some(rdeps) = self.rev_deps.get(&key) {
+                    for rdep in rdeps {
+                        stack.push(&self.units[rdep]);
+                    }
+                }
+            }
+        }
+
+        results.into_iter().map(|key| &self.units[&key]).collect()
+    }
+
+    fn queue(&self, units: Vec<&Invocation>) -> WorkStatus {
+        let units = units.into_iter().map(|unit| (unit.key(), unit.job())).collect();
+
+        WorkStatus::Execute(JobQueue::with_graph(units, &self.rev_deps))
+    }
 }
 
 impl BuildGraph for ExternalPlan {
@@ -361,21 +472,7 @@ impl BuildGraph for ExternalPlan {
     }
 
     fn dirties_transitive<T: AsRef<Path>>(&self, files: &[T]) -> Vec<&Self::Unit> {
-        let mut results = HashSet::new();
-
-        let mut stack = self.dirties(files);
-
-        while let Some(key) = stack.pop().map(BuildKey::key) {
-            if results.insert(key) {
-                if let Some(rdeps) = self.rev_deps.get(&key) {
-                    for rdep in rdeps {
-                        stack.push(&self.units[rdep]);
-                    }
-                }
-            }
-        }
-
-        results.into_iter().map(|key| &self.units[&key]).collect()
+        self.with_rev_deps(self.dirties(files))
     }
 
     fn topological_sort(&self, units: Vec<&Self::Unit>) -> Vec<&Self::Unit> {
@@ -413,9 +510,7 @@ impl BuildGraph for ExternalPlan {
         let dirties = self.dirties_transitive(files);
         let topo = self.topological_sort(dirties);
 
-        let cmds = topo.into_iter().map(|unit| unit.command.clone()).collect();
-
-        WorkStatus::Execute(JobQueue::with_commands(cmds))
+        self.queue(topo)
     }
 }
 
@@ -551,4 +646,78 @@ mod tests {
         let topo_units = plan.topological_sort(units_to_rebuild);
         assert_eq!(paths(&topo_units), to_paths(&["/my/repo/src/lib.rs", "/my/repo/build.rs"]),)
     }
+
+    #[test]
+    fn build_script_runs() {
+        let plan = r#"{"invocations": [
+            { "deps": [],  "program": "rustc", "args": ["--crate-name", "build_script_build", "/my/repo/build.rs"], "env": {}, "outputs": [], "compile_mode": "build" },
+            { "deps": [0], "program": "/my/repo/target/debug/build/repo-1/build-script-build", "args": [], "env": {}, "outputs": [], "compile_mode": "run-custom-build" },
+            { "deps": [1], "program": "rustc", "args": ["--crate-name", "repo", "/my/repo/src/lib.rs"], "env": {}, "outputs": [], "compile_mode": "check" }
+        ]}"#;
+        let build_dir = std::env::temp_dir();
+        let plan = serde_json::from_str::<RawPlan>(&plan).unwrap();
+        let plan = ExternalPlan::try_from_raw(&build_dir, plan).unwrap();
+
+        let build_scripts: Vec<_> = plan
+            .units()
+            .into_iter()
+            .filter(|unit| unit.rustc_args.is_none())
+            .map(|unit| unit.command.get_program().to_owned())
+            .collect();
+        assert_eq!(build_scripts, vec!["/my/repo/target/debug/build/repo-1/build-script-build"]);
+
+        let to_paths = |x| to_paths(&build_dir, x);
+        // Changing the build script reruns it before rebuilding the crate.
+        assert_eq!(plan.dirties_transitive(&to_paths(&["/my/repo/build.rs"])).len(), 3);
+    }
+
+    #[test]
+    fn regenerated_plan_keys() {
+        let old = r#"{"invocations": [
+            { "deps": [],  "program": "rustc", "args": ["--crate-name", "dep", "/my/dep/src/lib.rs"], "env": {}, "outputs": [] },
+            { "deps": [0], "program": "rustc", "args": ["--crate-name", "repo", "--cfg", "a", "--cfg", "b", "--extern", "dep=libdep.rlib", "/my/repo/src/lib.rs"], "env": {}, "outputs": [] }
+        ]}"#;
+        let new = r#"{"invocations": [
+            { "deps": [],  "program": "rustc", "args": ["--crate-name=dep", "/my/dep/src/lib.rs", "-C", "opt-level=1"], "env": {}, "outputs": [] },
+            { "deps": [0], "program": "rustc", "args": ["/my/repo/src/lib.rs", "--extern=dep=libdep.rlib", "--cfg", "b", "--cfg", "a", "--crate-name", "repo"], "env": {}, "outputs": [] }
+        ]}"#;
+        let build_dir = std::env::temp_dir();
+        let old = serde_json::from_str::<RawPlan>(&old).unwrap();
+        let old = ExternalPlan::try_from_raw(&build_dir, old).unwrap();
+        let new = serde_json::from_str::<RawPlan>(&new).unwrap();
+        let new = ExternalPlan::try_from_raw(&build_dir, new).unwrap();
+
+        // Only `dep` changed, as `repo`'s arguments are merely reordered.
+        assert_eq!(paths(&new.changed_units(&old)), to_paths(&build_dir, &["/my/dep/src/lib.rs"]));
+        let repo_src = to_paths(&build_dir, &["/my/repo/src/lib.rs"]).pop();
+        let repo = new.units().into_iter().find(|unit| unit.src_path == repo_src).unwrap();
+        assert_eq!(
+            repo.to_string(),
+            "rustc --crate-name repo --cfg a --cfg b --extern dep=libdep.rlib /my/repo/src/lib.rs"
+        );
+    }
+
+    #[test]
+    fn identical_plan_has_no_work() {
+        let plan = r#"{"invocations": [
+            { "deps": [],  "program": "rustc", "args": ["--crate-name", "dep", "/my/dep/src/lib.rs"], "env": {}, "outputs": [] },
+            { "deps": [0], "program": "rustc", "args": ["--crate-name", "repo", "/my/repo/src/lib.rs"], "env": {}, "outputs": [] }
+        ]}"#;
+        let build_dir = std::env::temp_dir();
+        let read_plan = || {
+            let plan = serde_json::from_str::<RawPlan>(&plan).unwrap();
+            ExternalPlan::try_from_raw(&build_dir, plan).unwrap()
+        };
+        let (old, new) = (read_plan(), read_plan());
+
+        let no_files: &[PathBuf] = &[];
+        match new.prepare_work_since(&old, no_files) {
+            WorkStatus::Execute(queue) => assert!(queue.is_empty()),
+            work => panic!("unexpected work: {:?}", work),
+        }
+        match new.prepare_work_since(&old, &to_paths(&build_dir, &["/my/repo/src/lib.rs"])) {
+            WorkStatus::Execute(queue) => assert!(!queue.is_empty()),
+            work => panic!("unexpected work: {:?}", work),
+        }
+    }
 }
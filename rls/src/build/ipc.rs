@@ -1,5 +1,6 @@
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
@@ -9,8 +10,11 @@ use jsonrpc_core::{ErrorCode, IoHandler};
 
 use crate::build::plan::Crate;
 
-use rls_ipc::rpc::{self, Error, Result as RpcResult};
+use rls_ipc::rpc::callbacks::Server as _;
+use rls_ipc::rpc::file_loader::Server as _;
+use rls_ipc::rpc::{self, CompileJob, Error, JobOutcome, Result as RpcResult};
 use rls_ipc::server::{CloseHandle, ServerBuilder};
+use rls_vfs::Snapshot;
 
 /// An IPC server spawned on a different thread.
 pub struct Server {
@@ -32,19 +36,27 @@ impl Server {
     }
 }
 
-/// Starts an IPC server in the background supporting both VFS requests and data
-/// callbacks used by rustc for the out-of-process compilation.
-pub fn start_with_all(
-    changed_files: HashMap<PathBuf, String>,
-    analysis: Arc<Mutex<Option<rls_data::Analysis>>>,
-    input_files: Arc<Mutex<HashMap<PathBuf, HashSet<Crate>>>>,
-) -> Result<Server, ()> {
-    use rls_ipc::rpc::callbacks::Server as _;
-    use rls_ipc::rpc::file_loader::Server as _;
+/// A compile job for a long-lived worker, along with where to put the data it
+/// hands back.
+pub struct WorkerJob {
+    pub job: CompileJob,
+    pub snapshot: Snapshot,
+    pub analysis: Arc<Mutex<Option<rls_data::Analysis>>>,
+    pub input_files: Arc<Mutex<HashMap<PathBuf, HashSet<Crate>>>>,
+    pub on_diagnostic: Box<dyn Fn(String) + Send + Sync>,
+}
+
+/// Starts an IPC server in the background for a long-lived worker, which runs
+/// the jobs received over `jobs` one after another, supporting both VFS
+/// requests and data callbacks for the current job. The worker is told to exit
+/// once `jobs` is closed.
+pub fn start_worker(jobs: Receiver<(WorkerJob, Sender<JobOutcome>)>) -> Result<Server, ()> {
+    let handler = WorkerHandler { jobs: Arc::new(Mutex::new(jobs)), current: Arc::default() };
 
     let mut io = IoHandler::new();
-    io.extend_with(ChangedFiles(changed_files).to_delegate());
-    io.extend_with(callbacks::CallbackHandler { analysis, input_files }.to_delegate());
+    io.extend_with(rpc::file_loader::Server::to_delegate(handler.clone()));
+    io.extend_with(rpc::callbacks::Server::to_delegate(handler.clone()));
+    io.extend_with(rpc::worker::Server::to_delegate(handler));
 
     self::start_with_handler(io)
 }
@@ -94,7 +106,7 @@ fn rpc_error(msg: &str) -> Error {
 
 mod callbacks {
     use super::PathBuf;
-    use super::{rpc, RpcResult};
+    use super::{rpc, rpc_error, RpcResult};
     use super::{Arc, Mutex};
     use super::{HashMap, HashSet};
 
@@ -115,7 +127,11 @@ mod callbacks {
 
     pub struct CallbackHandler {
         pub analysis: Arc<Mutex<Option<rls_data::Analysis>>>,
+        /// Analysis data which is still being handed back in chunks.
+        pub partial_analysis: Mutex<Option<rls_data::Analysis>>,
         pub input_files: Arc<Mutex<HashMap<PathBuf, HashSet<crate::build::plan::Crate>>>>,
+        /// Called with each diagnostic as soon as rustc emits it.
+        pub on_diagnostic: Box<dyn Fn(String) + Send + Sync>,
     }
 
     impl rpc::callbacks::Rpc for CallbackHandler {
@@ -124,6 +140,37 @@ mod callbacks {
             Ok(())
         }
 
+        fn begin_analysis(&self, header: rls_data::Analysis) -> RpcResult<()> {
+            *self.partial_analysis.lock().unwrap() = Some(header);
+            Ok(())
+        }
+
+        fn analysis_chunk(&self, chunk: rpc::AnalysisChunk) -> RpcResult<()> {
+            match *self.partial_analysis.lock().unwrap() {
+                Some(ref mut analysis) => {
+                    chunk.append_to(analysis);
+                    Ok(())
+                }
+                None => Err(rpc_error("Analysis chunk sent before `begin_analysis`")),
+            }
+        }
+
+        fn end_analysis(&self) -> RpcResult<()> {
+            let analysis = self.partial_analysis.lock().unwrap().take();
+            match analysis {
+                Some(analysis) => {
+                    *self.analysis.lock().unwrap() = Some(analysis);
+                    Ok(())
+                }
+                None => Err(rpc_error("`end_analysis` sent before `begin_analysis`")),
+            }
+        }
+
+        fn diagnostic(&self, diagnostic: String) -> RpcResult<()> {
+            (self.on_diagnostic)(diagnostic);
+            Ok(())
+        }
+
         fn input_files(
             &self,
             input_files: HashMap<PathBuf, HashSet<rls_ipc::rpc::Crate>>,
@@ -137,26 +184,130 @@ mod callbacks {
     }
 }
 
-pub struct ChangedFiles(HashMap<PathBuf, String>);
+/// Serves a long-lived worker with jobs, and its current job with files and
+/// callbacks.
+#[derive(Clone)]
+struct WorkerHandler {
+    jobs: Arc<Mutex<Receiver<(WorkerJob, Sender<JobOutcome>)>>>,
+    current: Arc<Mutex<Option<CurrentJob>>>,
+}
+
+struct CurrentJob {
+    files: ChangedFiles,
+    callbacks: callbacks::CallbackHandler,
+    outcome: Sender<JobOutcome>,
+}
+
+impl WorkerHandler {
+    fn with_current<T>(&self, f: impl FnOnce(&CurrentJob) -> RpcResult<T>) -> RpcResult<T> {
+        match *self.current.lock().unwrap() {
+            Some(ref job) => f(job),
+            None => Err(rpc_error("No compile job is running")),
+        }
+    }
+}
+
+impl rpc::worker::Rpc for WorkerHandler {
+    fn next_job(&self) -> RpcResult<Option<CompileJob>> {
+        // Blocks until there is work for the worker.
+        let (job, outcome) = match self.jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return Ok(None),
+        };
 
-impl rpc::file_loader::Rpc for ChangedFiles {
+        let WorkerJob { job, snapshot, analysis, input_files, on_diagnostic } = job;
+        *self.current.lock().unwrap() = Some(CurrentJob {
+            files: ChangedFiles { snapshot, cwd: job.cwd.clone() },
+            callbacks: callbacks::CallbackHandler {
+                analysis,
+                partial_analysis: Mutex::default(),
+                input_files,
+                on_diagnostic,
+            },
+            outcome,
+        });
+        Ok(Some(job))
+    }
+
+    fn finish_job(&self, outcome: JobOutcome) -> RpcResult<()> {
+        match self.current.lock().unwrap().take() {
+            Some(job) => {
+                // Nobody is waiting if the job has been abandoned.
+                let _ = job.outcome.send(outcome);
+                Ok(())
+            }
+            None => Err(rpc_error("No compile job is running")),
+        }
+    }
+}
+
+impl rpc::file_loader::Rpc for WorkerHandler {
     fn file_exists(&self, path: PathBuf) -> RpcResult<bool> {
-        Ok(fs::metadata(path).is_ok())
+        self.with_current(|job| job.files.file_exists(path))
     }
 
     fn read_file(&self, path: PathBuf) -> RpcResult<String> {
-        if let Some(contents) = abs_path(&path).and_then(|x| self.0.get(&x)) {
-            return Ok(contents.clone());
-        }
+        self.with_current(|job| job.files.read_file(path))
+    }
+}
 
-        fs::read_to_string(path).map_err(|e| rpc_error(&e.to_string()))
+impl rpc::callbacks::Rpc for WorkerHandler {
+    fn complete_analysis(&self, analysis: rls_data::Analysis) -> RpcResult<()> {
+        self.with_current(|job| job.callbacks.complete_analysis(analysis))
+    }
+
+    fn begin_analysis(&self, header: rls_data::Analysis) -> RpcResult<()> {
+        self.with_current(|job| job.callbacks.begin_analysis(header))
+    }
+
+    fn analysis_chunk(&self, chunk: rpc::AnalysisChunk) -> RpcResult<()> {
+        self.with_current(|job| job.callbacks.analysis_chunk(chunk))
+    }
+
+    fn end_analysis(&self) -> RpcResult<()> {
+        self.with_current(|job| job.callbacks.end_analysis())
+    }
+
+    fn diagnostic(&self, diagnostic: String) -> RpcResult<()> {
+        self.with_current(|job| job.callbacks.diagnostic(diagnostic))
+    }
+
+    fn input_files(
+        &self,
+        input_files: HashMap<PathBuf, HashSet<rls_ipc::rpc::Crate>>,
+    ) -> RpcResult<()> {
+        self.with_current(|job| job.callbacks.input_files(input_files))
     }
 }
 
-fn abs_path(path: &Path) -> Option<PathBuf> {
-    if path.is_absolute() {
-        Some(path.to_path_buf())
-    } else {
-        env::current_dir().ok().map(|cwd| cwd.join(path))
+/// Files as the client sees them, for a compilation in `cwd`.
+pub struct ChangedFiles {
+    snapshot: Snapshot,
+    cwd: Option<PathBuf>,
+}
+
+impl ChangedFiles {
+    fn abs_path(&self, path: &Path) -> Option<PathBuf> {
+        if path.is_absolute() {
+            Some(path.to_path_buf())
+        } else {
+            self.cwd.clone().or_else(|| env::current_dir().ok()).map(|cwd| cwd.join(path))
+        }
+    }
+}
+
+impl rpc::file_loader::Rpc for ChangedFiles {
+    fn file_exists(&self, path: PathBuf) -> RpcResult<bool> {
+        let path = self.abs_path(&path).unwrap_or(path);
+        Ok(self.snapshot.contains(&path) || fs::metadata(path).is_ok())
+    }
+
+    fn read_file(&self, path: PathBuf) -> RpcResult<String> {
+        let path = self.abs_path(&path).unwrap_or(path);
+        if let Ok(contents) = self.snapshot.load_file(&path) {
+            return Ok(contents);
+        }
+
+        fs::read_to_string(path).map_err(|e| rpc_error(&e.to_string()))
     }
 }
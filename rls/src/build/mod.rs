@@ -12,7 +12,7 @@ use std::time::{Duration, Instant};
 
 use log::{debug, info, trace};
 use rls_data::Analysis;
-use rls_vfs::Vfs;
+use rls_vfs::{Snapshot, Vfs};
 
 use self::environment::EnvironmentLock;
 use self::plan::{BuildGraph, BuildPlan, WorkStatus};
@@ -30,6 +30,9 @@ mod external;
 mod ipc;
 mod plan;
 mod rustc;
+mod rustc_args;
+#[cfg(feature = "ipc")]
+mod worker;
 
 /// Manages builds.
 ///
@@ -96,7 +99,7 @@ pub enum BuildResult {
     /// Analysis data and list of input files to the compilation.
     /// Final bool is true if and only if compiler's exit code would be 0.
     Success(PathBuf, Vec<String>, Vec<Analysis>, HashMap<PathBuf, HashSet<Crate>>, bool),
-    /// Build was coalesced with another build.
+    /// Build was coalesced with another build, or there was nothing to build.
     Squashed,
     /// There was an error attempting to build.
     /// 0: error cause
@@ -181,7 +184,6 @@ enum Build {
 struct PendingBuild {
     build_dir: PathBuf,
     priority: BuildPriority,
-    built_files: HashMap<PathBuf, FileVersion>,
     notifier: Box<dyn ProgressNotifier>,
     pbh: PostBuildHandler,
 }
@@ -263,13 +265,7 @@ impl BuildQueue {
         if self.internals.compilation_cx.lock().unwrap().needs_rebuild {
             priority = BuildPriority::Cargo;
         }
-        let build = PendingBuild {
-            build_dir: new_build_dir.to_owned(),
-            built_files: self.internals.dirty_files.lock().unwrap().clone(),
-            priority,
-            notifier,
-            pbh,
-        };
+        let build = PendingBuild { build_dir: new_build_dir.to_owned(), priority, notifier, pbh };
 
         let mut queued = self.queued.lock().unwrap();
         Self::push_build(&mut queued, build);
@@ -381,6 +377,8 @@ impl BuildQueue {
 
             // Notifier of window/progress.
             let notifier = build.notifier;
+            // Diagnostics emitted before the build finishes skip the post-build handler.
+            let mut early_diagnostics = build.pbh.early_diagnostics();
 
             // Use this thread to propagate the progress messages until the sender is dropped.
             let progress_thread = thread::Builder::new()
@@ -389,29 +387,29 @@ impl BuildQueue {
                     // Window/progress notification that we are about to build.
                     notifier.notify_begin_progress();
                     while let Ok(progress) = progress_receiver.recv() {
-                        notifier.notify_progress(progress);
+                        match progress {
+                            ProgressUpdate::Diagnostic(cwd, message) => {
+                                early_diagnostics.publish(&cwd, &message)
+                            }
+                            progress => notifier.notify_progress(progress),
+                        }
                     }
                     notifier.notify_end_progress();
                 })
                 .expect("Failed to start progress-notifier thread");
 
-            // Run the build.
-            let result = internals.run_build(
-                &build.build_dir,
-                build.priority,
-                &build.built_files,
-                progress_sender,
-            );
-            // Assert that the build was not squashed.
-            if let BuildResult::Squashed = result {
-                unreachable!();
-            }
+            // Run the build on the files as they are now, edits made from
+            // here on will need another build.
+            let snapshot = internals.vfs.snapshot();
+            let result =
+                internals.run_build(&build.build_dir, build.priority, &snapshot, progress_sender);
 
             let mut pbh = build.pbh;
             {
                 let mut blocked = internals.blocked.lock().unwrap();
                 pbh.blocked_threads.extend(blocked.drain(..));
             }
+            pbh.snapshot = Some(snapshot);
 
             // wait for progress to complete before starting analysis
             progress_thread.join().expect("progress-notifier panicked!");
@@ -457,7 +455,7 @@ impl Internals {
         &self,
         new_build_dir: &Path,
         priority: BuildPriority,
-        built_files: &HashMap<PathBuf, FileVersion>,
+        snapshot: &Snapshot,
         progress_sender: Sender<ProgressUpdate>,
     ) -> BuildResult {
         trace!("run_build, {:?} {:?}", new_build_dir, priority);
@@ -474,16 +472,16 @@ impl Internals {
             compilation_cx.needs_rebuild = priority.is_cargo();
         }
 
-        let result = self.build(progress_sender);
+        let result = self.build(snapshot, progress_sender);
         // On a successful build, clear dirty files that were successfully built
-        // now. It's possible that a build was scheduled with given files, but
-        // user later changed them. These should still be left as dirty (not built).
+        // now. It's possible that the user changed files after we took the
+        // snapshot to build. These should still be left as dirty (not built).
         if let BuildResult::Success(..) = result {
             let mut dirty_files = self.dirty_files.lock().unwrap();
             dirty_files.retain(|file, dirty_version| {
-                built_files
-                    .get(file)
-                    .map(|built_version| built_version < dirty_version)
+                snapshot
+                    .version(file)
+                    .map(|built_version| built_version < *dirty_version)
                     .unwrap_or(false)
             });
             trace!("Files still dirty after the build: {:?}", *dirty_files);
@@ -492,7 +490,7 @@ impl Internals {
     }
 
     // Build the project.
-    fn build(&self, progress_sender: Sender<ProgressUpdate>) -> BuildResult {
+    fn build(&self, snapshot: &Snapshot, progress_sender: Sender<ProgressUpdate>) -> BuildResult {
         trace!("running build");
         let start = Instant::now();
         // When we change build directory (presumably because the IDE is
@@ -513,6 +511,7 @@ impl Internals {
 
         // If the build plan has already been cached, use it, unless Cargo
         // has to be specifically rerun (e.g., when build scripts changed).
+        let mut from_persisted_plan = false;
         let work = {
             let modified: Vec<_> = self.dirty_files.lock().unwrap().keys().cloned().collect();
 
@@ -520,8 +519,13 @@ impl Internals {
             let build_dir = cx.build_dir.clone().unwrap();
             let needs_rebuild = cx.needs_rebuild;
 
+            let (build_command, build_plan) = {
+                let config = self.config.lock().unwrap();
+                (config.build_command.clone(), config.build_plan.clone())
+            };
+
             // Check if an external build command was provided and execute that, instead.
-            if let Some(cmd) = self.config.lock().unwrap().build_command.clone() {
+            if let Some(cmd) = build_command {
                 match (needs_rebuild, &cx.build_plan) {
                     (false, BuildPlan::External(ref plan)) => plan.prepare_work(&modified),
                     // We need to rebuild; regenerate the build plan if possible.
@@ -537,18 +541,57 @@ impl Internals {
                         }
                     },
                 }
+            // Otherwise, run an external build plan if one was provided.
+            } else if let Some(path) = build_plan {
+                match (needs_rebuild, &cx.build_plan) {
+                    (false, BuildPlan::External(ref plan)) => plan.prepare_work(&modified),
+                    // (Re)load the plan, reusing what's unchanged since the last one.
+                    (_, old) => match external::read_build_plan(Path::new(&path), &build_dir) {
+                        Ok(plan) => {
+                            let work = match old {
+                                BuildPlan::External(old) => plan.prepare_work_since(old, &modified),
+                                BuildPlan::Cargo(_) => plan.prepare_all_work(),
+                            };
+                            cx.needs_rebuild = false;
+                            cx.build_plan = BuildPlan::External(plan);
+                            work
+                        }
+                        Err(cause) => return BuildResult::Err(cause, Some(path)),
+                    },
+                }
             // Fall back to Cargo.
             } else {
-                // Cargo plan is recreated and `needs_rebuild` reset if we run `cargo::cargo()`.
-                match cx.build_plan {
-                    BuildPlan::External(_) => WorkStatus::NeedsCargo(PackageArg::Default),
-                    BuildPlan::Cargo(ref plan) => {
-                        match plan.prepare_work(&modified) {
-                            // Don't reuse the plan if we need to rebuild.
-                            WorkStatus::Execute(_) if needs_rebuild => {
-                                WorkStatus::NeedsCargo(PackageArg::Default)
+                // After a restart, reuse the plan persisted by the last Cargo build.
+                let persisted = match cx.build_plan {
+                    BuildPlan::Cargo(ref plan) if needs_rebuild && !plan.is_ready() => {
+                        cargo::load_persisted_plan(&build_dir, &self.config.lock().unwrap())
+                    }
+                    _ => None,
+                };
+                let persisted = persisted.and_then(|(plan, cwd)| match plan.prepare_all_work() {
+                    WorkStatus::Execute(job_queue) => Some((plan, cwd, job_queue)),
+                    WorkStatus::NeedsCargo(_) => None,
+                });
+
+                if let Some((plan, cwd, job_queue)) = persisted {
+                    trace!("reusing persisted build plan");
+                    from_persisted_plan = true;
+                    cx.needs_rebuild = false;
+                    cx.cwd = cwd;
+                    cx.build_plan = BuildPlan::Cargo(plan);
+                    WorkStatus::Execute(job_queue)
+                } else {
+                    // Cargo plan is recreated and `needs_rebuild` reset if we run `cargo::cargo()`.
+                    match cx.build_plan {
+                        BuildPlan::External(_) => WorkStatus::NeedsCargo(PackageArg::Default),
+                        BuildPlan::Cargo(ref plan) => {
+                            match plan.prepare_work(&modified) {
+                                // Don't reuse the plan if we need to rebuild.
+                                WorkStatus::Execute(_) if needs_rebuild => {
+                                    WorkStatus::NeedsCargo(PackageArg::Default)
+                                }
+                                work => work,
                             }
-                            work => work,
                         }
                     }
                 }
@@ -557,8 +600,24 @@ impl Internals {
         trace!("specified work: {:#?}", work);
 
         let result = match work {
-            WorkStatus::NeedsCargo(package_arg) => cargo::cargo(self, package_arg, progress_sender),
-            WorkStatus::Execute(job_queue) => job_queue.execute(self, progress_sender),
+            WorkStatus::NeedsCargo(package_arg) => {
+                cargo::cargo(self, package_arg, snapshot, progress_sender)
+            }
+            WorkStatus::Execute(job_queue) => {
+                match job_queue.execute(self, snapshot, progress_sender.clone()) {
+                    // The persisted plan may be out of date in ways its
+                    // fingerprint doesn't cover (e.g., a changed build
+                    // script), so let Cargo have a go.
+                    BuildResult::Success(.., false) | BuildResult::Err(..)
+                        if from_persisted_plan =>
+                    {
+                        info!("build from the persisted plan failed, running Cargo");
+                        self.compilation_cx.lock().unwrap().needs_rebuild = true;
+                        cargo::cargo(self, PackageArg::Default, snapshot, progress_sender)
+                    }
+                    result => result,
+                }
+            }
         };
 
         if let BuildResult::Success(.., true) = result {
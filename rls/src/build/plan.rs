@@ -10,10 +10,12 @@ use std::ffi::OsStr;
 use std::hash::Hash;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use cargo::util::ProcessBuilder;
+use lazy_static::lazy_static;
 use log::trace;
+use rls_vfs::Snapshot;
 use serde::{Deserialize, Serialize};
 
 use crate::actions::progress::ProgressUpdate;
@@ -74,8 +76,31 @@ impl BuildPlan {
     }
 }
 
+/// Cached compiler invocations to rebuild, as a stack whose last element is
+/// the first one to run. Each job runs once the jobs it depends on have
+/// finished, concurrently with other jobs if rustc is run out of process.
 #[derive(Debug)]
-pub(crate) struct JobQueue(Vec<ProcessBuilder>);
+pub(crate) struct JobQueue {
+    jobs: Vec<Job>,
+    /// Indices of the jobs which each job depends on.
+    deps: Vec<Vec<usize>>,
+}
+
+/// A job in a `JobQueue`.
+#[derive(Debug)]
+pub(crate) enum Job {
+    /// A rustc invocation, which we run ourselves to retrieve diagnostics and
+    /// analysis data.
+    Rustc(ProcessBuilder),
+    /// Any other command, e.g., running a build script, which is run as is.
+    Command(ProcessBuilder),
+}
+
+impl From<ProcessBuilder> for Job {
+    fn from(job: ProcessBuilder) -> Job {
+        Job::Rustc(job)
+    }
+}
 
 /// Returns an immediately next argument to the one specified in a given
 /// ProcessBuilder (or `None` if the searched or the next argument could not be found).
@@ -91,97 +116,285 @@ fn proc_argument_value<T: AsRef<OsStr>>(prc: &ProcessBuilder, key: T) -> Option<
 }
 
 impl JobQueue {
-    pub(crate) fn with_commands(jobs: Vec<ProcessBuilder>) -> JobQueue {
-        JobQueue(jobs)
+    /// Creates a queue from topologically sorted units and their commands,
+    /// where `rev_deps` maps units to the units which depend on them.
+    pub(crate) fn with_graph<K: Eq + Hash, J: Into<Job>>(
+        units: Vec<(K, J)>,
+        rev_deps: &HashMap<K, HashSet<K>>,
+    ) -> JobQueue {
+        let indices: HashMap<&K, usize> =
+            units.iter().enumerate().map(|(idx, (key, _))| (key, idx)).collect();
+
+        let mut deps = vec![vec![]; units.len()];
+        for (idx, (key, _)) in units.iter().enumerate() {
+            for rev_dep in rev_deps.get(key).into_iter().flatten() {
+                if let Some(&rev_dep) = indices.get(rev_dep) {
+                    deps[rev_dep].push(idx);
+                }
+            }
+        }
+
+        let jobs = units.into_iter().map(|(_, job)| job.into()).collect();
+        JobQueue { jobs, deps }
     }
 
-    pub(crate) fn dequeue(&mut self) -> Option<ProcessBuilder> {
-        self.0.pop()
+    pub(crate) fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
     }
 
     /// Performs a rustc build using cached compiler invocations.
     pub(super) fn execute(
-        mut self,
+        self,
         internals: &Internals,
+        snapshot: &Snapshot,
         progress_sender: Sender<ProgressUpdate>,
     ) -> BuildResult {
-        // TODO: In case of an empty job queue we shouldn't be here, since the
-        // returned results will replace currently held diagnostics/analyses.
-        // Either allow to return a BuildResult::Squashed here or just delegate
-        // to Cargo (which we do currently) in `prepare_work`
-        assert!(!self.0.is_empty());
+        // Returning results would replace the currently held diagnostics and
+        // analyses, which are still up to date if there's nothing to rebuild.
+        if self.jobs.is_empty() {
+            return BuildResult::Squashed;
+        }
 
-        let mut compiler_messages = vec![];
-        let mut analyses = vec![];
-        let mut input_files = HashMap::<_, HashSet<_>>::new();
-        let (build_dir, mut cwd) = {
+        let (build_dir, cwd) = {
             let comp_cx = internals.compilation_cx.lock().unwrap();
             (comp_cx.build_dir.clone().expect("no build directory"), comp_cx.cwd.clone())
         };
+        let (max_jobs, parallel) = {
+            let config = internals.config.lock().unwrap();
+            let max_jobs = config.jobs.map_or_else(num_cpus::get, |jobs| jobs as usize).max(1);
+            (max_jobs, config.parallel_rustc)
+        };
+        // Compiling in process holds the environment lock for the whole
+        // compilation, so jobs can only run in parallel out of process.
+        let out_of_process =
+            cfg!(feature = "ipc") && parallel && max_jobs > 1 && self.jobs.len() > 1;
+        let pool = match build_pool(max_jobs) {
+            Ok(pool) => pool,
+            Err(e) => {
+                return BuildResult::Err(format!("Couldn't start build threads: {}", e), None)
+            }
+        };
 
-        // Go through cached compiler invocations sequentially, collecting each
-        // invocation's compiler messages for diagnostics and analysis data
-        while let Some(job) = self.dequeue() {
-            trace!("Executing: {:#?}", job);
-            let mut args: Vec<_> = job
-                .get_args()
-                .iter()
-                .cloned()
-                .map(|x| x.into_string().expect("cannot stringify job args"))
-                .collect();
-
-            let program =
-                job.get_program().clone().into_string().expect("cannot stringify job program");
-            args.insert(0, program.clone());
-
-            // Needed to parse rustc diagnostics
-            if args.iter().find(|x| x.as_str() == "--error-format=json").is_none() {
-                args.push("--error-format=json".to_owned());
+        let mut rev_deps = vec![vec![]; self.jobs.len()];
+        for (idx, deps) in self.deps.iter().enumerate() {
+            for &dep in deps {
+                rev_deps[dep].push(idx);
             }
+        }
+        let scheduler = Scheduler {
+            queue: &self,
+            rev_deps,
+            out_of_process,
+            internals,
+            snapshot,
+            build_dir,
+            cwd,
+            progress_sender: Mutex::new(progress_sender),
+            state: Mutex::new(SchedulerState {
+                results: self.jobs.iter().map(|_| None).collect(),
+                pending_deps: self.deps.iter().map(Vec::len).collect(),
+                failed: false,
+                finished: 0,
+            }),
+        };
+
+        // Jobs spawn the jobs depending on them once they're finished.
+        pool.scope(|scope| {
+            for idx in (0..self.jobs.len()).rev().filter(|&idx| self.deps[idx].is_empty()) {
+                let scheduler = &scheduler;
+                scope.spawn(move |scope| scheduler.run(scope, idx));
+            }
+        });
+
+        scheduler.merge_results()
+    }
+}
+
+lazy_static! {
+    /// Threads which run the jobs of a `JobQueue`, along with their number.
+    static ref BUILD_POOL: Mutex<Option<(usize, Arc<rayon::ThreadPool>)>> = Mutex::default();
+}
+
+/// Returns the threads to run builds on, which are kept across builds unless
+/// the number of jobs changes.
+fn build_pool(num_threads: usize) -> Result<Arc<rayon::ThreadPool>, rayon::ThreadPoolBuildError> {
+    let mut pool = BUILD_POOL.lock().unwrap();
+    if let Some((len, pool)) = &*pool {
+        if *len == num_threads {
+            return Ok(Arc::clone(pool));
+        }
+    }
+
+    let new_pool = Arc::new(
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|idx| format!("rls-build-{}", idx))
+            .build()?,
+    );
+    *pool = Some((num_threads, Arc::clone(&new_pool)));
+    Ok(new_pool)
+}
+
+/// Runs the jobs of a `JobQueue`, as soon as their dependencies are built.
+struct Scheduler<'a> {
+    queue: &'a JobQueue,
+    /// Indices of the jobs which depend on each job.
+    rev_deps: Vec<Vec<usize>>,
+    /// Whether to compile out of process, so that jobs run in parallel.
+    out_of_process: bool,
+    internals: &'a Internals,
+    snapshot: &'a Snapshot,
+    build_dir: PathBuf,
+    cwd: Option<PathBuf>,
+    progress_sender: Mutex<Sender<ProgressUpdate>>,
+    state: Mutex<SchedulerState>,
+}
+
+struct SchedulerState {
+    results: Vec<Option<BuildResult>>,
+    /// Number of unfinished dependencies of each job.
+    pending_deps: Vec<usize>,
+    /// Set once a job failed, after which no more jobs are started.
+    failed: bool,
+    finished: usize,
+}
 
-            if args.iter().find(|x| x.as_str() == "--sysroot").is_none() {
-                let sysroot = super::rustc::current_sysroot()
-                    .expect("need to specify SYSROOT env var or use rustup or multirust");
+impl<'a> Scheduler<'a> {
+    fn run<'s>(&'s self, scope: &rayon::Scope<'s>, idx: usize) {
+        if self.state.lock().unwrap().failed {
+            return;
+        }
+
+        let result = self.compile(idx);
 
-                let config = internals.config.lock().unwrap();
-                if config.sysroot.is_none() {
-                    args.push("--sysroot".to_owned());
-                    args.push(sysroot);
+        let ready = {
+            let mut state = self.state.lock().unwrap();
+            match result {
+                BuildResult::Success(.., true) => {}
+                _ => state.failed = true,
+            }
+            state.results[idx] = Some(result);
+            state.finished += 1;
+
+            let mut ready = vec![];
+            for &rev_dep in &self.rev_deps[idx] {
+                state.pending_deps[rev_dep] -= 1;
+                if state.pending_deps[rev_dep] == 0 {
+                    ready.push(rev_dep);
                 }
             }
+            ready
+        };
 
-            // Send a window/progress notification.
-            {
-                let crate_name = proc_argument_value(&job, "--crate-name").and_then(OsStr::to_str);
-                let update = match crate_name {
-                    Some(name) => {
-                        let cfg_test = job.get_args().iter().any(|arg| arg == "--test");
-                        ProgressUpdate::Message(if cfg_test {
-                            format!("{} cfg(test)", name)
-                        } else {
-                            name.to_owned()
-                        })
-                    }
-                    None => {
-                        // divide by zero is avoided by earlier assert!
-                        let percentage = compiler_messages.len() as f64 / self.0.len() as f64;
-                        ProgressUpdate::Percentage(percentage)
-                    }
-                };
+        for rev_dep in ready {
+            scope.spawn(move |scope| self.run(scope, rev_dep));
+        }
+    }
+
+    fn compile(&self, idx: usize) -> BuildResult {
+        let job = match &self.queue.jobs[idx] {
+            Job::Rustc(job) => job,
+            Job::Command(job) => return self.run_command(job),
+        };
+        trace!("Executing: {:#?}", job);
+        let mut args: Vec<_> = job
+            .get_args()
+            .iter()
+            .cloned()
+            .map(|x| x.into_string().expect("cannot stringify job args"))
+            .collect();
+
+        let program =
+            job.get_program().clone().into_string().expect("cannot stringify job program");
+        args.insert(0, program.clone());
+
+        // Needed to parse rustc diagnostics
+        if args.iter().find(|x| x.as_str() == "--error-format=json").is_none() {
+            args.push("--error-format=json".to_owned());
+        }
+
+        if args.iter().find(|x| x.as_str() == "--sysroot").is_none() {
+            let sysroot = super::rustc::current_sysroot()
+                .expect("need to specify SYSROOT env var or use rustup or multirust");
+
+            let config = self.internals.config.lock().unwrap();
+            if config.sysroot.is_none() {
+                args.push("--sysroot".to_owned());
+                args.push(sysroot);
+            }
+        }
+
+        // Send a window/progress notification.
+        let progress_sender = self.progress_sender.lock().unwrap().clone();
+        {
+            let crate_name = proc_argument_value(job, "--crate-name").and_then(OsStr::to_str);
+            let update = match crate_name {
+                Some(name) => {
+                    let cfg_test = job.get_args().iter().any(|arg| arg == "--test");
+                    ProgressUpdate::Message(if cfg_test {
+                        format!("{} cfg(test)", name)
+                    } else {
+                        name.to_owned()
+                    })
+                }
+                None => {
+                    // divide by zero is avoided by the earlier check
+                    let finished = self.state.lock().unwrap().finished;
+                    ProgressUpdate::Percentage(finished as f64 / self.queue.jobs.len() as f64)
+                }
+            };
 
-                progress_sender.send(update).expect("Failed to send progress update");
+            progress_sender.send(update).expect("Failed to send progress update");
+        }
+
+        match super::rustc::rustc(
+            self.snapshot,
+            &args,
+            job.get_envs(),
+            job.get_cwd().or_else(|| self.cwd.as_deref()),
+            &self.build_dir,
+            Arc::clone(&self.internals.config),
+            &self.internals.env_lock.as_facade(),
+            &progress_sender,
+            self.out_of_process,
+        ) {
+            BuildResult::Err(cause, _) => {
+                let cmd = format!("{} {}", program, args.join(" "));
+                BuildResult::Err(cause, Some(cmd))
             }
+            result => result,
+        }
+    }
+
+    fn run_command(&self, job: &ProcessBuilder) -> BuildResult {
+        trace!("Running: {:#?}", job);
+        let mut job = job.clone();
+        let cwd = match job.get_cwd().or_else(|| self.cwd.as_deref()) {
+            Some(cwd) => cwd.to_owned(),
+            None => PathBuf::from("."),
+        };
+        job.cwd(&cwd);
+
+        match job.exec_with_output() {
+            Ok(_) => BuildResult::Success(cwd, vec![], vec![], HashMap::new(), true),
+            Err(e) => BuildResult::Err(format!("{:?}", e), Some(job.to_string())),
+        }
+    }
 
-            match super::rustc::rustc(
-                &internals.vfs,
-                &args,
-                job.get_envs(),
-                job.get_cwd().or_else(|| cwd.as_deref()),
-                &build_dir,
-                Arc::clone(&internals.config),
-                &internals.env_lock.as_facade(),
-            ) {
-                BuildResult::Success(c, mut messages, mut analysis, files, success) => {
+    /// Merges the results of the finished jobs in the order of the queue, so
+    /// that they don't depend on which jobs happened to finish first.
+    fn merge_results(self) -> BuildResult {
+        let mut compiler_messages = vec![];
+        let mut analyses = vec![];
+        let mut input_files = HashMap::<_, HashSet<_>>::new();
+        let mut cwd = self.cwd;
+
+        let state = self.state.into_inner().unwrap();
+        let complete = state.finished == state.results.len();
+        // The queue is a stack, so its last job ran first.
+        for result in state.results.into_iter().rev().flatten() {
+            match result {
+                BuildResult::Success(c, mut messages, mut analysis, files, _) => {
                     compiler_messages.append(&mut messages);
                     analyses.append(&mut analysis);
                     for (file, inputs) in files {
@@ -189,33 +402,20 @@ impl JobQueue {
                     }
 
                     cwd = Some(c);
-
-                    // This compilation failed, but the build as a whole does not
-                    // need to error out.
-                    if !success {
-                        return BuildResult::Success(
-                            cwd.unwrap(),
-                            compiler_messages,
-                            analyses,
-                            input_files,
-                            false,
-                        );
-                    }
-                }
-                BuildResult::Err(cause, _) => {
-                    let cmd = format!("{} {}", program, args.join(" "));
-                    return BuildResult::Err(cause, Some(cmd));
                 }
+                BuildResult::Err(cause, cmd) => return BuildResult::Err(cause, cmd),
                 _ => {}
             }
         }
 
+        // A failed compilation stops the build, but the build as a whole does
+        // not need to error out.
         BuildResult::Success(
             cwd.unwrap_or_else(|| PathBuf::from(".")),
             compiler_messages,
             analyses,
             input_files,
-            true,
+            complete && !state.failed,
         )
     }
 }
@@ -257,3 +457,27 @@ impl std::convert::TryFrom<&str> for Edition {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cargo::util::process;
+
+    #[test]
+    fn job_queue_deps() {
+        // `lib` is used by `a` and `b`, which are both used by `bin`.
+        let units = vec!["bin", "b", "a", "lib"].into_iter().map(|name| (name, process(name)));
+        let rev_deps: HashMap<_, HashSet<_>> = vec![
+            ("lib", vec!["a", "b"]),
+            ("a", vec!["bin"]),
+            ("b", vec!["bin"]),
+            ("not-queued", vec!["bin"]),
+        ]
+        .into_iter()
+        .map(|(key, rev_deps)| (key, rev_deps.into_iter().collect()))
+        .collect();
+
+        let queue = JobQueue::with_graph(units.collect(), &rev_deps);
+        assert_eq!(queue.deps, vec![vec![1, 2], vec![3], vec![3], vec![]]);
+    }
+}
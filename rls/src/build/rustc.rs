@@ -13,11 +13,15 @@ use std::io;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, PoisonError};
 
 use log::trace;
 use rls_data::Analysis;
-use rls_vfs::Vfs;
+#[cfg(feature = "ipc")]
+use rls_ipc::rpc::CompileJob;
+use rls_vfs::Snapshot;
 
 use self::rustc_driver::{Compilation, RunCompiler};
 use self::rustc_interface::interface;
@@ -28,20 +32,31 @@ use self::rustc_session::config::Input;
 use self::rustc_session::Session;
 use self::rustc_span::edition::Edition as RustcEdition;
 use self::rustc_span::source_map::{FileLoader, RealFileLoader};
+use crate::actions::progress::ProgressUpdate;
 use crate::build::environment::{Environment, EnvironmentLockFacade};
 use crate::build::plan::{Crate, Edition};
 use crate::build::{BufWriter, BuildResult};
 use crate::config::{ClippyPreference, Config};
 
-// Runs a single instance of Rustc.
+/// Set once rustc crashed in process. From then on, we compile out of process
+/// so that a crash can't take down the RLS, e.g., with a stack overflow, which
+/// can't be caught.
+static CRASHED_IN_PROCESS: AtomicBool = AtomicBool::new(false);
+
+// Runs a single instance of Rustc. Compiles out of process if `out_of_process`
+// is set, e.g., so that it can run in parallel with other compilations, since
+// compiling in process holds the environment lock throughout.
+#[cfg_attr(not(feature = "ipc"), allow(unused_variables))]
 pub(crate) fn rustc(
-    vfs: &Vfs,
+    snapshot: &Snapshot,
     args: &[String],
     envs: &BTreeMap<String, Option<OsString>>,
     cwd: Option<&Path>,
     build_dir: &Path,
     rls_config: Arc<Mutex<Config>>,
     env_lock: &EnvironmentLockFacade,
+    progress_sender: &Sender<ProgressUpdate>,
+    out_of_process: bool,
 ) -> BuildResult {
     trace!(
         "rustc - args: `{:?}`, envs: {:?}, cwd: {:?}, build dir: {:?}",
@@ -51,17 +66,23 @@ pub(crate) fn rustc(
         build_dir
     );
 
-    let changed = vfs.get_cached_files();
-
     let mut envs = envs.clone();
 
-    let clippy_preference = {
+    // Save-analysis doesn't emit item signatures by default, however we need
+    // them for the crates being edited to provide signature help.
+    envs.entry(String::from("RUST_SAVE_ANALYSIS_CONFIG")).or_insert_with(|| {
+        let config = rls_data::config::Config { signatures: true, ..Default::default() };
+        serde_json::to_string(&config).ok().map(OsString::from)
+    });
+
+    let (clippy_preference, max_workers) = {
         let config = rls_config.lock().unwrap();
         if config.clear_env_rust_log {
             envs.insert(String::from("RUST_LOG"), None);
         }
 
-        config.clippy_preference
+        let max_workers = config.jobs.map_or_else(num_cpus::get, |jobs| jobs as usize);
+        (config.clippy_preference, max_workers)
     };
 
     let lock_environment = |envs, cwd| {
@@ -69,21 +90,45 @@ pub(crate) fn rustc(
         Environment::push_with_lock(envs, cwd, guard)
     };
 
-    let CompilationResult { result, stderr, analysis, input_files } = match std::env::var(
-        "RLS_OUT_OF_PROCESS",
-    ) {
-        #[cfg(feature = "ipc")]
-        Ok(..) => run_out_of_process(changed.clone(), &args, &envs, clippy_preference)
+    let out_of_process = out_of_process
+        || std::env::var("RLS_OUT_OF_PROCESS").is_ok()
+        || CRASHED_IN_PROCESS.load(Ordering::SeqCst);
+    let CompilationResult { result, mut stderr, analysis, input_files, crashed } =
+        match out_of_process {
+            #[cfg(feature = "ipc")]
+            true => run_out_of_process(
+                snapshot,
+                &args,
+                &envs,
+                cwd,
+                clippy_preference,
+                max_workers,
+                progress_sender,
+            )
             .unwrap_or_else(|_| {
-                run_in_process(changed, &args, clippy_preference, lock_environment(&envs, cwd))
+                run_in_process(snapshot, &args, clippy_preference, lock_environment(&envs, cwd))
             }),
-        #[cfg(not(feature = "ipc"))]
-        Ok(..) => {
-            log::warn!("Support for out-of-process compilation was not compiled. Rebuild with 'ipc' feature enabled");
-            run_in_process(changed, &args, clippy_preference, lock_environment(&envs, cwd))
+            #[cfg(not(feature = "ipc"))]
+            true => {
+                log::warn!("Support for out-of-process compilation was not compiled. Rebuild with 'ipc' feature enabled");
+                run_in_process(snapshot, &args, clippy_preference, lock_environment(&envs, cwd))
+            }
+            false => {
+                run_in_process(snapshot, &args, clippy_preference, lock_environment(&envs, cwd))
+            }
+        };
+
+    if crashed {
+        log::warn!("rustc crashed, args: `{:?}`", args);
+        if let Some(diagnostic) = crash_diagnostic(args) {
+            stderr.extend(diagnostic.into_bytes());
         }
-        Err(..) => run_in_process(changed, &args, clippy_preference, lock_environment(&envs, cwd)),
-    };
+        // Only out-of-process compilation can survive the next crash.
+        if cfg!(feature = "ipc") && !out_of_process {
+            log::warn!("Compiling out of process from now on");
+            CRASHED_IN_PROCESS.store(true, Ordering::SeqCst);
+        }
+    }
 
     let stderr = String::from_utf8(stderr).unwrap();
     log::debug!("rustc - stderr: {}", &stderr);
@@ -97,6 +142,43 @@ pub(crate) fn rustc(
     BuildResult::Success(cwd, stderr_json_msgs, analysis, input_files, result.is_ok())
 }
 
+/// Reports that rustc crashed as a diagnostic in rustc's JSON format, since
+/// rustc doesn't emit one itself. It points at the crate root, as we don't
+/// know where rustc crashed.
+fn crash_diagnostic(args: &[String]) -> Option<String> {
+    let arg_value = |name| args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1));
+    let crate_name = arg_value("--crate-name").map_or("<unknown>", String::as_str);
+    let src_path = args.iter().skip(1).find(|arg| arg.ends_with(".rs"))?;
+
+    let message = format!(
+        "internal compiler error while compiling crate `{}`\n\ncommand line: {}",
+        crate_name,
+        args.join(" ")
+    );
+    let diagnostic = serde_json::json!({
+        "message": message,
+        "code": null,
+        "level": "error",
+        "spans": [{
+            "file_name": src_path,
+            "byte_start": 0,
+            "byte_end": 0,
+            "line_start": 1,
+            "line_end": 1,
+            "column_start": 1,
+            "column_end": 1,
+            "is_primary": true,
+            "text": [],
+            "label": null,
+            "suggested_replacement": null,
+            "expansion": null,
+        }],
+        "children": [],
+        "rendered": null,
+    });
+    Some(format!("\n{}\n", diagnostic))
+}
+
 /// Resulting data from compiling a crate (in the rustc sense)
 pub struct CompilationResult {
     /// Whether compilation was succesful
@@ -105,54 +187,68 @@ pub struct CompilationResult {
     analysis: Option<Analysis>,
     // TODO: Move to Vec<PathBuf>
     input_files: HashMap<PathBuf, HashSet<Crate>>,
+    /// Whether rustc crashed, i.e., hit an internal compiler error
+    crashed: bool,
 }
 
 #[cfg(feature = "ipc")]
 fn run_out_of_process(
-    changed: HashMap<PathBuf, String>,
+    snapshot: &Snapshot,
     args: &[String],
     envs: &BTreeMap<String, Option<OsString>>,
+    cwd: Option<&Path>,
     clippy_preference: ClippyPreference,
+    max_workers: usize,
+    progress_sender: &Sender<ProgressUpdate>,
 ) -> Result<CompilationResult, ()> {
     let analysis = Arc::default();
     let input_files = Arc::default();
-
-    let ipc_server =
-        super::ipc::start_with_all(changed, Arc::clone(&analysis), Arc::clone(&input_files))?;
-
-    // Compiling out of process is only supported by our own shim
-    let rustc_shim = env::current_exe()
-        .ok()
-        .and_then(|x| x.to_str().map(String::from))
-        .expect("Couldn't set executable for RLS rustc shim");
-
-    let output = Command::new(rustc_shim)
-        .env(crate::RUSTC_SHIM_ENV_VAR_NAME, "1")
-        .env("RLS_IPC_ENDPOINT", ipc_server.endpoint())
-        .env("RLS_CLIPPY_PREFERENCE", clippy_preference.to_string())
-        .args(args.iter().skip(1))
-        .envs(envs.iter().filter_map(|(k, v)| v.as_ref().map(|v| (k, v))))
-        .output()
-        .map_err(|_| ());
-
-    let result = match &output {
-        Ok(output) if output.status.code() == Some(0) => Ok(()),
-        _ => Err(()),
+    let on_diagnostic = {
+        let cwd = cwd.unwrap_or_else(|| Path::new(".")).to_owned();
+        let progress_sender = Mutex::new(progress_sender.clone());
+        move |diagnostic| {
+            let update = ProgressUpdate::Diagnostic(cwd.clone(), diagnostic);
+            // Nobody is listening if the build has been abandoned.
+            let _ = progress_sender.lock().unwrap().send(update);
+        }
     };
+
+    let mut envs: BTreeMap<_, _> = envs
+        .iter()
+        .map(|(k, v)| (k.clone(), v.as_ref().map(|v| v.to_string_lossy().into_owned())))
+        .collect();
+    envs.insert("RLS_CLIPPY_PREFERENCE".to_owned(), Some(clippy_preference.to_string()));
     // NOTE: Make sure that we pass JSON error format
-    let stderr = output.map(|out| out.stderr).unwrap_or_default();
+    let job = super::ipc::WorkerJob {
+        job: CompileJob {
+            args: args.iter().skip(1).cloned().collect(),
+            envs,
+            cwd: cwd.map(Path::to_owned),
+        },
+        snapshot: snapshot.clone(),
+        analysis: Arc::clone(&analysis),
+        input_files: Arc::clone(&input_files),
+        on_diagnostic: Box::new(on_diagnostic),
+    };
 
-    ipc_server.close();
+    // The worker may have crashed before handing back any diagnostics.
+    let (result, stderr, crashed) =
+        match super::worker::WorkerPool::get().compile(job, max_workers)? {
+            Some(outcome) if outcome.success => (Ok(()), outcome.stderr, false),
+            Some(outcome) => (Err(()), outcome.stderr, outcome.crashed),
+            None => (Err(()), String::new(), true),
+        };
 
-    let input_files = unwrap_shared(input_files, "Other ref dropped by closed IPC server");
-    let analysis = unwrap_shared(analysis, "Other ref dropped by closed IPC server");
+    // The worker may still hold on to these if it crashed.
+    let input_files = mem::take(&mut *input_files.lock().unwrap());
+    let analysis = analysis.lock().unwrap().take();
     // FIXME(#25): given that we are running the compiler directly, there is no need
     // to serialize the error messages -- we should pass them in memory.
-    Ok(CompilationResult { result, stderr, analysis, input_files })
+    Ok(CompilationResult { result, stderr: stderr.into_bytes(), analysis, input_files, crashed })
 }
 
 fn run_in_process(
-    changed: HashMap<PathBuf, String>,
+    snapshot: &Snapshot,
     args: &[String],
     clippy_preference: ClippyPreference,
     environment_lock: Environment<'_>,
@@ -176,10 +272,8 @@ fn run_in_process(
         args.to_owned()
     };
 
-    // rustc explicitly panics in `run_compiler()` on compile failure, regardless
-    // of whether it encounters an ICE (internal compiler error) or not.
-    // TODO: Change librustc_driver behaviour to distinguish between ICEs and
-    // regular compilation failure with errors?
+    // Fatal compile errors are caught by `catch_fatal_errors()`, so any other
+    // panic is an ICE (internal compiler error).
     let stderr = Arc::default();
     let result = std::panic::catch_unwind({
         let stderr = Arc::clone(&stderr);
@@ -187,15 +281,15 @@ fn run_in_process(
             rustc_driver::catch_fatal_errors(move || {
                 let mut compiler = RunCompiler::new(&args, &mut callbacks);
                 compiler
-                    .set_file_loader(Some(Box::new(ReplacedFileLoader::new(changed))))
+                    .set_file_loader(Some(Box::new(ReplacedFileLoader::new(snapshot.clone()))))
                     // Replace stderr so we catch most errors.
                     .set_emitter(Some(Box::new(BufWriter(stderr))));
                 compiler.run()
             })
         }
-    })
-    .map(|_| ())
-    .map_err(|_| ());
+    });
+    let crashed = result.is_err();
+    let result = result.map(|_| ()).map_err(|_| ());
     // Explicitly drop the global environment lock
     mem::drop(environment_lock);
 
@@ -203,7 +297,7 @@ fn run_in_process(
     let input_files = unwrap_shared(input_files, "Other ref dropped by scoped compilation");
     let analysis = unwrap_shared(analysis, "Other ref dropped by scoped compilation");
 
-    CompilationResult { result, stderr, analysis, input_files }
+    CompilationResult { result, stderr, analysis, input_files, crashed }
 }
 
 // Our compiler controller. We mostly delegate to the default rustc
@@ -339,15 +433,15 @@ fn fetch_input_files(sess: &Session) -> Vec<PathBuf> {
         .collect()
 }
 
-/// Tries to read a file from a list of replacements, and if the file is not
+/// Tries to read a file from a snapshot of the VFS, and if the file is not
 /// there, then reads it from disk, by delegating to `RealFileLoader`.
 struct ReplacedFileLoader {
-    replacements: HashMap<PathBuf, String>,
+    replacements: Snapshot,
     real_file_loader: RealFileLoader,
 }
 
 impl ReplacedFileLoader {
-    fn new(replacements: HashMap<PathBuf, String>) -> ReplacedFileLoader {
+    fn new(replacements: Snapshot) -> ReplacedFileLoader {
         ReplacedFileLoader { replacements, real_file_loader: RealFileLoader }
     }
 }
@@ -358,8 +452,8 @@ impl FileLoader for ReplacedFileLoader {
     }
 
     fn read_file(&self, path: &Path) -> io::Result<String> {
-        if let Some(contents) = abs_path(path).and_then(|x| self.replacements.get(&x)) {
-            return Ok(contents.clone());
+        if let Some(contents) = abs_path(path).and_then(|x| self.replacements.load_file(&x).ok()) {
+            return Ok(contents);
         }
 
         self.real_file_loader.read_file(path)
@@ -404,5 +498,35 @@ pub fn src_path(cwd: Option<&Path>, path: impl AsRef<Path>) -> Option<PathBuf> {
 }
 
 fn unwrap_shared<T: std::fmt::Debug>(shared: Arc<Mutex<T>>, msg: &'static str) -> T {
-    Arc::try_unwrap(shared).expect(msg).into_inner().unwrap()
+    // The lock is poisoned if rustc crashed while holding it.
+    Arc::try_unwrap(shared).expect(msg).into_inner().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::actions::diagnostics::parse_diagnostics;
+
+    #[test]
+    fn crash_diagnostic_points_at_crate_root() {
+        let args: Vec<String> = ["rustc", "--crate-name", "foo", "src/lib.rs", "--edition=2018"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let diagnostic = crash_diagnostic(&args).unwrap();
+
+        let cwd = Path::new("/project");
+        let parsed = parse_diagnostics(diagnostic.trim(), cwd, false).unwrap();
+        let diagnostics = &parsed.diagnostics[&cwd.join("src/lib.rs")];
+        assert_eq!(diagnostics.len(), 1);
+        let message = &diagnostics[0].0.message;
+        assert!(message.starts_with("internal compiler error while compiling crate `foo`"));
+        assert!(message.contains("rustc --crate-name foo src/lib.rs --edition=2018"));
+    }
+
+    #[test]
+    fn no_crash_diagnostic_without_crate_root() {
+        let args = vec!["rustc".to_owned(), "-".to_owned()];
+        assert_eq!(crash_diagnostic(&args), None);
+    }
 }
@@ -0,0 +1,316 @@
+//! A normalized model of rustc command lines, so that equivalent invocations
+//! (e.g., ones only differing in the order of their `--extern`s or `--cfg`s)
+//! compare and hash the same.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Options taking a value, which can be passed as a separate argument.
+const OPTIONS_WITH_VALUE: &[&str] = &[
+    "-A",
+    "-C",
+    "-D",
+    "-F",
+    "-L",
+    "-W",
+    "-Z",
+    "-l",
+    "-o",
+    "--cap-lints",
+    "--cfg",
+    "--check-cfg",
+    "--codegen",
+    "--color",
+    "--crate-name",
+    "--crate-type",
+    "--edition",
+    "--emit",
+    "--error-format",
+    "--explain",
+    "--extern",
+    "--force-warn",
+    "--json",
+    "--out-dir",
+    "--print",
+    "--remap-path-prefix",
+    "--sysroot",
+    "--target",
+];
+
+/// Codegen options which take a single value, so that a later one overrides an
+/// earlier one. Others (e.g., `link-arg`, `llvm-args` or `passes`) add up, so
+/// they are kept in order, repetitions and all.
+const SINGLE_VALUED_CODEGEN_OPTS: &[&str] = &[
+    "code-model",
+    "codegen-units",
+    "debug-assertions",
+    "debuginfo",
+    "embed-bitcode",
+    "extra-filename",
+    "force-frame-pointers",
+    "incremental",
+    "linker",
+    "linker-flavor",
+    "lto",
+    "opt-level",
+    "overflow-checks",
+    "panic",
+    "prefer-dynamic",
+    "relocation-model",
+    "rpath",
+    "split-debuginfo",
+    "strip",
+    "target-cpu",
+];
+
+/// Arguments of a rustc invocation (without the program name).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct RustcArgs {
+    /// `--crate-name`
+    pub(crate) crate_name: Option<String>,
+    /// `--crate-type`s, which may also be given as a comma-separated list.
+    pub(crate) crate_types: BTreeSet<String>,
+    /// `--edition`
+    pub(crate) edition: Option<String>,
+    /// `--cfg` specs
+    pub(crate) cfgs: BTreeSet<String>,
+    /// `--extern name[=path]` specs
+    pub(crate) externs: BTreeSet<String>,
+    /// `-L [kind=]path` search paths
+    pub(crate) search_paths: BTreeSet<String>,
+    /// Single-valued `-C opt[=value]` codegen options, where later ones
+    /// override earlier ones.
+    pub(crate) codegen_opts: BTreeMap<String, Option<String>>,
+    /// Any other `-C opt[=value]` codegen options, in order.
+    pub(crate) repeated_codegen_opts: Vec<(String, Option<String>)>,
+    /// Input files, i.e., the crate root.
+    pub(crate) inputs: Vec<String>,
+    /// Any other arguments, in order, since their meaning can depend on it
+    /// (e.g., for lint levels).
+    pub(crate) other: Vec<String>,
+}
+
+impl RustcArgs {
+    pub(crate) fn parse<S: AsRef<str>>(args: &[S]) -> RustcArgs {
+        let mut result = RustcArgs::default();
+
+        let mut args = args.iter().map(AsRef::as_ref);
+        while let Some(arg) = args.next() {
+            let (option, value) = match split_option(arg) {
+                Some((option, Some(value))) => (option, value),
+                Some((option, None)) => match args.next() {
+                    Some(value) => (option, value),
+                    None => {
+                        result.other.push(arg.to_owned());
+                        continue;
+                    }
+                },
+                None if arg.starts_with('-') && arg != "-" => {
+                    result.other.push(arg.to_owned());
+                    continue;
+                }
+                None => {
+                    result.inputs.push(arg.to_owned());
+                    continue;
+                }
+            };
+
+            match option {
+                "--crate-name" => result.crate_name = Some(value.to_owned()),
+                "--crate-type" => {
+                    result.crate_types.extend(value.split(',').map(ToOwned::to_owned))
+                }
+                "--edition" => result.edition = Some(value.to_owned()),
+                "--cfg" => {
+                    result.cfgs.insert(value.to_owned());
+                }
+                "--extern" => {
+                    result.externs.insert(value.to_owned());
+                }
+                "-L" => {
+                    result.search_paths.insert(value.to_owned());
+                }
+                "-C" | "--codegen" => {
+                    let mut opt = value.splitn(2, '=');
+                    let name = opt.next().unwrap_or_default().to_owned();
+                    let value = opt.next().map(ToOwned::to_owned);
+                    if SINGLE_VALUED_CODEGEN_OPTS.contains(&name.as_str()) {
+                        result.codegen_opts.insert(name, value);
+                    } else {
+                        result.repeated_codegen_opts.push((name, value));
+                    }
+                }
+                _ => {
+                    result.other.push(option.to_owned());
+                    result.other.push(value.to_owned());
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Returns the arguments in a canonical order.
+    pub(crate) fn to_args(&self) -> Vec<String> {
+        let mut args = vec![];
+
+        let mut push = |option: &str, value: &str| {
+            args.push(option.to_owned());
+            args.push(value.to_owned());
+        };
+        if let Some(crate_name) = &self.crate_name {
+            push("--crate-name", crate_name);
+        }
+        if let Some(edition) = &self.edition {
+            push("--edition", edition);
+        }
+        for crate_type in &self.crate_types {
+            push("--crate-type", crate_type);
+        }
+        for cfg in &self.cfgs {
+            push("--cfg", cfg);
+        }
+        for search_path in &self.search_paths {
+            push("-L", search_path);
+        }
+        for extern_ in &self.externs {
+            push("--extern", extern_);
+        }
+        for (name, value) in &self.codegen_opts {
+            push("-C", &codegen_opt(name, value));
+        }
+        for (name, value) in &self.repeated_codegen_opts {
+            push("-C", &codegen_opt(name, value));
+        }
+
+        args.extend(self.other.iter().cloned());
+        args.extend(self.inputs.iter().cloned());
+        args
+    }
+}
+
+impl fmt::Display for RustcArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_args().join(" "))
+    }
+}
+
+fn codegen_opt(name: &str, value: &Option<String>) -> String {
+    match value {
+        Some(value) => format!("{}={}", name, value),
+        None => name.to_owned(),
+    }
+}
+
+/// Splits an option taking a value into the option and its value, if it's
+/// passed in the same argument (e.g., `--cfg=test`, `-Copt-level=0`).
+fn split_option(arg: &str) -> Option<(&str, Option<&str>)> {
+    if OPTIONS_WITH_VALUE.contains(&arg) {
+        return Some((arg, None));
+    }
+
+    if arg.starts_with("--") {
+        let eq = arg.find('=')?;
+        let option = &arg[..eq];
+        if OPTIONS_WITH_VALUE.contains(&option) {
+            return Some((option, Some(&arg[eq + 1..])));
+        }
+    } else if arg.starts_with('-') && arg.len() > 2 && arg.is_char_boundary(2) {
+        let option = &arg[..2];
+        if OPTIONS_WITH_VALUE.contains(&option) {
+            return Some((option, Some(&arg[2..])));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn equivalent_args() {
+        let a = RustcArgs::parse(&[
+            "--crate-name",
+            "foo",
+            "--edition=2018",
+            "src/lib.rs",
+            "--crate-type",
+            "lib",
+            "--cfg",
+            "feature=\"a\"",
+            "--cfg",
+            "feature=\"b\"",
+            "-C",
+            "opt-level=0",
+            "-L",
+            "dependency=target/debug/deps",
+            "--extern",
+            "bar=target/debug/deps/libbar.rmeta",
+            "--extern",
+            "baz=target/debug/deps/libbaz.rmeta",
+        ]);
+        let b = RustcArgs::parse(&[
+            "--extern=baz=target/debug/deps/libbaz.rmeta",
+            "--extern",
+            "bar=target/debug/deps/libbar.rmeta",
+            "--crate-type=lib",
+            "-Ldependency=target/debug/deps",
+            "--cfg",
+            "feature=\"b\"",
+            "--cfg",
+            "feature=\"a\"",
+            "-Copt-level=0",
+            "--crate-name=foo",
+            "--edition",
+            "2018",
+            "src/lib.rs",
+        ]);
+        assert_eq!(a, b);
+
+        assert_eq!(a.crate_name.as_deref(), Some("foo"));
+        assert_eq!(a.inputs, vec!["src/lib.rs"]);
+        assert_eq!(a.codegen_opts.get("opt-level"), Some(&Some("0".to_owned())));
+    }
+
+    #[test]
+    fn ordered_args() {
+        let parse = |args: &[&str]| RustcArgs::parse(args);
+
+        // Lint levels override each other in order.
+        assert_ne!(
+            parse(&["-A", "warnings", "-D", "unused"]),
+            parse(&["-D", "unused", "-A", "warnings"])
+        );
+        // Later codegen options override earlier ones.
+        assert_eq!(parse(&["-C", "debuginfo=0", "-C", "debuginfo=2"]), parse(&["-Cdebuginfo=2"]));
+        // Unless they add up.
+        let link_args =
+            parse(&["-C", "link-arg=-lfoo", "-C", "link-arg=-lbar", "-Clink-arg=-lfoo"]);
+        assert_ne!(link_args, parse(&["-C", "link-arg=-lfoo"]));
+        assert_ne!(link_args, parse(&["-C", "link-arg=-lbar", "-C", "link-arg=-lfoo"]));
+        assert_eq!(link_args.to_string(), "-C link-arg=-lfoo -C link-arg=-lbar -C link-arg=-lfoo");
+        // Values aren't mistaken for inputs.
+        assert_eq!(parse(&["--out-dir", "target", "lib.rs"]).inputs, vec!["lib.rs"]);
+    }
+
+    #[test]
+    fn canonical_args() {
+        let args = RustcArgs::parse(&[
+            "src/main.rs",
+            "--error-format=json",
+            "--extern",
+            "foo",
+            "--crate-type",
+            "bin,lib",
+            "--crate-name",
+            "main",
+        ]);
+        assert_eq!(
+            args.to_string(),
+            "--crate-name main --crate-type bin --crate-type lib --extern foo \
+             --error-format json src/main.rs"
+        );
+        assert_eq!(RustcArgs::parse(&args.to_args()), args);
+    }
+}
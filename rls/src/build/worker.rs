@@ -0,0 +1,187 @@
+//! A pool of long-lived `rls-rustc` workers for out-of-process compilation, so
+//! that starting up the compiler is only paid for once rather than per crate.
+
+use std::env;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::warn;
+use rls_ipc::rpc::JobOutcome;
+
+use super::ipc::{self, WorkerJob};
+
+// How often to check whether a worker crashed while waiting for its job.
+const CRASH_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Idle workers, waiting for compile jobs.
+pub struct WorkerPool {
+    idle: Mutex<Vec<Worker>>,
+}
+
+impl WorkerPool {
+    /// Returns the pool shared by all builds.
+    pub fn get() -> &'static WorkerPool {
+        lazy_static! {
+            static ref POOL: WorkerPool = WorkerPool { idle: Mutex::default() };
+        }
+        &POOL
+    }
+
+    /// Runs `job` on an idle worker, starting one if there are none, and keeps
+    /// at most `max_idle` workers around afterwards. Returns `None` if the
+    /// worker crashed before handing back an outcome. Workers which crash are
+    /// replaced straightaway.
+    pub fn compile(&self, job: WorkerJob, max_idle: usize) -> Result<Option<JobOutcome>, ()> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut worker = match idle {
+            Some(worker) => worker,
+            None => Worker::spawn()?,
+        };
+
+        let outcome = worker.run(job);
+        let crashed = outcome.as_ref().map_or(true, |outcome| outcome.crashed);
+        let worker = if crashed {
+            warn!("Out-of-process compiler crashed, starting a new one");
+            // Make sure the crashed worker is gone before replacing it.
+            std::mem::drop(worker);
+            match Worker::spawn() {
+                Ok(worker) => worker,
+                Err(()) => return Ok(outcome),
+            }
+        } else {
+            worker
+        };
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < max_idle {
+            idle.push(worker);
+        }
+        Ok(outcome)
+    }
+}
+
+/// A `rls-rustc` process which compiles the jobs sent to its IPC server.
+struct Worker {
+    process: Child,
+    // Closing this tells the worker to exit.
+    jobs: Option<Sender<(WorkerJob, Sender<JobOutcome>)>>,
+    server: Option<ipc::Server>,
+}
+
+impl Worker {
+    fn spawn() -> Result<Worker, ()> {
+        let (jobs, receiver) = channel();
+        let server = ipc::start_worker(receiver)?;
+
+        // Compiling out of process is only supported by our own shim
+        let rustc_shim = env::current_exe().expect("Couldn't set executable for RLS rustc shim");
+        let process = Command::new(rustc_shim)
+            .env(crate::RUSTC_SHIM_ENV_VAR_NAME, "1")
+            .env("RLS_IPC_ENDPOINT", server.endpoint())
+            .env("RLS_IPC_WORKER", "1")
+            // Our stdout is the LSP channel.
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn();
+
+        match process {
+            Ok(process) => Ok(Worker { process, jobs: Some(jobs), server: Some(server) }),
+            Err(e) => {
+                warn!("Couldn't start out-of-process compiler: {}", e);
+                server.close();
+                Err(())
+            }
+        }
+    }
+
+    /// Runs `job`, returning its outcome, or `None` if the worker exited
+    /// before handing it back.
+    fn run(&mut self, job: WorkerJob) -> Option<JobOutcome> {
+        let (sender, outcome) = channel();
+        self.jobs.as_ref()?.send((job, sender)).ok()?;
+
+        let process = &mut self.process;
+        wait_for_outcome(&outcome, CRASH_CHECK_INTERVAL, || {
+            process.try_wait().map_or(true, |status| status.is_some())
+        })
+    }
+}
+
+/// Waits for a job's `outcome`, checking whether the worker `exited` every
+/// `interval`.
+fn wait_for_outcome(
+    outcome: &Receiver<JobOutcome>,
+    interval: Duration,
+    mut exited: impl FnMut() -> bool,
+) -> Option<JobOutcome> {
+    loop {
+        match outcome.recv_timeout(interval) {
+            Ok(outcome) => return Some(outcome),
+            Err(RecvTimeoutError::Disconnected) => return None,
+            Err(RecvTimeoutError::Timeout) => {
+                if exited() {
+                    // The outcome may have arrived just before the worker exited.
+                    return outcome.try_recv().ok();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // The server can't shut down while the worker is waiting for a job.
+        self.jobs.take();
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        if let Some(server) = self.server.take() {
+            server.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(1);
+
+    fn job_outcome() -> JobOutcome {
+        JobOutcome { success: true, stderr: "warning".to_owned(), crashed: false }
+    }
+
+    #[test]
+    fn outcome_before_exit() {
+        let (sender, outcome) = channel();
+        let mut checks = 0;
+        let result = wait_for_outcome(&outcome, INTERVAL, || {
+            checks += 1;
+            if checks < 3 {
+                return false;
+            }
+            // The outcome arrives after the last wait timed out, but before
+            // the exit is noticed.
+            sender.send(job_outcome()).unwrap();
+            true
+        });
+        let result = result.expect("the outcome was dropped");
+        assert!(result.success && !result.crashed);
+        assert_eq!(result.stderr, "warning");
+    }
+
+    #[test]
+    fn exit_without_outcome() {
+        let (_sender, outcome) = channel();
+        assert!(wait_for_outcome(&outcome, INTERVAL, || true).is_none());
+    }
+
+    #[test]
+    fn outcome_sender_dropped() {
+        let (sender, outcome) = channel::<JobOutcome>();
+        std::mem::drop(sender);
+        assert!(wait_for_outcome(&outcome, INTERVAL, || false).is_none());
+    }
+}
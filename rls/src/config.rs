@@ -2,6 +2,7 @@
 //! tweaking the RLS's behavior itself.
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::env;
 use std::fmt;
 use std::fmt::Debug;
@@ -173,8 +174,19 @@ pub struct Config {
     /// to be loaded by the RLS. The program given should output a list of
     /// resulting JSON files on stdout.
     pub build_command: Option<String>,
+    /// EXPERIMENTAL (needs unstable features)
+    /// If set, reads a build plan in the format emitted by `cargo build --build-plan`
+    /// from the given path (relative to the project root) and runs it instead of Cargo.
+    pub build_plan: Option<String>,
+    /// EXPERIMENTAL (needs unstable features)
+    /// If set, compiles independent crates of cached or build plan invocations
+    /// in parallel, each out of process. Requires the `ipc` feature.
+    pub parallel_rustc: bool,
     /// DEPRECATED: Use `crate_blacklist` instead.
     pub use_crate_blacklist: Option<bool>,
+    /// The memory (in MB) used to cache files which aren't open in the client,
+    /// e.g., those loaded for hover or completion. `None` means no limit.
+    pub vfs_cache_limit: Option<u64>,
 }
 
 impl Default for Config {
@@ -204,7 +216,10 @@ impl Default for Config {
             show_hover_context: true,
             rustfmt_path: None,
             build_command: None,
+            build_plan: None,
+            parallel_rustc: false,
             use_crate_blacklist: None,
+            vfs_cache_limit: Some(256),
         };
         result.normalise();
         result
@@ -222,6 +237,12 @@ lazy_static::lazy_static! {
 }
 
 impl Config {
+    /// The memory limit for the VFS, in bytes.
+    pub fn vfs_memory_limit(&self) -> Option<usize> {
+        self.vfs_cache_limit
+            .map(|mb| usize::try_from(mb).unwrap_or(usize::max_value()).saturating_mul(1024 * 1024))
+    }
+
     /// try to deserialize a Config from a json value, val is expected to be a
     /// Value::Object, all first level keys of val are converted to snake_case,
     /// duplicated and unknown keys are reported
@@ -305,6 +326,13 @@ impl Config {
             self.cfg_test = false;
             self.rustfmt_path = None;
             self.build_command = None;
+            self.build_plan = None;
+            self.parallel_rustc = false;
+        }
+
+        // An empty path means no build plan, as for an unset one.
+        if self.build_plan.as_ref().map_or(false, |plan| plan.trim().is_empty()) {
+            self.build_plan = None;
         }
     }
 
@@ -536,3 +564,30 @@ fn blacklist_default() {
         Config::try_deserialize(&value, &mut Default::default(), &mut vec![], &mut vec![]).unwrap();
     assert_eq!(&*config.crate_blacklist.as_ref().0, &["serde".to_string()]);
 }
+
+#[test]
+fn vfs_memory_limit_saturates() {
+    let mut config = Config::default();
+    config.vfs_cache_limit = Some(256);
+    assert_eq!(config.vfs_memory_limit(), Some(256 * 1024 * 1024));
+    config.vfs_cache_limit = Some(u64::max_value());
+    assert_eq!(config.vfs_memory_limit(), Some(usize::max_value()));
+    config.vfs_cache_limit = None;
+    assert_eq!(config.vfs_memory_limit(), None);
+}
+
+#[test]
+fn empty_build_plan_is_none() {
+    // `build_plan` is an unstable option.
+    if !unstable_features_allowed() {
+        return;
+    }
+    let mut config = Config { unstable_features: true, ..Config::default() };
+    config.build_plan = Some(String::new());
+    config.normalise();
+    assert_eq!(config.build_plan, None);
+
+    config.build_plan = Some("build-plan.json".to_owned());
+    config.normalise();
+    assert_eq!(config.build_plan.as_deref(), Some("build-plan.json"));
+}
@@ -7,7 +7,7 @@ use std::path::PathBuf;
 pub use lsp_types::notification::Notification as LSPNotification;
 pub use lsp_types::request::Request as LSPRequest;
 pub use lsp_types::*;
-use rls_analysis::DefKind;
+use rls_analysis::{Attribute, DefKind};
 use rls_span as span;
 use serde_derive::{Deserialize, Serialize};
 use url::Url;
@@ -155,6 +155,16 @@ pub fn source_kind_from_def_kind(k: DefKind) -> SymbolKind {
     }
 }
 
+/// Returns `Some(true)` if the attributes mark the symbol as deprecated, for
+/// the `deprecated` field of `SymbolInformation`.
+pub fn symbol_deprecated(attributes: &[Attribute]) -> Option<bool> {
+    if attributes.iter().any(|attr| attr.name() == "deprecated") {
+        Some(true)
+    } else {
+        None
+    }
+}
+
 /// Indicates the kind of completion for this racer match type.
 pub fn completion_kind_from_match_type(m: racer::MatchType) -> CompletionItemKind {
     match m {
@@ -300,6 +310,9 @@ impl Default for InitializationOptions {
 pub struct ClientCapabilities {
     pub code_completion_has_snippet_support: bool,
     pub related_information_support: bool,
+    pub hierarchical_document_symbol_support: bool,
+    /// Whether the client can tell us about changes to files on disk.
+    pub watched_files_support: bool,
 }
 
 impl ClientCapabilities {
@@ -328,6 +341,270 @@ impl ClientCapabilities {
             .copied()
             .unwrap_or(false);
 
-        ClientCapabilities { code_completion_has_snippet_support, related_information_support }
+        let hierarchical_document_symbol_support = params
+            .capabilities
+            .text_document
+            .as_ref()
+            .and_then(|doc| doc.document_symbol.as_ref())
+            .and_then(|symbol| symbol.hierarchical_document_symbol_support.as_ref())
+            .copied()
+            .unwrap_or(false);
+
+        let watched_files_support = params
+            .capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files.as_ref())
+            .and_then(|watched| watched.dynamic_registration)
+            .unwrap_or(false);
+
+        ClientCapabilities {
+            code_completion_has_snippet_support,
+            related_information_support,
+            hierarchical_document_symbol_support,
+            watched_files_support,
+        }
     }
 }
+
+/* ------  Proposed protocol extensions not (yet) in `lsp_types` ------ */
+
+/// `ServerCapabilities` extended with capabilities for proposed protocol
+/// features which `lsp_types` does not know about.
+#[derive(Debug, PartialEq, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerCapabilitiesExt {
+    #[serde(flatten)]
+    pub base: ServerCapabilities,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub call_hierarchy_provider: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub type_hierarchy_provider: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub semantic_tokens_provider: Option<SemanticTokensOptions>,
+}
+
+/// `InitializeResult` which advertises the extended server capabilities.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct InitializeResultExt {
+    pub capabilities: ServerCapabilitiesExt,
+}
+
+/// Represents a function or method in the call hierarchy.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallHierarchyItem {
+    pub name: String,
+    pub kind: SymbolKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub uri: Url,
+    pub range: Range,
+    pub selection_range: Range,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct CallHierarchyCallsParams {
+    pub item: CallHierarchyItem,
+}
+
+/// A function calling the item from `callHierarchy/incomingCalls`.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallHierarchyIncomingCall {
+    pub from: CallHierarchyItem,
+    /// Ranges of the calls, in the file of `from`.
+    pub from_ranges: Vec<Range>,
+}
+
+/// A function called by the item from `callHierarchy/outgoingCalls`.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallHierarchyOutgoingCall {
+    pub to: CallHierarchyItem,
+    /// Ranges of the calls, in the file of the item from the request.
+    pub from_ranges: Vec<Range>,
+}
+
+#[derive(Debug)]
+pub enum CallHierarchyPrepare {}
+
+impl LSPRequest for CallHierarchyPrepare {
+    type Params = TextDocumentPositionParams;
+    type Result = Option<Vec<CallHierarchyItem>>;
+    const METHOD: &'static str = "textDocument/prepareCallHierarchy";
+}
+
+#[derive(Debug)]
+pub enum CallHierarchyIncomingCalls {}
+
+impl LSPRequest for CallHierarchyIncomingCalls {
+    type Params = CallHierarchyCallsParams;
+    type Result = Option<Vec<CallHierarchyIncomingCall>>;
+    const METHOD: &'static str = "callHierarchy/incomingCalls";
+}
+
+#[derive(Debug)]
+pub enum CallHierarchyOutgoingCalls {}
+
+impl LSPRequest for CallHierarchyOutgoingCalls {
+    type Params = CallHierarchyCallsParams;
+    type Result = Option<Vec<CallHierarchyOutgoingCall>>;
+    const METHOD: &'static str = "callHierarchy/outgoingCalls";
+}
+
+/// Represents a type or trait in the type hierarchy.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeHierarchyItem {
+    pub name: String,
+    pub kind: SymbolKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub uri: Url,
+    pub range: Range,
+    pub selection_range: Range,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct TypeHierarchyParams {
+    pub item: TypeHierarchyItem,
+}
+
+#[derive(Debug)]
+pub enum TypeHierarchyPrepare {}
+
+impl LSPRequest for TypeHierarchyPrepare {
+    type Params = TextDocumentPositionParams;
+    type Result = Option<Vec<TypeHierarchyItem>>;
+    const METHOD: &'static str = "textDocument/prepareTypeHierarchy";
+}
+
+/// Returns the supertraits of a trait or the traits implemented by a type.
+#[derive(Debug)]
+pub enum TypeHierarchySupertypes {}
+
+impl LSPRequest for TypeHierarchySupertypes {
+    type Params = TypeHierarchyParams;
+    type Result = Option<Vec<TypeHierarchyItem>>;
+    const METHOD: &'static str = "typeHierarchy/supertypes";
+}
+
+/// Returns the subtraits and implementing types of a trait.
+#[derive(Debug)]
+pub enum TypeHierarchySubtypes {}
+
+impl LSPRequest for TypeHierarchySubtypes {
+    type Params = TypeHierarchyParams;
+    type Result = Option<Vec<TypeHierarchyItem>>;
+    const METHOD: &'static str = "typeHierarchy/subtypes";
+}
+
+/// The token types and modifiers which the indices in semantic tokens refer to.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensLegend {
+    pub token_types: Vec<String>,
+    pub token_modifiers: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct SemanticTokensFullOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct SemanticTokensOptions {
+    pub legend: SemanticTokensLegend,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full: Option<SemanticTokensFullOptions>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensParams {
+    pub text_document: TextDocumentIdentifier,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensRangeParams {
+    pub text_document: TextDocumentIdentifier,
+    pub range: Range,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensDeltaParams {
+    pub text_document: TextDocumentIdentifier,
+    pub previous_result_id: String,
+}
+
+/// Semantic tokens, encoded as five integers per token: the line (relative to
+/// the previous token), the start character (relative to the previous token
+/// if on the same line), the length, the token type and the token modifiers.
+#[derive(Debug, PartialEq, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokens {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_id: Option<String>,
+    pub data: Vec<u32>,
+}
+
+/// Replaces `delete_count` integers of the previous tokens' `data`, starting
+/// at `start`, with `data`.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensEdit {
+    pub start: u32,
+    pub delete_count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Vec<u32>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_id: Option<String>,
+    pub edits: Vec<SemanticTokensEdit>,
+}
+
+/// The response to a delta request, with all tokens if the previous result is
+/// not known.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum SemanticTokensFullDeltaResult {
+    Tokens(SemanticTokens),
+    TokensDelta(SemanticTokensDelta),
+}
+
+#[derive(Debug)]
+pub enum SemanticTokensFull {}
+
+impl LSPRequest for SemanticTokensFull {
+    type Params = SemanticTokensParams;
+    type Result = Option<SemanticTokens>;
+    const METHOD: &'static str = "textDocument/semanticTokens/full";
+}
+
+#[derive(Debug)]
+pub enum SemanticTokensFullDelta {}
+
+impl LSPRequest for SemanticTokensFullDelta {
+    type Params = SemanticTokensDeltaParams;
+    type Result = Option<SemanticTokensFullDeltaResult>;
+    const METHOD: &'static str = "textDocument/semanticTokens/full/delta";
+}
+
+#[derive(Debug)]
+pub enum SemanticTokensRange {}
+
+impl LSPRequest for SemanticTokensRange {
+    type Params = SemanticTokensRangeParams;
+    type Result = Option<SemanticTokens>;
+    const METHOD: &'static str = "textDocument/semanticTokens/range";
+}
@@ -87,10 +87,17 @@ macro_rules! define_dispatch_request_enum {
 define_dispatch_request_enum!(
     Completion,
     Definition,
+    TypeDefinition,
     References,
     WorkspaceSymbol,
     Symbols,
+    FoldingRange,
+    SelectionRange,
+    SemanticTokensFull,
+    SemanticTokensFullDelta,
+    SemanticTokensRange,
     Hover,
+    SignatureHelp,
     Implementation,
     DocumentHighlight,
     Rename,
@@ -100,6 +107,12 @@ define_dispatch_request_enum!(
     RangeFormatting,
     ExecuteCommand,
     CodeLensRequest,
+    CallHierarchyPrepare,
+    CallHierarchyIncomingCalls,
+    CallHierarchyOutgoingCalls,
+    TypeHierarchyPrepare,
+    TypeHierarchySupertypes,
+    TypeHierarchySubtypes,
 );
 
 /// Provides ability to dispatch requests to a worker thread that will
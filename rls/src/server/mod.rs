@@ -2,11 +2,12 @@
 //! interactions (for example, to add support for handling new types of
 //! requests).
 
-use crate::actions::{notifications, requests, ActionContext};
+use crate::actions::{notifications, requests, semantic_tokens, ActionContext};
 use crate::config::{Config, DEPRECATED_OPTIONS};
 use crate::lsp_data;
 use crate::lsp_data::{
-    InitializationOptions, LSPNotification, LSPRequest, MessageType, ShowMessageParams,
+    InitializationOptions, InitializeResultExt, LSPNotification, LSPRequest, MessageType,
+    SemanticTokensFullOptions, SemanticTokensOptions, ServerCapabilitiesExt, ShowMessageParams,
 };
 use crate::server::dispatch::Dispatcher;
 pub use crate::server::dispatch::{RequestAction, DEFAULT_REQUEST_TIMEOUT};
@@ -25,8 +26,9 @@ pub use lsp_types::request::Initialize as InitializeRequest;
 pub use lsp_types::request::Shutdown as ShutdownRequest;
 use lsp_types::{
     CodeActionProviderCapability, CodeLensOptions, CompletionOptions, ExecuteCommandOptions,
-    ImplementationProviderCapability, InitializeParams, InitializeResult, RenameProviderCapability,
-    ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind,
+    FoldingRangeProviderCapability, GenericCapability, ImplementationProviderCapability,
+    InitializeParams, RenameProviderCapability, ServerCapabilities, SignatureHelpOptions,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TypeDefinitionProviderCapability,
 };
 use rls_analysis::AnalysisHost;
 use rls_vfs::Vfs;
@@ -176,7 +178,7 @@ impl BlockingRequestAction for InitializeRequest {
         maybe_notify_deprecated_configs(&out, &deprecated);
         maybe_notify_duplicated_configs(&out, &dups);
 
-        let result = InitializeResult { capabilities: server_caps(ctx) };
+        let result = InitializeResultExt { capabilities: server_caps(ctx) };
 
         // Send response early before `ctx.init` to enforce
         // initialize-response-before-all-other-messages constraint.
@@ -319,6 +321,7 @@ impl<O: Output> LsService<O> {
                 notifications::Initialized,
                 notifications::DidOpenTextDocument,
                 notifications::DidChangeTextDocument,
+                notifications::DidCloseTextDocument,
                 notifications::DidSaveTextDocument,
                 notifications::DidChangeConfiguration,
                 notifications::DidChangeWatchedFiles,
@@ -336,12 +339,25 @@ impl<O: Output> LsService<O> {
                 requests::DocumentHighlight,
                 requests::Implementation,
                 requests::Symbols,
+                requests::FoldingRange,
+                requests::SelectionRange,
+                requests::SemanticTokensFull,
+                requests::SemanticTokensFullDelta,
+                requests::SemanticTokensRange,
                 requests::Hover,
+                requests::SignatureHelp,
                 requests::WorkspaceSymbol,
                 requests::Definition,
+                requests::TypeDefinition,
                 requests::References,
                 requests::Completion,
-                requests::CodeLensRequest;
+                requests::CodeLensRequest,
+                requests::CallHierarchyPrepare,
+                requests::CallHierarchyIncomingCalls,
+                requests::CallHierarchyOutgoingCalls,
+                requests::TypeHierarchyPrepare,
+                requests::TypeHierarchySupertypes,
+                requests::TypeHierarchySubtypes;
         );
         Ok(())
     }
@@ -417,8 +433,8 @@ pub enum ServerStateChange {
     Break { exit_code: i32 },
 }
 
-fn server_caps(ctx: &ActionContext) -> ServerCapabilities {
-    ServerCapabilities {
+fn server_caps(ctx: &ActionContext) -> ServerCapabilitiesExt {
+    let base = ServerCapabilities {
         text_document_sync: Some(TextDocumentSyncCapability::Kind(
             TextDocumentSyncKind::Incremental,
         )),
@@ -428,7 +444,7 @@ fn server_caps(ctx: &ActionContext) -> ServerCapabilities {
             trigger_characters: Some(vec![".".to_string(), ":".to_string()]),
         }),
         definition_provider: Some(true),
-        type_definition_provider: None,
+        type_definition_provider: Some(TypeDefinitionProviderCapability::Simple(true)),
         implementation_provider: Some(ImplementationProviderCapability::Simple(true)),
         references_provider: Some(true),
         document_highlight_provider: Some(true),
@@ -455,11 +471,24 @@ fn server_caps(ctx: &ActionContext) -> ServerCapabilities {
 
         code_lens_provider: Some(CodeLensOptions { resolve_provider: Some(false) }),
         document_on_type_formatting_provider: None,
-        signature_help_provider: None,
+        signature_help_provider: Some(SignatureHelpOptions {
+            trigger_characters: Some(vec!["(".to_string(), ",".to_string()]),
+        }),
 
-        folding_range_provider: None,
+        folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
         workspace: None,
-        selection_range_provider: None,
+        selection_range_provider: Some(GenericCapability::default()),
+    };
+
+    ServerCapabilitiesExt {
+        base,
+        call_hierarchy_provider: Some(true),
+        type_hierarchy_provider: Some(true),
+        semantic_tokens_provider: Some(SemanticTokensOptions {
+            legend: semantic_tokens::legend(),
+            range: Some(true),
+            full: Some(SemanticTokensFullOptions { delta: Some(true) }),
+        }),
     }
 }
 
@@ -1554,6 +1554,62 @@ fn client_workspace_symbol() {
     }
 }
 
+#[test]
+fn client_document_symbol_nested() {
+    let p =
+        ProjectBuilder::try_from_fixture(fixtures_dir().join("workspace_symbol")).unwrap().build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let mut params = initialize_params(root_path);
+    params.capabilities.text_document = Some(TextDocumentClientCapabilities {
+        document_symbol: Some(DocumentSymbolCapability {
+            hierarchical_document_symbol_support: Some(true),
+            ..DocumentSymbolCapability::default()
+        }),
+        ..TextDocumentClientCapabilities::default()
+    });
+    rls.request::<Initialize>(0, params);
+
+    rls.wait_for_indexing();
+
+    let symbols = rls
+        .request::<DocumentSymbolRequest>(
+            42,
+            DocumentSymbolParams {
+                text_document: TextDocumentIdentifier {
+                    uri: Url::from_file_path(p.root().join("src/main.rs")).unwrap(),
+                },
+            },
+        )
+        .unwrap();
+    let symbols = match symbols {
+        DocumentSymbolResponse::Nested(symbols) => symbols,
+        DocumentSymbolResponse::Flat(_) => panic!("expected nested symbols"),
+    };
+
+    let range = |start_l, start_c, end_l, end_c| Range {
+        start: Position { line: start_l, character: start_c },
+        end: Position { line: end_l, character: end_c },
+    };
+
+    let x = symbols.iter().find(|s| s.name == "x").unwrap();
+    assert_eq!(x.kind, SymbolKind::Module);
+    assert_eq!(x.range, range(0, 0, 2, 1));
+    assert_eq!(x.selection_range, range(0, 4, 0, 5));
+
+    let children = x.children.as_ref().unwrap();
+    assert_eq!(children.len(), 1);
+    assert_eq!(children[0].name, "nemo");
+    assert_eq!(children[0].kind, SymbolKind::Function);
+    assert_eq!(children[0].range, range(1, 4, 1, 20));
+    assert_eq!(children[0].selection_range, range(1, 11, 1, 15));
+
+    let main = symbols.iter().find(|s| s.name == "main").unwrap();
+    assert_eq!(main.range, range(6, 0, 8, 1));
+    assert!(!symbols.iter().any(|s| s.name == "nemo" && s.kind == SymbolKind::Function));
+}
+
 #[test]
 fn client_workspace_symbol_duplicates() {
     let p = ProjectBuilder::try_from_fixture(fixtures_dir().join("workspace_symbol_duplicates"))
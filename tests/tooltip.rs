@@ -182,6 +182,8 @@ impl TooltipTestHarness {
         let client_caps = ClientCapabilities {
             code_completion_has_snippet_support: true,
             related_information_support: true,
+            hierarchical_document_symbol_support: false,
+            watched_files_support: true,
         };
 
         let _working_dir = tempfile::tempdir().expect("Couldn't create tempdir");